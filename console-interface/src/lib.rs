@@ -1,21 +1,75 @@
 #![allow(clippy::unused_unit)]
 use wasm_bindgen::prelude::*;
-use js_sys::{Promise, Uint8Array, Array};
-use protocols::protocol::{Connection, Protocol, create_connection, create_connection_with_uri};
-use wasm_bindgen_futures::{future_to_promise};
+use js_sys::{Function, Promise, Uint8Array, Array};
+use protocols::protocol::{AddressSpace, Connection, ConnectionError, MappingOverride, MemoryMapping, Protocol, create_connection, create_connection_with_uri};
+use wasm_bindgen_futures::{future_to_promise, spawn_local};
 use std::iter::FromIterator;
 use std::sync::{Arc};
+use std::time::Duration;
+use futures::{future, Future, StreamExt};
+use protocols::discovery;
+use protocols::recording::{Recorder, RecordingConnection, ReplayConnection, Trace};
+use protocols::timeout::Operation;
+use protocols::watch::Watcher;
+use web_sys::AbortSignal;
 
 pub mod protocols;
 
+#[cfg(all(feature = "wasm", feature = "native"))]
+compile_error!("The \"wasm\" and \"native\" features are mutually exclusive");
+#[cfg(not(any(feature = "wasm", feature = "native")))]
+compile_error!("Either the \"wasm\" or the \"native\" feature must be enabled");
+
 // #[global_allocator]
 // static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
 
 static LOG_LEVEL: log::Level = if cfg!(debug_assertions) { log::Level::Debug } else { log::Level::Info };
 
+// Run a request that JS can cancel with an AbortSignal. Cancelling is reported as Cancelled, which doesn't need a
+// reconnect, but the connection is recovered the same way as after a timeout so the abandoned request doesn't
+// affect the next one.
+async fn cancellable<T, F>(conn: &dyn Connection, signal: Option<AbortSignal>, future: F) -> Result<T, ConnectionError>
+where
+    F: Future<Output = Result<T, ConnectionError>>
+{
+    let signal = match signal {
+        Some(s) => s,
+        None => return future.await
+    };
+
+    if signal.aborted() {
+        return Err(ConnectionError::Cancelled("Request was cancelled".into()));
+    }
+
+    let (future, handle) = future::abortable(future);
+    let on_abort: Closure<dyn FnMut()> = Closure::once(move || handle.abort());
+    let _ = signal.add_event_listener_with_callback("abort", on_abort.as_ref().unchecked_ref());
+    let result = future.await;
+    let _ = signal.remove_event_listener_with_callback("abort", on_abort.as_ref().unchecked_ref());
+
+    match result {
+        Ok(result) => result,
+        Err(_) => {
+            conn.recover().await;
+            Err(ConnectionError::Cancelled("Request was cancelled".into()))
+        }
+    }
+}
+
+// Connection errors are passed to JS as Error objects with a stable `code` field to match on
+impl From<ConnectionError> for JsValue {
+    fn from(error: ConnectionError) -> Self {
+        let js_error = js_sys::Error::new(&error.detail().to_string());
+        let _ = js_sys::Reflect::set(&js_error, &JsValue::from("code"), &JsValue::from(error.code()));
+        js_error.into()
+    }
+}
+
 #[wasm_bindgen]
 pub struct ConsoleInterface {
-    connection: Arc<Box<dyn Connection>>
+    connection: Arc<Box<dyn Connection>>,
+    recorder: Arc<Recorder>,
+    watcher: Watcher
 }
 
 #[wasm_bindgen]
@@ -27,28 +81,83 @@ impl ConsoleInterface {
 
     #[wasm_bindgen(constructor)]
     pub fn new(proto: String, uri: Option<String>) -> Self {
-        let protocol = match proto.to_lowercase().as_str() {
-            "sni" => Protocol::Sni,
-            _ => Protocol::Usb2Snes,
-        };
+        let protocol = Protocol::from_name(&proto).unwrap_or(Protocol::Usb2Snes);
 
         log::debug!("Created ConsoleInterface [{:?}] - {:?}", &protocol, &uri);
 
+        let connection = if let Some(uri) = uri {
+            create_connection_with_uri(&protocol, &uri)
+        } else {
+            create_connection(&protocol)
+        };
+
+        // The recorder stays idle until a recording is started
+        let recorder = Arc::new(Recorder::default());
         Self {
-            connection: Arc::new(
-                if let Some(uri) = uri {
-                    create_connection_with_uri(&protocol, &uri)
-                } else {
-                    create_connection(&protocol)
-                }
-            ),
+            connection: Arc::new(Box::new(RecordingConnection::with_recorder(connection, recorder.clone()))),
+            recorder,
+            watcher: Watcher::new()
+        }
+    }
+
+    // A console interface that plays back a trace from stop_recording instead of talking to a device
+    pub fn replay(trace: String) -> Result<ConsoleInterface, JsValue> {
+        let trace = Trace::from_json(&trace)?;
+        Ok(Self {
+            connection: Arc::new(Box::new(ReplayConnection::new(trace))),
+            recorder: Arc::new(Recorder::default()),
+            watcher: Watcher::new()
+        })
+    }
+
+    // Record every call made through this interface from now on, replacing any earlier recording
+    pub fn start_recording(&self) {
+        self.recorder.start();
+    }
+
+    // Stop recording and get the trace as JSON, to attach to a bug report or pass to replay
+    pub fn stop_recording(&self) -> Result<String, JsValue> {
+        Ok(self.recorder.stop().to_json()?)
+    }
+
+    // Look for bridges and emulators, optionally given as a list of { protocol, uri } candidates to try instead of
+    // the defaults. Resolves to the ones that answered, as { protocol, uri, devices } in the order of preference.
+    pub fn discover(candidates: JsValue, timeout_ms: Option<u32>) -> Promise {
+        future_to_promise(async move {
+            let candidates = if candidates.is_undefined() || candidates.is_null() {
+                discovery::default_candidates()
+            } else {
+                serde_wasm_bindgen::from_value(candidates).map_err(|_| JsValue::from("Could not parse discovery candidates"))?
+            };
+
+            let timeout = timeout_ms.map(|ms| Duration::from_millis(ms as u64)).unwrap_or(discovery::DEFAULT_PROBE_TIMEOUT);
+            let bridges = discovery::discover(&candidates, timeout).await;
+            serde_wasm_bindgen::to_value(&bridges).map_err(|_| JsValue::from("Could not parse discovery results"))
+        })
+    }
+
+    // Change the timeout for a kind of operation, leaving it out waits forever
+    pub fn set_timeout(&self, operation: Operation, timeout_ms: Option<u32>) -> Result<(), JsValue> {
+        self.connection.set_timeout(operation, timeout_ms.map(|ms| Duration::from_millis(ms as u64)))?;
+        Ok(())
+    }
+
+    // Latency histograms per operation, byte counts, retries, verification failures and reconnects so far
+    pub fn metrics(&self) -> Result<JsValue, JsValue> {
+        let metrics = self.connection.metrics().ok_or_else(|| JsValue::from(ConnectionError::Unsupported("Metrics are not kept for this connection".into())))?;
+        serde_wasm_bindgen::to_value(&metrics.snapshot()).map_err(|_| JsValue::from("Could not serialize metrics"))
+    }
+
+    pub fn reset_metrics(&self) {
+        if let Some(metrics) = self.connection.metrics() {
+            metrics.reset();
         }
     }
 
     pub fn connect(&self) -> Promise {
         let conn = self.connection.clone();
         future_to_promise(async move {
-            conn.connect().await.map_err(|_| "Could not connect to device")?;
+            conn.connect().await?;
             Ok(JsValue::TRUE)
         })
     }
@@ -56,7 +165,7 @@ impl ConsoleInterface {
     pub fn disconnect(&self) -> Promise {
         let conn = self.connection.clone();
         future_to_promise(async move {
-            conn.disconnect().await.map_err(|_| "Could not disconnect from device")?;
+            conn.disconnect().await?;
             Ok(JsValue::TRUE)
         })
     }
@@ -64,41 +173,211 @@ impl ConsoleInterface {
     pub fn list_devices(&self) -> Promise {
         let conn = self.connection.clone();
         future_to_promise(async move {
-            let devices = conn.list_devices().await.map_err(|e| format!("Device list request failed: {:?}", e))?;
+            let devices = conn.list_devices().await?;
             serde_wasm_bindgen::to_value(&devices).map_err(|_| JsValue::from("Could not parse device list"))
         })
     }
 
-    pub fn read(&self, device: String, address: u32, size: u32) -> Promise {
+    pub fn read(&self, device: String, address: u32, size: u32, signal: Option<AbortSignal>) -> Promise {
         let conn = self.connection.clone();
         future_to_promise(async move {
-            let data = conn.read_single(&device, address, size).await.map_err(|e| format!("Read memory request failed: {:?}", e))?;            
+            let data = cancellable(conn.as_ref().as_ref(), signal, conn.read_single(&device, address, size)).await?;
             Ok(JsValue::from(Uint8Array::from(data.as_slice())))
         })
     }
 
-    pub fn read_multi(&self, device: String, address_info: Vec<u32>) -> Promise {
+    pub fn read_multi(&self, device: String, address_info: Vec<u32>, signal: Option<AbortSignal>) -> Promise {
         let conn = self.connection.clone();
         future_to_promise(async move {
-            let data = conn.read_multi(&device, &address_info).await.map_err(|e| format!("Read memory request failed: {:?}", e))?;
+            let data = cancellable(conn.as_ref().as_ref(), signal, conn.read_multi(&device, &address_info)).await?;
             let js_data = Array::from_iter(data.iter().map(|d| Uint8Array::from(d.as_slice())));
             Ok(JsValue::from(js_data))
         })
     }
 
-    pub fn write(&self, device: String, address: u32, data: Uint8Array) -> Promise {
+    pub fn write(&self, device: String, address: u32, data: Uint8Array, signal: Option<AbortSignal>) -> Promise {
         let conn = self.connection.clone();
         future_to_promise(async move {
-            conn.write_single(&device, address, &data.to_vec()).await.map_err(|e| format!("Write memory request failed: {:?}", e))?;            
+            cancellable(conn.as_ref().as_ref(), signal, conn.write_single(&device, address, &data.to_vec())).await?;
             Ok(JsValue::TRUE)
         })
     }
 
-    pub fn write_multi(&self, device: String, addresses: Vec<u32>, data: Vec<Uint8Array>) -> Promise {
+    pub fn write_multi(&self, device: String, addresses: Vec<u32>, data: Vec<Uint8Array>, signal: Option<AbortSignal>) -> Promise {
         let conn = self.connection.clone();
         future_to_promise(async move {
             let data: Vec<Vec<u8>> = data.iter().map(|d| d.to_vec()).collect();
-            conn.write_multi(&device, &addresses, &data).await.map_err(|e| format!("Write memory request failed: {:?}", e))?;
+            cancellable(conn.as_ref().as_ref(), signal, conn.write_multi(&device, &addresses, &data)).await?;
+            Ok(JsValue::TRUE)
+        })
+    }
+
+    pub fn read_multi_in(&self, device: String, space: AddressSpace, address_info: Vec<u32>, signal: Option<AbortSignal>) -> Promise {
+        let conn = self.connection.clone();
+        future_to_promise(async move {
+            let data = cancellable(conn.as_ref().as_ref(), signal, conn.read_multi_in(&device, space, &address_info)).await?;
+            let js_data = Array::from_iter(data.iter().map(|d| Uint8Array::from(d.as_slice())));
+            Ok(JsValue::from(js_data))
+        })
+    }
+
+    pub fn write_multi_in(&self, device: String, space: AddressSpace, addresses: Vec<u32>, data: Vec<Uint8Array>, signal: Option<AbortSignal>) -> Promise {
+        let conn = self.connection.clone();
+        future_to_promise(async move {
+            let data: Vec<Vec<u8>> = data.iter().map(|d| d.to_vec()).collect();
+            cancellable(conn.as_ref().as_ref(), signal, conn.write_multi_in(&device, space, &addresses, &data)).await?;
+            Ok(JsValue::TRUE)
+        })
+    }
+
+    // Watch the (address, size) ranges, calling the callback with a change event whenever the bytes in them change.
+    // All watches share one polling loop that reads every due range of a device in a single request.
+    // Returns the watch id to pass to unwatch.
+    pub fn watch(&self, device: String, address_info: Vec<u32>, interval_ms: u32, callback: Function) -> Result<u32, JsValue> {
+        let (id, mut events) = self.watcher.watch(&device, &address_info, Duration::from_millis(interval_ms as u64))?;
+
+        spawn_local(async move {
+            while let Some(event) = events.next().await {
+                if let Ok(event) = serde_wasm_bindgen::to_value(&event) {
+                    let _ = callback.call1(&JsValue::NULL, &event);
+                }
+            }
+        });
+
+        if self.watcher.start() {
+            let conn = self.connection.clone();
+            let watcher = self.watcher.clone();
+            spawn_local(async move {
+                watcher.run(conn.as_ref().as_ref()).await;
+            });
+        }
+
+        Ok(id)
+    }
+
+    pub fn unwatch(&self, id: u32) -> bool {
+        self.watcher.unwatch(id)
+    }
+
+    pub fn memory_mapping(&self, device: String) -> Promise {
+        let conn = self.connection.clone();
+        future_to_promise(async move {
+            let mapping = conn.memory_mapping(&device).await?;
+            Ok(JsValue::from(mapping as u32))
+        })
+    }
+
+    // Pass no mapping to go back to detecting it from the running ROM
+    pub fn set_memory_mapping(&self, device: String, mapping: Option<MemoryMapping>) -> Promise {
+        let conn = self.connection.clone();
+        future_to_promise(async move {
+            let mapping = mapping.map(MappingOverride::Fixed).unwrap_or(MappingOverride::Detect);
+            conn.set_memory_mapping(&device, mapping).await?;
+            Ok(JsValue::TRUE)
+        })
+    }
+
+    pub fn set_memory_mapping_hint(&self, device: String, fallback: Option<MemoryMapping>, rom_header: Option<Uint8Array>) -> Promise {
+        let conn = self.connection.clone();
+        future_to_promise(async move {
+            conn.set_memory_mapping(&device, MappingOverride::Hint { fallback, rom_header: rom_header.map(|h| h.to_vec()) }).await?;
+            Ok(JsValue::TRUE)
+        })
+    }
+
+    pub fn list_files(&self, device: String, path: String) -> Promise {
+        let conn = self.connection.clone();
+        future_to_promise(async move {
+            let files = conn.require_filesystem()?.list_files(&device, &path).await?;
+            serde_wasm_bindgen::to_value(&files).map_err(|_| JsValue::from("Could not parse file list"))
+        })
+    }
+
+    pub fn get_file(&self, device: String, path: String, signal: Option<AbortSignal>) -> Promise {
+        let conn = self.connection.clone();
+        future_to_promise(async move {
+            let data = cancellable(conn.as_ref().as_ref(), signal, conn.require_filesystem()?.get_file(&device, &path)).await?;
+            Ok(JsValue::from(Uint8Array::from(data.as_slice())))
+        })
+    }
+
+    // The optional progress callback is called with (bytes sent, total bytes) during the upload
+    pub fn put_file(&self, device: String, path: String, data: Uint8Array, progress: Option<Function>, signal: Option<AbortSignal>) -> Promise {
+        let conn = self.connection.clone();
+        future_to_promise(async move {
+            let fs = conn.require_filesystem()?;
+            let data = data.to_vec();
+            match progress {
+                Some(callback) => {
+                    let report = |sent: usize, total: usize| { let _ = callback.call2(&JsValue::NULL, &JsValue::from(sent as u32), &JsValue::from(total as u32)); };
+                    cancellable(conn.as_ref().as_ref(), signal, fs.put_file_with_progress(&device, &path, &data, &report)).await?
+                },
+                None => cancellable(conn.as_ref().as_ref(), signal, fs.put_file(&device, &path, &data)).await?
+            }
+            Ok(JsValue::TRUE)
+        })
+    }
+
+    pub fn remove_file(&self, device: String, path: String) -> Promise {
+        let conn = self.connection.clone();
+        future_to_promise(async move {
+            conn.require_filesystem()?.remove_file(&device, &path).await?;
+            Ok(JsValue::TRUE)
+        })
+    }
+
+    pub fn rename_file(&self, device: String, path: String, new_path: String) -> Promise {
+        let conn = self.connection.clone();
+        future_to_promise(async move {
+            conn.require_filesystem()?.rename_file(&device, &path, &new_path).await?;
+            Ok(JsValue::TRUE)
+        })
+    }
+
+    pub fn make_directory(&self, device: String, path: String) -> Promise {
+        let conn = self.connection.clone();
+        future_to_promise(async move {
+            conn.require_filesystem()?.make_directory(&device, &path).await?;
+            Ok(JsValue::TRUE)
+        })
+    }
+
+    pub fn reset(&self, device: String) -> Promise {
+        let conn = self.connection.clone();
+        future_to_promise(async move {
+            conn.require_control()?.reset(&device).await?;
+            Ok(JsValue::TRUE)
+        })
+    }
+
+    pub fn reset_to_menu(&self, device: String) -> Promise {
+        let conn = self.connection.clone();
+        future_to_promise(async move {
+            conn.require_control()?.reset_to_menu(&device).await?;
+            Ok(JsValue::TRUE)
+        })
+    }
+
+    pub fn boot(&self, device: String, path: String) -> Promise {
+        let conn = self.connection.clone();
+        future_to_promise(async move {
+            conn.require_control()?.boot(&device, &path).await?;
+            Ok(JsValue::TRUE)
+        })
+    }
+
+    pub fn pause_emulation(&self, device: String, paused: bool) -> Promise {
+        let conn = self.connection.clone();
+        future_to_promise(async move {
+            let paused = conn.require_control()?.pause_emulation(&device, paused).await?;
+            Ok(JsValue::from(paused))
+        })
+    }
+
+    pub fn toggle_pause_emulation(&self, device: String) -> Promise {
+        let conn = self.connection.clone();
+        future_to_promise(async move {
+            conn.require_control()?.toggle_pause_emulation(&device).await?;
             Ok(JsValue::TRUE)
         })
     }
@@ -0,0 +1,275 @@
+use crate::protocols::protocol::{AddressSpace, ConnectionError, MemoryMapping};
+
+// Translation between SNES A-bus addresses and the FxPakPro linear address space.
+//
+// FxPakPro layout:
+//   $00_0000..$DF_FFFF  ROM, linearly mapped
+//   $E0_0000..$EF_FFFF  SRAM, linearly mapped
+//   $F5_0000..$F6_FFFF  WRAM, linearly mapped
+//   $F7_0000..          VRAM, APU, CGRAM, OAM and registers, which are not visible on the A-bus
+
+const ROM_END: u32 = 0xE0_0000;
+const SRAM_START: u32 = 0xE0_0000;
+const SRAM_END: u32 = 0xF0_0000;
+const WRAM_START: u32 = 0xF5_0000;
+const WRAM_END: u32 = 0xF7_0000;
+
+// Locations of the internal ROM header for each mapping, in FxPakPro addresses
+pub const LOROM_HEADER: u32 = 0x00_7FC0;
+pub const HIROM_HEADER: u32 = 0x00_FFC0;
+pub const EXHIROM_HEADER: u32 = 0x40_FFC0;
+pub const HEADER_SIZE: u32 = 0x20;
+
+fn unmapped(address: u32, space: &str) -> ConnectionError {
+    ConnectionError::InvalidRequest(format!("Address {:06X} is not mapped in the {} address space", address, space).into())
+}
+
+fn unknown_mapping() -> ConnectionError {
+    ConnectionError::MappingDetectFailed("Address translation requires a known memory mapping".into())
+}
+
+// Convert a SNES A-bus address to an FxPakPro address for the given mapping
+pub fn bus_to_fxpak(address: u32, mapping: MemoryMapping) -> Result<u32, ConnectionError> {
+    let bank = (address >> 16) & 0xFF;
+    let offset = address & 0xFFFF;
+
+    if address > 0xFF_FFFF {
+        return Err(unmapped(address, "SNES A-bus"));
+    }
+
+    // WRAM is in the same place regardless of mapping, and banks $00-$3F/$80-$BF mirror the first 8KB
+    if bank == 0x7E || bank == 0x7F {
+        return Ok(WRAM_START + (bank - 0x7E) * 0x1_0000 + offset);
+    }
+
+    if bank & 0x7F < 0x40 && offset < 0x2000 {
+        return Ok(WRAM_START + offset);
+    }
+
+    match mapping {
+        MemoryMapping::LoRom => {
+            if offset >= 0x8000 {
+                Ok((bank & 0x7F) * 0x8000 + (offset - 0x8000))
+            } else if (0x70..0x7E).contains(&(bank & 0x7F)) {
+                Ok(SRAM_START + ((bank & 0x7F) - 0x70) * 0x8000 + offset)
+            } else {
+                Err(unmapped(address, "SNES A-bus"))
+            }
+        },
+        MemoryMapping::HiRom | MemoryMapping::ExHiRom => {
+            // ExHiROM puts the upper 4MB of ROM in the banks that mirror the lower 4MB with HiROM
+            let upper = if mapping == MemoryMapping::ExHiRom && bank < 0x80 { 0x40_0000 } else { 0 };
+            if (0x40..0x7E).contains(&bank) || bank >= 0xC0 || offset >= 0x8000 {
+                Ok(upper + (bank & 0x3F) * 0x1_0000 + offset)
+            } else if (0x20..0x40).contains(&(bank & 0x7F)) && (0x6000..0x8000).contains(&offset) {
+                Ok(SRAM_START + ((bank & 0x7F) - 0x20) * 0x2000 + (offset - 0x6000))
+            } else {
+                Err(unmapped(address, "SNES A-bus"))
+            }
+        },
+        MemoryMapping::Unknown => Err(unknown_mapping())
+    }
+}
+
+// Convert an FxPakPro address to a SNES A-bus address for the given mapping.
+// ROM is mapped to the $80-$FF FastROM banks, SRAM and WRAM to their canonical locations.
+// WRAM is in the same place for every mapping, so it can be translated even when the mapping is unknown.
+pub fn fxpak_to_bus(address: u32, mapping: MemoryMapping) -> Result<u32, ConnectionError> {
+    if (WRAM_START..WRAM_END).contains(&address) {
+        return Ok(0x7E_0000 + (address - WRAM_START));
+    }
+
+    if mapping == MemoryMapping::Unknown {
+        return Err(unknown_mapping());
+    }
+
+    if (SRAM_START..SRAM_END).contains(&address) {
+        let sram = address - SRAM_START;
+        return match mapping {
+            MemoryMapping::LoRom if sram < 0x7_0000 => Ok(((0x70 + sram / 0x8000) << 16) | (sram % 0x8000)),
+            MemoryMapping::HiRom | MemoryMapping::ExHiRom if sram < 0x4_0000 => Ok(((0xA0 + sram / 0x2000) << 16) | (0x6000 + sram % 0x2000)),
+            _ => Err(unmapped(address, "FxPakPro"))
+        };
+    }
+
+    if address < ROM_END {
+        return match mapping {
+            MemoryMapping::LoRom if address < 0x40_0000 => Ok(((0x80 + address / 0x8000) << 16) | (0x8000 + address % 0x8000)),
+            MemoryMapping::HiRom | MemoryMapping::ExHiRom if address < 0x40_0000 => Ok(0xC0_0000 + address),
+            MemoryMapping::ExHiRom if address < 0x7E_0000 => Ok(address),
+            // The last 128KB of an 8MB ExHiROM is only visible in the upper half of banks $3E-$3F
+            MemoryMapping::ExHiRom if address < 0x80_0000 && address & 0x8000 != 0 => Ok(address - 0x40_0000),
+            _ => Err(unmapped(address, "FxPakPro"))
+        };
+    }
+
+    Err(unmapped(address, "FxPakPro"))
+}
+
+// Convert an address in the FxPakPro or SNES A-bus address space to the FxPakPro address space.
+// Raw addresses only mean something to the device itself, so they can't be translated.
+pub fn to_fxpak(address: u32, space: AddressSpace, mapping: MemoryMapping) -> Result<u32, ConnectionError> {
+    match space {
+        AddressSpace::FxPakPro => Ok(address),
+        AddressSpace::SnesABus => bus_to_fxpak(address, mapping),
+        AddressSpace::Raw => Err(ConnectionError::Unsupported(format!("Raw address {:06X} can not be translated to the FxPakPro address space", address).into()))
+    }
+}
+
+// Same as to_fxpak, but makes sure the whole range is contiguous in the FxPakPro address space,
+// since a range on the bus can cross into a bank that is mapped somewhere else entirely.
+pub fn range_to_fxpak(address: u32, size: u32, space: AddressSpace, mapping: MemoryMapping) -> Result<u32, ConnectionError> {
+    let start = to_fxpak(address, space, mapping)?;
+    if size > 1 && space == AddressSpace::SnesABus {
+        let end = address.checked_add(size - 1).ok_or_else(|| unmapped(address, "SNES A-bus"))?;
+        if to_fxpak(end, space, mapping)? != start + (size - 1) {
+            return Err(ConnectionError::InvalidRequest(format!("Request at {:06X} with size {:X} is not contiguous in the FxPakPro address space", address, size).into()));
+        }
+    }
+    Ok(start)
+}
+
+// Translate a flat list of (address, size) pairs, like read_multi takes them, to the FxPakPro address space
+pub fn ranges_to_fxpak(address_info: &[u32], space: AddressSpace, mapping: MemoryMapping) -> Result<Vec<u32>, ConnectionError> {
+    let mut translated = Vec::with_capacity(address_info.len());
+    for req in address_info.chunks(2) {
+        let size = *req.get(1).unwrap_or(&0);
+        translated.push(range_to_fxpak(req[0], size, space, mapping)?);
+        translated.push(size);
+    }
+    Ok(translated)
+}
+
+// Translate the addresses of a write_multi to the FxPakPro address space
+pub fn writes_to_fxpak(addresses: &[u32], data: &[Vec<u8>], space: AddressSpace, mapping: MemoryMapping) -> Result<Vec<u32>, ConnectionError> {
+    addresses.iter().zip(data.iter())
+        .map(|(a, d)| range_to_fxpak(*a, d.len() as u32, space, mapping))
+        .collect()
+}
+
+// Guess the memory mapping from the ROM headers read at LOROM_HEADER, HIROM_HEADER and EXHIROM_HEADER.
+// A header counts when its map mode byte matches its location and the checksum and complement add up.
+pub fn detect_mapping(headers: &[Vec<u8>]) -> MemoryMapping {
+    // (mapping, index into headers, expected map mode)
+    let candidates = [(MemoryMapping::ExHiRom, 2, 0x05), (MemoryMapping::HiRom, 1, 0x01), (MemoryMapping::LoRom, 0, 0x00)];
+    let valid = |header: &[u8], mode: u8| {
+        header.len() >= HEADER_SIZE as usize
+            && header[0x15] & 0x0F == mode
+            && u16::from_le_bytes([header[0x1C], header[0x1D]]) ^ u16::from_le_bytes([header[0x1E], header[0x1F]]) == 0xFFFF
+    };
+
+    candidates.iter()
+        .find(|(_, index, mode)| headers.get(*index).map(|h| valid(h, *mode)).unwrap_or(false))
+        .map(|(mapping, _, _)| *mapping)
+        .unwrap_or(MemoryMapping::Unknown)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    const MAPPINGS: [MemoryMapping; 3] = [MemoryMapping::LoRom, MemoryMapping::HiRom, MemoryMapping::ExHiRom];
+
+    fn header(mode: u8, checksum: u16) -> Vec<u8> {
+        let mut header = vec![0; HEADER_SIZE as usize];
+        header[0x15] = mode;
+        header[0x1C..0x1E].copy_from_slice(&(checksum ^ 0xFFFF).to_le_bytes());
+        header[0x1E..0x20].copy_from_slice(&checksum.to_le_bytes());
+        header
+    }
+
+    #[test]
+    fn known_addresses() {
+        assert_eq!(bus_to_fxpak(0x00_8000, MemoryMapping::LoRom).unwrap(), 0x00_0000);
+        assert_eq!(bus_to_fxpak(0x80_FFC0, MemoryMapping::LoRom).unwrap(), LOROM_HEADER);
+        assert_eq!(bus_to_fxpak(0x70_0010, MemoryMapping::LoRom).unwrap(), 0xE0_0010);
+        assert_eq!(bus_to_fxpak(0xC0_FFC0, MemoryMapping::HiRom).unwrap(), HIROM_HEADER);
+        assert_eq!(bus_to_fxpak(0x20_6000, MemoryMapping::HiRom).unwrap(), 0xE0_0000);
+        assert_eq!(bus_to_fxpak(0x40_FFC0, MemoryMapping::ExHiRom).unwrap(), EXHIROM_HEADER);
+        assert_eq!(bus_to_fxpak(0xC0_FFC0, MemoryMapping::ExHiRom).unwrap(), HIROM_HEADER);
+        assert_eq!(bus_to_fxpak(0x3F_FFFF, MemoryMapping::ExHiRom).unwrap(), 0x7F_FFFF);
+
+        for mapping in MAPPINGS.iter().chain(&[MemoryMapping::Unknown]) {
+            assert_eq!(bus_to_fxpak(0x7E_0000, *mapping).unwrap(), 0xF5_0000);
+            assert_eq!(bus_to_fxpak(0x80_1FFF, *mapping).unwrap(), 0xF5_1FFF);
+            assert_eq!(fxpak_to_bus(0xF6_FFFF, *mapping).unwrap(), 0x7F_FFFF);
+        }
+    }
+
+    #[test]
+    fn unknown_mapping_only_translates_wram() {
+        assert!(matches!(bus_to_fxpak(0x80_8000, MemoryMapping::Unknown), Err(ConnectionError::MappingDetectFailed(_))));
+        assert!(matches!(fxpak_to_bus(0x00_0000, MemoryMapping::Unknown), Err(ConnectionError::MappingDetectFailed(_))));
+    }
+
+    #[test]
+    fn every_rom_and_sram_address_round_trips() {
+        // (mapping, ROM size, SRAM size) that every address below must translate for
+        let sizes = [(MemoryMapping::LoRom, 0x40_0000, 0x7_0000), (MemoryMapping::HiRom, 0x40_0000, 0x4_0000), (MemoryMapping::ExHiRom, 0x7E_0000, 0x4_0000)];
+        for (mapping, rom, sram) in sizes.iter() {
+            for address in (0..*rom).step_by(0x7F).chain(SRAM_START..SRAM_START + sram).chain(WRAM_START..WRAM_END) {
+                let bus = fxpak_to_bus(address, *mapping).unwrap_or_else(|e| panic!("{:06X} in {:?}: {}", address, mapping, e));
+                assert_eq!(bus_to_fxpak(bus, *mapping).unwrap(), address, "{:06X} -> {:06X} in {:?}", address, bus, mapping);
+            }
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn fxpak_to_bus_round_trips(address in 0..0x100_0000u32, mapping in 0..3usize) {
+            let mapping = MAPPINGS[mapping];
+            if let Ok(bus) = fxpak_to_bus(address, mapping) {
+                prop_assert_eq!(bus_to_fxpak(bus, mapping).unwrap(), address);
+            }
+        }
+
+        #[test]
+        fn bus_to_fxpak_round_trips(address in 0..0x100_0000u32, mapping in 0..3usize) {
+            // Mirrors translate to the same FxPakPro address, which has to lead back to itself
+            let mapping = MAPPINGS[mapping];
+            if let Ok(fxpak) = bus_to_fxpak(address, mapping) {
+                if let Ok(bus) = fxpak_to_bus(fxpak, mapping) {
+                    prop_assert_eq!(bus_to_fxpak(bus, mapping).unwrap(), fxpak);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn ranges_must_be_contiguous() {
+        assert_eq!(range_to_fxpak(0x80_FFF0, 0x10, AddressSpace::SnesABus, MemoryMapping::LoRom).unwrap(), 0x00_7FF0);
+        assert!(matches!(range_to_fxpak(0x80_FFFF, 2, AddressSpace::SnesABus, MemoryMapping::LoRom), Err(ConnectionError::InvalidRequest(_))));
+        assert_eq!(range_to_fxpak(0x00_FFFF, 2, AddressSpace::FxPakPro, MemoryMapping::Unknown).unwrap(), 0x00_FFFF);
+    }
+
+    #[test]
+    fn raw_addresses_are_not_translated() {
+        assert!(matches!(to_fxpak(0x00_0000, AddressSpace::Raw, MemoryMapping::LoRom), Err(ConnectionError::Unsupported(_))));
+        assert!(matches!(ranges_to_fxpak(&[0x00_0000, 1], AddressSpace::Raw, MemoryMapping::LoRom), Err(ConnectionError::Unsupported(_))));
+    }
+
+    #[test]
+    fn detects_mapping_from_headers() {
+        let invalid = vec![0; HEADER_SIZE as usize];
+        assert_eq!(detect_mapping(&[header(0x20, 0x1234), invalid.clone(), invalid.clone()]), MemoryMapping::LoRom);
+        assert_eq!(detect_mapping(&[header(0x30, 0x1234), invalid.clone(), invalid.clone()]), MemoryMapping::LoRom);
+        assert_eq!(detect_mapping(&[invalid.clone(), header(0x31, 0x1234), invalid.clone()]), MemoryMapping::HiRom);
+        assert_eq!(detect_mapping(&[invalid.clone(), header(0x21, 0x1234), header(0x35, 0x5678)]), MemoryMapping::ExHiRom);
+        // Both headers look valid, the ExHiROM one wins
+        assert_eq!(detect_mapping(&[invalid.clone(), header(0x35, 0x5678), header(0x35, 0x5678)]), MemoryMapping::ExHiRom);
+    }
+
+    #[test]
+    fn rejects_headers_in_the_wrong_place_or_with_a_bad_checksum() {
+        let invalid = vec![0; HEADER_SIZE as usize];
+        assert_eq!(detect_mapping(&[header(0x21, 0x1234), invalid.clone(), invalid.clone()]), MemoryMapping::Unknown);
+        assert_eq!(detect_mapping(&[invalid.clone(), header(0x20, 0x1234), invalid.clone()]), MemoryMapping::Unknown);
+
+        let mut corrupt = header(0x20, 0x1234);
+        corrupt[0x1C] ^= 1;
+        assert_eq!(detect_mapping(&[corrupt, invalid.clone(), invalid.clone()]), MemoryMapping::Unknown);
+        assert_eq!(detect_mapping(&[header(0x20, 0x1234)[..0x10].to_vec()]), MemoryMapping::Unknown);
+        assert_eq!(detect_mapping(&[]), MemoryMapping::Unknown);
+    }
+}
@@ -0,0 +1,79 @@
+// Looking for bridges and emulators to connect to. Every candidate is probed at the same time, and every
+// candidate that answers is reported together with its devices, in the order the candidates were given
+// so the caller can pick by preference.
+
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::protocols::protocol::{create_connection_with_uri, Connection, ConnectionError, Device, Protocol};
+use crate::protocols::timeout::deadline;
+
+// How long a candidate gets to connect and list its devices before it's given up on
+pub const DEFAULT_PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Candidate {
+    pub protocol: Protocol,
+    pub uri: String
+}
+
+impl Candidate {
+    pub fn new(protocol: Protocol, uri: &str) -> Self {
+        Self { protocol, uri: uri.to_string() }
+    }
+}
+
+// A candidate that answered, with the connection to it so it can be used right away
+#[derive(Serialize)]
+pub struct Bridge {
+    pub protocol: Protocol,
+    pub uri: String,
+    pub devices: Vec<Device>,
+    #[serde(skip)]
+    pub connection: Box<dyn Connection>
+}
+
+// SNI first, then usb2snes on the current and the legacy port. Native builds can also reach emulators directly.
+pub fn default_candidates() -> Vec<Candidate> {
+    let mut candidates = vec![
+        Candidate::new(Protocol::Sni, "http://127.0.0.1:8190"),
+        Candidate::new(Protocol::Usb2Snes, "ws://localhost:23074"),
+        Candidate::new(Protocol::Usb2Snes, "ws://localhost:8080")
+    ];
+
+    if cfg!(feature = "native") {
+        candidates.extend(vec![
+            Candidate::new(Protocol::RetroArch, "udp://127.0.0.1:55355"),
+            Candidate::new(Protocol::Nwa, "nwa://127.0.0.1")
+        ]);
+    }
+    candidates
+}
+
+async fn connect_and_list(connection: &dyn Connection) -> Result<Vec<Device>, ConnectionError> {
+    connection.connect().await?;
+    connection.list_devices().await
+}
+
+async fn probe(candidate: &Candidate, timeout: Duration) -> Result<Bridge, ConnectionError> {
+    let connection = create_connection_with_uri(&candidate.protocol, &candidate.uri);
+    let devices = deadline(Some(timeout), connect_and_list(connection.as_ref())).await?;
+
+    Ok(Bridge { protocol: candidate.protocol, uri: candidate.uri.to_string(), devices, connection })
+}
+
+// Probe all candidates in parallel, returning the ones that answered in the order they were given
+pub async fn discover(candidates: &[Candidate], timeout: Duration) -> Vec<Bridge> {
+    let results = join_all(candidates.iter().map(|c| probe(c, timeout))).await;
+    candidates.iter().zip(results).filter_map(|(candidate, result)| match result {
+        Ok(bridge) => {
+            log::debug!("discovery: Found {:?} at {} with {} devices", candidate.protocol, candidate.uri, bridge.devices.len());
+            Some(bridge)
+        },
+        Err(e) => {
+            log::debug!("discovery: No {:?} at {}: {}", candidate.protocol, candidate.uri, e);
+            None
+        }
+    }).collect()
+}
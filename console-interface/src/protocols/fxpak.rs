@@ -0,0 +1,649 @@
+use async_trait::async_trait;
+use futures::lock::Mutex;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::protocols::planner::{Limits, ReadPlan, WritePlan};
+use crate::protocols::protocol::{AddressSpace, Capability, Connection, ConnectionError, ControlConnection, Device, FileEntry, FileType, FilesystemConnection};
+use crate::protocols::serial::SerialPort;
+
+// The FX Pak Pro's own USB-CDC protocol, which is what usb2snes servers use to talk to the cart.
+//
+// Every command is a 512 byte packet starting with "USBA", the opcode, the address space and flags, with the
+// size at 252 and the address or file name at 256. Vectored commands use a 64 byte packet instead, with up to
+// 8 (size, 24-bit address) entries at 32. The cart answers with a 512 byte response header that has the
+// size of the data at 252, and the data follows in blocks padded to 512 bytes, or 64 bytes for vectored commands.
+// Data sent to the cart uses the same blocks, after the response header has been received.
+
+const BLOCK_SIZE: usize = 512;
+const VECTOR_BLOCK_SIZE: usize = 64;
+
+// A VGET/VPUT entry has a single byte for the size
+const LIMITS: Limits = Limits { max_ranges: 8, max_range_size: 255, max_batch_size: 8 * 255, max_single_size: None };
+
+// Largest file name that fits in a packet, after the null terminator
+const MAX_PATH_LENGTH: usize = 255;
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Opcode {
+    Get = 0,
+    Put = 1,
+    VGet = 2,
+    VPut = 3,
+    Ls = 4,
+    Mkdir = 5,
+    Rm = 6,
+    Mv = 7,
+    Reset = 8,
+    Boot = 9,
+    PowerCycle = 10,
+    Info = 11,
+    MenuReset = 12,
+    Stream = 13,
+    Time = 14,
+    Response = 15
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Space {
+    File = 0,
+    Snes = 1
+}
+
+const FLAG_NONE: u8 = 0;
+const FLAG_DATA64B: u8 = 128;
+
+// LS entry types, the list is spread over blocks and NEXT_BLOCK skips to the next one
+const LS_DIRECTORY: u8 = 0;
+const LS_FILE: u8 = 1;
+const LS_END: u8 = 2;
+const LS_NEXT_BLOCK: u8 = 0xFF;
+
+struct Packet(Vec<u8>);
+
+impl Packet {
+    fn new(opcode: Opcode, space: Space, flags: u8) -> Self {
+        let mut data = vec![0; if flags & FLAG_DATA64B != 0 { VECTOR_BLOCK_SIZE } else { BLOCK_SIZE }];
+        data[0..4].copy_from_slice(b"USBA");
+        data[4] = opcode as u8;
+        data[5] = space as u8;
+        data[6] = flags;
+        Self(data)
+    }
+
+    fn size(mut self, size: u32) -> Self {
+        self.0[252..256].copy_from_slice(&size.to_be_bytes());
+        self
+    }
+
+    fn address(mut self, address: u32) -> Self {
+        self.0[256..260].copy_from_slice(&address.to_be_bytes());
+        self
+    }
+
+    fn path_at(mut self, offset: usize, path: &str) -> Result<Self, ConnectionError> {
+        if path.len() > MAX_PATH_LENGTH || offset + path.len() >= self.0.len() {
+            return Err(ConnectionError::InvalidRequest(format!("Path is too long: {}", path).into()));
+        }
+        self.0[offset..offset + path.len()].copy_from_slice(path.as_bytes());
+        Ok(self)
+    }
+
+    fn path(self, path: &str) -> Result<Self, ConnectionError> {
+        self.path_at(256, path)
+    }
+
+    // Vectored entries are (size, 24-bit big endian address)
+    fn ranges(mut self, ranges: &[(u32, u32)]) -> Self {
+        for (i, (address, size)) in ranges.iter().enumerate() {
+            let entry = 32 + i * 4;
+            self.0[entry] = *size as u8;
+            self.0[entry + 1..entry + 4].copy_from_slice(&address.to_be_bytes()[1..]);
+        }
+        self
+    }
+}
+
+// Round a size up to a whole number of blocks
+fn whole_blocks(size: usize, block_size: usize) -> usize {
+    (size + block_size - 1) / block_size * block_size
+}
+
+// Pad data to a whole number of blocks
+fn padded(data: &[u8], block_size: usize) -> Vec<u8> {
+    let mut padded = data.to_vec();
+    padded.resize(whole_blocks(data.len(), block_size), 0);
+    padded
+}
+
+fn c_string(data: &[u8]) -> String {
+    String::from_utf8_lossy(data.split(|b| *b == 0).next().unwrap_or_default()).to_string()
+}
+
+// Send a command and read the response header, returning the size of the data that follows
+async fn exchange(port: &mut SerialPort, packet: &Packet) -> Result<(Vec<u8>, usize), ConnectionError> {
+    port.send(&packet.0).await?;
+    let header = port.read_exact(BLOCK_SIZE).await?;
+    if &header[0..4] != b"USBA" || header[4] != Opcode::Response as u8 {
+        return Err(ConnectionError::ProtocolViolation("Invalid response header from FX Pak Pro".into()));
+    }
+    if header[5] != 0 {
+        return Err(ConnectionError::InvalidRequest(format!("FX Pak Pro rejected the command with error {}", header[5]).into()));
+    }
+
+    let size = u32::from_be_bytes([header[252], header[253], header[254], header[255]]) as usize;
+    Ok((header, size))
+}
+
+async fn receive(port: &mut SerialPort, size: usize, block_size: usize) -> Result<Vec<u8>, ConnectionError> {
+    let mut data = port.read_exact(whole_blocks(size, block_size)).await?;
+    data.truncate(size);
+    Ok(data)
+}
+
+async fn get_data(port: &mut SerialPort, packet: &Packet, block_size: usize) -> Result<Vec<u8>, ConnectionError> {
+    let (_, size) = exchange(port, packet).await?;
+    receive(port, size, block_size).await
+}
+
+async fn put_data(port: &mut SerialPort, packet: &Packet, data: &[u8], block_size: usize, progress: Option<&dyn Fn(usize, usize)>) -> Result<(), ConnectionError> {
+    exchange(port, packet).await?;
+    let data = padded(data, block_size);
+    // Send in bigger pieces than a block, but small enough to report progress along the way
+    for (i, chunk) in data.chunks(BLOCK_SIZE * 64).enumerate() {
+        port.send(chunk).await?;
+        if let Some(progress) = progress {
+            progress(((i + 1) * BLOCK_SIZE * 64).min(data.len()), data.len());
+        }
+    }
+    Ok(())
+}
+
+// Send an LS command and read the blocks of entries until the end of the list
+async fn receive_list(port: &mut SerialPort, packet: &Packet) -> Result<Vec<FileEntry>, ConnectionError> {
+    exchange(port, packet).await?;
+    let mut entries = Vec::new();
+    loop {
+        let block = port.read_exact(BLOCK_SIZE).await?;
+        let mut position = 0;
+        while position < block.len() {
+            match block[position] {
+                LS_END => return Ok(entries),
+                LS_NEXT_BLOCK => break,
+                file_type @ (LS_DIRECTORY | LS_FILE) => {
+                    let name = c_string(&block[position + 1..]);
+                    position += name.len() + 2;
+                    if name != "." && name != ".." {
+                        entries.push(FileEntry { name, file_type: if file_type == LS_DIRECTORY { FileType::Directory } else { FileType::File } });
+                    }
+                },
+                t => return Err(ConnectionError::ProtocolViolation(format!("Unknown directory entry type: {:02X}", t).into()))
+            }
+        }
+    }
+}
+
+// Open the serial port of the device if it isn't open already
+async fn open<'a>(port: &'a mut Option<SerialPort>, device: &str) -> Result<&'a mut SerialPort, ConnectionError> {
+    if port.is_none() {
+        *port = Some(SerialPort::open(device).await?);
+        log::debug!("fxpak: Opened {}", device);
+    }
+    port.as_mut().ok_or_else(|| ConnectionError::Disconnected("Could not get serial port".into()))
+}
+
+// Close the port if a command failed part way, since there's no telling how much of the exchange is left on the line
+fn release<T>(port: &mut Option<SerialPort>, result: &Result<T, ConnectionError>) {
+    if let Err(ConnectionError::Disconnected(_)) | Err(ConnectionError::ProtocolViolation(_)) = result {
+        *port = None;
+    }
+}
+
+type Slot = Arc<Mutex<Option<SerialPort>>>;
+
+pub struct FxPakConnection {
+    // Serial port to use, or None to use every FX Pak Pro that is plugged in
+    path: Option<String>,
+    ports: std::sync::Mutex<HashMap<String, Slot>>
+}
+
+impl FxPakConnection {
+    // The uri is "fxpak://" to find the carts automatically, or "fxpak://" followed by the serial port to use
+    pub fn new(uri: &str) -> Self {
+        let path = uri.trim_start_matches("fxpak://");
+        Self {
+            path: if path.is_empty() { None } else { Some(path.to_string()) },
+            ports: std::sync::Mutex::new(HashMap::new())
+        }
+    }
+
+    fn slot(&self, device: &str) -> Slot {
+        self.ports.lock().unwrap().entry(device.to_string()).or_insert_with(|| Arc::new(Mutex::new(None))).clone()
+    }
+
+    // Send a command and read back the data that comes with the response
+    async fn get(&self, device: &str, packet: Packet, block_size: usize) -> Result<Vec<u8>, ConnectionError> {
+        let slot = self.slot(device);
+        let mut port = slot.lock().await;
+        let result = get_data(open(&mut port, device).await?, &packet, block_size).await;
+        release(&mut port, &result);
+        result
+    }
+
+    // Send a command followed by its data
+    async fn put(&self, device: &str, packet: Packet, data: &[u8], block_size: usize, progress: Option<&dyn Fn(usize, usize)>) -> Result<(), ConnectionError> {
+        let slot = self.slot(device);
+        let mut port = slot.lock().await;
+        let result = put_data(open(&mut port, device).await?, &packet, data, block_size, progress).await;
+        release(&mut port, &result);
+        result
+    }
+
+    // Send a command that has no data in either direction
+    async fn command(&self, device: &str, packet: Packet) -> Result<(), ConnectionError> {
+        self.get(device, packet, BLOCK_SIZE).await.map(|_| ())
+    }
+
+    async fn info(&self, device: &str) -> Result<Device, ConnectionError> {
+        let slot = self.slot(device);
+        let mut port = slot.lock().await;
+        let result = exchange(open(&mut port, device).await?, &Packet::new(Opcode::Info, Space::Snes, FLAG_NONE)).await;
+        release(&mut port, &result);
+        let (header, _) = result?;
+
+        let firmware = u32::from_be_bytes([header[256], header[257], header[258], header[259]]);
+        let rom_name = c_string(&header[16..252]);
+        Ok(Device {
+            name: format!("FX Pak Pro ({})", device),
+            uri: device.to_string(),
+            kind: "fxpakpro".into(),
+            firmware_version: Some(format!("{:X} {}", firmware, c_string(&header[260..]))),
+            rom_name: if rom_name.is_empty() || rom_name == "No Info" { None } else { Some(rom_name) },
+            default_address_space: AddressSpace::FxPakPro,
+            capabilities: [Capability::ReadMemory, Capability::WriteMemory, Capability::ResetSystem, Capability::ResetToMenu,
+                           Capability::ReadDirectory, Capability::MakeDirectory, Capability::RemoveFile, Capability::RenameFile,
+                           Capability::PutFile, Capability::GetFile, Capability::BootFile].iter().copied().collect()
+        })
+    }
+}
+
+#[async_trait(?Send)]
+impl Connection for FxPakConnection {
+    async fn connect(&self) -> Result<bool, ConnectionError> {
+        if self.list_devices().await?.is_empty() {
+            return Err(ConnectionError::ConnectFailed("No FX Pak Pro found".into()));
+        }
+        Ok(true)
+    }
+
+    async fn disconnect(&self) -> Result<bool, ConnectionError> {
+        self.ports.lock().unwrap().clear();
+        Ok(true)
+    }
+
+    async fn list_devices(&self) -> Result<Vec<Device>, ConnectionError> {
+        let paths = match &self.path {
+            Some(path) => vec![path.to_string()],
+            None => SerialPort::available()
+        };
+
+        let mut devices = Vec::new();
+        for path in paths {
+            match self.info(&path).await {
+                Ok(device) => devices.push(device),
+                Err(e) => log::debug!("fxpak: No answer from {}: {}", path, e)
+            }
+        }
+        Ok(devices)
+    }
+
+    async fn read_single(&self, device: &str, address: u32, size: u32) -> Result<Vec<u8>, ConnectionError> {
+        Ok(self.read_multi(device, &[address, size]).await?.remove(0))
+    }
+
+    async fn read_multi(&self, device: &str, address_info: &[u32]) -> Result<Vec<Vec<u8>>, ConnectionError> {
+        let plan = ReadPlan::new(address_info, &LIMITS);
+        let mut responses = Vec::new();
+        for batch in plan.batches() {
+            let ranges: Vec<(u32, u32)> = batch.chunks(2).map(|r| (r[0], r[1])).collect();
+            responses.push(match ranges.as_slice() {
+                [(address, size)] => self.get(device, Packet::new(Opcode::Get, Space::Snes, FLAG_NONE).size(*size).address(*address), BLOCK_SIZE).await?,
+                _ => self.get(device, Packet::new(Opcode::VGet, Space::Snes, FLAG_DATA64B).ranges(&ranges), VECTOR_BLOCK_SIZE).await?
+            });
+        }
+        plan.assemble_flat(responses)
+    }
+
+    async fn write_single(&self, device: &str, address: u32, data: &[u8]) -> Result<(), ConnectionError> {
+        self.write_multi(device, &[address], &[data.to_vec()]).await
+    }
+
+    async fn write_multi(&self, device: &str, addresses: &[u32], data: &[Vec<u8>]) -> Result<(), ConnectionError> {
+        let plan = WritePlan::new(addresses, data, &LIMITS);
+        for (addresses, data) in plan.batches() {
+            match (addresses.as_slice(), data.as_slice()) {
+                ([address], [data]) => {
+                    let packet = Packet::new(Opcode::Put, Space::Snes, FLAG_NONE).size(data.len() as u32).address(*address);
+                    self.put(device, packet, data, BLOCK_SIZE, None).await?
+                },
+                _ => {
+                    let ranges: Vec<(u32, u32)> = addresses.iter().zip(data.iter()).map(|(a, d)| (*a, d.len() as u32)).collect();
+                    let packet = Packet::new(Opcode::VPut, Space::Snes, FLAG_DATA64B).ranges(&ranges);
+                    self.put(device, packet, &data.concat(), VECTOR_BLOCK_SIZE, None).await?
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn filesystem(&self) -> Option<&dyn FilesystemConnection> {
+        Some(self)
+    }
+
+    fn control(&self) -> Option<&dyn ControlConnection> {
+        Some(self)
+    }
+}
+
+#[async_trait(?Send)]
+impl FilesystemConnection for FxPakConnection {
+    async fn list_files(&self, device: &str, path: &str) -> Result<Vec<FileEntry>, ConnectionError> {
+        let packet = Packet::new(Opcode::Ls, Space::File, FLAG_NONE).path(path)?;
+        let slot = self.slot(device);
+        let mut port = slot.lock().await;
+        let result = receive_list(open(&mut port, device).await?, &packet).await;
+        release(&mut port, &result);
+        result
+    }
+
+    async fn get_file(&self, device: &str, path: &str) -> Result<Vec<u8>, ConnectionError> {
+        self.get(device, Packet::new(Opcode::Get, Space::File, FLAG_NONE).path(path)?, BLOCK_SIZE).await
+    }
+
+    async fn put_file(&self, device: &str, path: &str, data: &[u8]) -> Result<(), ConnectionError> {
+        let packet = Packet::new(Opcode::Put, Space::File, FLAG_NONE).size(data.len() as u32).path(path)?;
+        self.put(device, packet, data, BLOCK_SIZE, None).await
+    }
+
+    async fn remove_file(&self, device: &str, path: &str) -> Result<(), ConnectionError> {
+        self.command(device, Packet::new(Opcode::Rm, Space::File, FLAG_NONE).path(path)?).await
+    }
+
+    // The new name goes at 8, before the size and path
+    async fn rename_file(&self, device: &str, path: &str, new_path: &str) -> Result<(), ConnectionError> {
+        let new_name = new_path.rsplit('/').next().unwrap_or(new_path);
+        if new_name.len() >= 252 - 8 {
+            return Err(ConnectionError::InvalidRequest(format!("File name is too long: {}", new_name).into()));
+        }
+        self.command(device, Packet::new(Opcode::Mv, Space::File, FLAG_NONE).path(path)?.path_at(8, new_name)?).await
+    }
+
+    async fn make_directory(&self, device: &str, path: &str) -> Result<(), ConnectionError> {
+        self.command(device, Packet::new(Opcode::Mkdir, Space::File, FLAG_NONE).path(path)?).await
+    }
+
+    async fn put_file_with_progress(&self, device: &str, path: &str, data: &[u8], progress: &dyn Fn(usize, usize)) -> Result<(), ConnectionError> {
+        let packet = Packet::new(Opcode::Put, Space::File, FLAG_NONE).size(data.len() as u32).path(path)?;
+        self.put(device, packet, data, BLOCK_SIZE, Some(&|sent: usize, _: usize| progress(sent.min(data.len()), data.len()))).await
+    }
+}
+
+#[async_trait(?Send)]
+impl ControlConnection for FxPakConnection {
+    async fn reset(&self, device: &str) -> Result<(), ConnectionError> {
+        self.command(device, Packet::new(Opcode::Reset, Space::Snes, FLAG_NONE)).await
+    }
+
+    async fn reset_to_menu(&self, device: &str) -> Result<(), ConnectionError> {
+        self.command(device, Packet::new(Opcode::MenuReset, Space::Snes, FLAG_NONE)).await
+    }
+
+    async fn boot(&self, device: &str, path: &str) -> Result<(), ConnectionError> {
+        self.command(device, Packet::new(Opcode::Boot, Space::File, FLAG_NONE).path(path)?).await
+    }
+
+    async fn pause_emulation(&self, _device: &str, _paused: bool) -> Result<bool, ConnectionError> {
+        Err(ConnectionError::Unsupported("The FX Pak Pro does not support pausing emulation".into()))
+    }
+
+    async fn toggle_pause_emulation(&self, _device: &str) -> Result<(), ConnectionError> {
+        Err(ConnectionError::Unsupported("The FX Pak Pro does not support pausing emulation".into()))
+    }
+}
+
+#[cfg(all(test, feature = "native", target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::{Read, Write};
+    use std::os::unix::io::FromRawFd;
+    use std::sync::Mutex as StdMutex;
+
+    #[derive(Default)]
+    struct State {
+        memory: HashMap<u32, u8>,
+        files: HashMap<String, Vec<u8>>,
+        commands: Vec<Opcode>
+    }
+
+    // Stands in for an FX Pak Pro on the other end of a pseudo-terminal, the way the cart shows up as a USB-CDC serial port
+    struct FakeFxPak {
+        path: String,
+        state: Arc<StdMutex<State>>
+    }
+
+    impl FakeFxPak {
+        fn start() -> Self {
+            // The pair shares one set of terminal settings, make it raw so bytes go through untouched
+            let (mut master, path) = unsafe {
+                let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+                assert!(fd >= 0);
+                assert_eq!(libc::grantpt(fd), 0);
+                assert_eq!(libc::unlockpt(fd), 0);
+                let mut termios = std::mem::zeroed::<libc::termios>();
+                assert_eq!(libc::tcgetattr(fd, &mut termios), 0);
+                libc::cfmakeraw(&mut termios);
+                assert_eq!(libc::tcsetattr(fd, libc::TCSANOW, &termios), 0);
+                let mut name = [0 as libc::c_char; 128];
+                assert_eq!(libc::ptsname_r(fd, name.as_mut_ptr(), name.len()), 0);
+                (File::from_raw_fd(fd), std::ffi::CStr::from_ptr(name.as_ptr()).to_string_lossy().to_string())
+            };
+
+            let state = Arc::new(StdMutex::new(State::default()));
+            let server = state.clone();
+            // Reading fails once the connection closes the port, which ends the thread
+            std::thread::spawn(move || while Self::serve(&mut master, &server).is_ok() {});
+            Self { path, state }
+        }
+
+        fn serve(master: &mut File, state: &StdMutex<State>) -> std::io::Result<()> {
+            let mut packet = vec![0; VECTOR_BLOCK_SIZE];
+            master.read_exact(&mut packet)?;
+            if packet[6] & FLAG_DATA64B == 0 {
+                packet.resize(BLOCK_SIZE, 0);
+                master.read_exact(&mut packet[VECTOR_BLOCK_SIZE..])?;
+            }
+
+            let opcode = [Opcode::Get, Opcode::Put, Opcode::VGet, Opcode::VPut, Opcode::Ls, Opcode::Mkdir, Opcode::Rm, Opcode::Mv,
+                          Opcode::Reset, Opcode::Boot, Opcode::PowerCycle, Opcode::Info, Opcode::MenuReset][packet[4] as usize];
+            let file = packet[5] == Space::File as u8;
+            let size = packet.get(252..256).map(|s| u32::from_be_bytes([s[0], s[1], s[2], s[3]])).unwrap_or_default() as usize;
+            let address = packet.get(256..260).map(|a| u32::from_be_bytes([a[0], a[1], a[2], a[3]])).unwrap_or_default();
+            let path = packet.get(256..).map(c_string).unwrap_or_default();
+            let ranges: Vec<(u32, usize)> = packet[32..64].chunks(4).take_while(|e| e[0] != 0).map(|e| (u32::from_be_bytes([0, e[1], e[2], e[3]]), e[0] as usize)).collect();
+
+            let mut state = state.lock().unwrap();
+            state.commands.push(opcode);
+            let mut header = vec![0; BLOCK_SIZE];
+            header[0..4].copy_from_slice(b"USBA");
+            header[4] = Opcode::Response as u8;
+            let respond = |master: &mut File, header: &mut Vec<u8>, size: usize| {
+                header[252..256].copy_from_slice(&(size as u32).to_be_bytes());
+                master.write_all(header)
+            };
+            let read = |state: &State, address: u32, size: usize| -> Vec<u8> { (address..address + size as u32).map(|a| state.memory.get(&a).copied().unwrap_or_default()).collect() };
+
+            match opcode {
+                Opcode::Info => {
+                    header[16..24].copy_from_slice(b"Test ROM");
+                    header[256..260].copy_from_slice(&0x10u32.to_be_bytes());
+                    header[260..266].copy_from_slice(b"1.10.3");
+                    respond(master, &mut header, 0)
+                },
+                Opcode::Get if file => match state.files.get(&path) {
+                    Some(data) => {
+                        respond(master, &mut header, data.len())?;
+                        master.write_all(&padded(data, BLOCK_SIZE))
+                    },
+                    None => {
+                        header[5] = 1;
+                        respond(master, &mut header, 0)
+                    }
+                },
+                Opcode::Get => {
+                    respond(master, &mut header, size)?;
+                    master.write_all(&padded(&read(&state, address, size), BLOCK_SIZE))
+                },
+                Opcode::VGet => {
+                    let data: Vec<u8> = ranges.iter().flat_map(|(address, size)| read(&state, *address, *size)).collect();
+                    respond(master, &mut header, data.len())?;
+                    master.write_all(&padded(&data, VECTOR_BLOCK_SIZE))
+                },
+                Opcode::Put | Opcode::VPut => {
+                    let (block_size, ranges) = if opcode == Opcode::VPut { (VECTOR_BLOCK_SIZE, ranges) } else { (BLOCK_SIZE, vec![(address, size)]) };
+                    let total: usize = ranges.iter().map(|(_, size)| size).sum();
+                    respond(master, &mut header, total)?;
+                    let mut data = vec![0; whole_blocks(total, block_size)];
+                    master.read_exact(&mut data)?;
+                    data.truncate(total);
+                    if file {
+                        state.files.insert(path, data);
+                    } else {
+                        let mut offset = 0;
+                        for (address, size) in ranges {
+                            for i in 0..size {
+                                state.memory.insert(address + i as u32, data[offset + i]);
+                            }
+                            offset += size;
+                        }
+                    }
+                    Ok(())
+                },
+                // Every entry is on a block of its own, so the list has to be followed across blocks
+                Opcode::Ls => {
+                    respond(master, &mut header, 0)?;
+                    let mut names: Vec<&String> = state.files.keys().filter(|f| f.starts_with(&format!("{}/", path))).collect();
+                    names.sort();
+                    for name in [".", ".."].iter().copied().chain(names.iter().map(|n| &n[path.len() + 1..])) {
+                        let mut block = vec![LS_NEXT_BLOCK; BLOCK_SIZE];
+                        block[0] = if name.starts_with('.') { LS_DIRECTORY } else { LS_FILE };
+                        block[1..1 + name.len()].copy_from_slice(name.as_bytes());
+                        block[1 + name.len()] = 0;
+                        master.write_all(&block)?;
+                    }
+                    let mut block = vec![0; BLOCK_SIZE];
+                    block[0] = LS_END;
+                    master.write_all(&block)
+                },
+                Opcode::Mv => {
+                    let new_name = c_string(&packet[8..]);
+                    if let Some(data) = state.files.remove(&path) {
+                        let directory = path.rsplit_once('/').map(|(d, _)| d).unwrap_or_default();
+                        state.files.insert(format!("{}/{}", directory, new_name), data);
+                    }
+                    respond(master, &mut header, 0)
+                },
+                Opcode::Rm => {
+                    state.files.remove(&path);
+                    respond(master, &mut header, 0)
+                },
+                _ => respond(master, &mut header, 0)
+            }
+        }
+
+        fn connection(&self) -> FxPakConnection {
+            FxPakConnection::new(&format!("fxpak://{}", self.path))
+        }
+
+        fn commands(&self) -> Vec<Opcode> {
+            self.state.lock().unwrap().commands.clone()
+        }
+    }
+
+    #[tokio::test]
+    async fn lists_the_cart() {
+        let fxpak = FakeFxPak::start();
+        let connection = fxpak.connection();
+
+        assert!(connection.connect().await.unwrap());
+        let devices = connection.list_devices().await.unwrap();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].uri, fxpak.path);
+        assert_eq!(devices[0].rom_name.as_deref(), Some("Test ROM"));
+        assert_eq!(devices[0].firmware_version.as_deref(), Some("10 1.10.3"));
+    }
+
+    #[tokio::test]
+    async fn reads_and_writes_memory() {
+        let fxpak = FakeFxPak::start();
+        let connection = fxpak.connection();
+
+        connection.write_single(&fxpak.path, 0xF5_0000, &[1, 2, 3, 4]).await.unwrap();
+        assert_eq!(connection.read_single(&fxpak.path, 0xF5_0000, 4).await.unwrap(), vec![1, 2, 3, 4]);
+        assert_eq!(fxpak.commands(), vec![Opcode::Put, Opcode::Get]);
+
+        let data: Vec<Vec<u8>> = (0..3).map(|i| (0..100).map(|b| (b + i) as u8).collect()).collect();
+        connection.write_multi(&fxpak.path, &[0xE0_0000, 0xE0_0400, 0xF5_1000], &data).await.unwrap();
+        assert_eq!(connection.read_multi(&fxpak.path, &[0xE0_0000, 100, 0xE0_0400, 100, 0xF5_1000, 100]).await.unwrap(), data);
+        assert_eq!(fxpak.commands()[2..], [Opcode::VPut, Opcode::VGet]);
+    }
+
+    #[tokio::test]
+    async fn reads_more_than_a_block() {
+        let fxpak = FakeFxPak::start();
+        let connection = fxpak.connection();
+
+        let data: Vec<u8> = (0..0x1234).map(|b| b as u8).collect();
+        connection.write_single(&fxpak.path, 0xE0_0000, &data).await.unwrap();
+        assert_eq!(connection.read_single(&fxpak.path, 0xE0_0000, data.len() as u32).await.unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn manages_files() {
+        let fxpak = FakeFxPak::start();
+        let connection = fxpak.connection();
+        let device = &fxpak.path;
+
+        let rom: Vec<u8> = (0..1500).map(|b| b as u8).collect();
+        let progress = StdMutex::new(Vec::new());
+        connection.put_file_with_progress(device, "/roms/seed.sfc", &rom, &|sent, total| progress.lock().unwrap().push((sent, total))).await.unwrap();
+        assert_eq!(progress.lock().unwrap().last(), Some(&(1500, 1500)));
+        connection.put_file(device, "/roms/other.sfc", &[1]).await.unwrap();
+        assert_eq!(connection.get_file(device, "/roms/seed.sfc").await.unwrap(), rom);
+
+        connection.rename_file(device, "/roms/other.sfc", "/roms/renamed.sfc").await.unwrap();
+        connection.remove_file(device, "/roms/seed.sfc").await.unwrap();
+        let files = connection.list_files(device, "/roms").await.unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].name, "renamed.sfc");
+        assert_eq!(files[0].file_type, FileType::File);
+    }
+
+    #[tokio::test]
+    async fn rejected_commands_keep_the_port_open() {
+        let fxpak = FakeFxPak::start();
+        let connection = fxpak.connection();
+
+        assert!(matches!(connection.get_file(&fxpak.path, "/missing.sfc").await, Err(ConnectionError::InvalidRequest(_))));
+        connection.write_single(&fxpak.path, 0xF5_0000, &[7]).await.unwrap();
+        assert_eq!(connection.read_single(&fxpak.path, 0xF5_0000, 1).await.unwrap(), vec![7]);
+    }
+
+    #[tokio::test]
+    async fn missing_port_fails_to_connect() {
+        let connection = FxPakConnection::new("fxpak:///dev/does-not-exist");
+        assert!(matches!(connection.connect().await, Err(ConnectionError::ConnectFailed(_))));
+        assert!(matches!(connection.read_single("/dev/does-not-exist", 0xF5_0000, 1).await, Err(ConnectionError::ConnectFailed(_))));
+    }
+}
@@ -0,0 +1,284 @@
+// Connection quality metrics. MetricsConnection wraps any other connection and keeps latency histograms per kind
+// of operation, along with byte counts and how often the connection was lost and set up again. Retries and
+// verification failures are counted by the verified reads and writes through Connection::metrics.
+
+use async_trait::async_trait;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::protocols::protocol::{AddressSpace, Connection, ConnectionError, ControlConnection, Device, FileEntry, FilesystemConnection, MappingOverride, MemoryMapping};
+use crate::protocols::timeout::{now_ms, Operation};
+
+// Upper bounds of the latency buckets in milliseconds, anything slower goes into one last bucket
+pub const BUCKET_BOUNDS_MS: &[f64] = &[1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0, 500.0, 1000.0, 2000.0, 5000.0];
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Histogram {
+    pub count: u64,
+    pub errors: u64,
+    pub total_ms: f64,
+    pub max_ms: f64,
+    // Number of operations per bucket of BUCKET_BOUNDS_MS, with one more bucket at the end for the slower ones
+    pub buckets: Vec<u64>
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self { count: 0, errors: 0, total_ms: 0.0, max_ms: 0.0, buckets: vec![0; BUCKET_BOUNDS_MS.len() + 1] }
+    }
+}
+
+impl Histogram {
+    fn add(&mut self, duration_ms: f64, failed: bool) {
+        self.count += 1;
+        if failed {
+            self.errors += 1;
+        }
+        self.total_ms += duration_ms;
+        self.max_ms = self.max_ms.max(duration_ms);
+        let bucket = BUCKET_BOUNDS_MS.iter().position(|b| duration_ms <= *b).unwrap_or(BUCKET_BOUNDS_MS.len());
+        self.buckets[bucket] += 1;
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MetricsSnapshot {
+    // Histograms by operation: connect, disconnect, list_devices, read, write, memory_mapping, filesystem and control
+    pub operations: BTreeMap<String, Histogram>,
+    pub bucket_bounds_ms: Vec<f64>,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    // Extra attempts made by verified reads and writes, and the ones that gave up
+    pub retries: u64,
+    pub verification_failures: u64,
+    // Operations that failed in a way that lost the connection
+    pub connection_losses: u64,
+    // Times the connection was set up again, or reset after an abandoned operation
+    pub reconnects: u64,
+    #[serde(skip)]
+    connects: u64
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    state: Mutex<MetricsSnapshot>
+}
+
+impl Metrics {
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let mut snapshot = self.state.lock().unwrap().clone();
+        snapshot.bucket_bounds_ms = BUCKET_BOUNDS_MS.to_vec();
+        snapshot
+    }
+
+    pub fn reset(&self) {
+        let mut state = self.state.lock().unwrap();
+        let connects = state.connects;
+        *state = MetricsSnapshot { connects, ..Default::default() };
+    }
+
+    pub fn record_retry(&self) {
+        self.state.lock().unwrap().retries += 1;
+    }
+
+    pub fn record_verification_failure(&self) {
+        self.state.lock().unwrap().verification_failures += 1;
+    }
+
+    fn record(&self, operation: &str, duration_ms: f64, error: Option<&ConnectionError>) {
+        let mut state = self.state.lock().unwrap();
+        state.operations.entry(operation.to_string()).or_default().add(duration_ms, error.is_some());
+        if error.is_some_and(|e| e.requires_reconnect()) {
+            state.connection_losses += 1;
+        }
+    }
+
+    fn record_connect(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.connects > 0 {
+            state.reconnects += 1;
+        }
+        state.connects += 1;
+    }
+
+    fn record_reconnect(&self) {
+        self.state.lock().unwrap().reconnects += 1;
+    }
+
+    fn record_bytes(&self, read: usize, written: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.bytes_read += read as u64;
+        state.bytes_written += written as u64;
+    }
+}
+
+pub struct MetricsConnection<C> {
+    inner: C,
+    metrics: Metrics
+}
+
+impl<C: Connection> MetricsConnection<C> {
+    pub fn new(inner: C) -> Self {
+        Self { inner, metrics: Metrics::default() }
+    }
+
+    async fn measure<T, F>(&self, operation: &str, future: F) -> Result<T, ConnectionError>
+    where
+        F: Future<Output = Result<T, ConnectionError>>
+    {
+        let start = now_ms();
+        let result = future.await;
+        self.metrics.record(operation, now_ms() - start, result.as_ref().err());
+        result
+    }
+
+    async fn measure_read(&self, future: impl Future<Output = Result<Vec<Vec<u8>>, ConnectionError>>) -> Result<Vec<Vec<u8>>, ConnectionError> {
+        let result = self.measure("read", future).await;
+        if let Ok(data) = &result {
+            self.metrics.record_bytes(data.iter().map(|d| d.len()).sum(), 0);
+        }
+        result
+    }
+
+    async fn measure_write(&self, size: usize, future: impl Future<Output = Result<(), ConnectionError>>) -> Result<(), ConnectionError> {
+        let result = self.measure("write", future).await;
+        if result.is_ok() {
+            self.metrics.record_bytes(0, size);
+        }
+        result
+    }
+}
+
+#[async_trait(?Send)]
+impl<C: Connection> Connection for MetricsConnection<C> {
+    async fn connect(&self) -> Result<bool, ConnectionError> {
+        self.metrics.record_connect();
+        self.measure("connect", self.inner.connect()).await
+    }
+
+    async fn disconnect(&self) -> Result<bool, ConnectionError> {
+        self.measure("disconnect", self.inner.disconnect()).await
+    }
+
+    async fn list_devices(&self) -> Result<Vec<Device>, ConnectionError> {
+        self.measure("list_devices", self.inner.list_devices()).await
+    }
+
+    async fn read_multi(&self, device: &str, address_info: &[u32]) -> Result<Vec<Vec<u8>>, ConnectionError> {
+        self.measure_read(self.inner.read_multi(device, address_info)).await
+    }
+
+    async fn read_single(&self, device: &str, address: u32, size: u32) -> Result<Vec<u8>, ConnectionError> {
+        Ok(self.measure_read(async { self.inner.read_single(device, address, size).await.map(|d| vec![d]) }).await?.remove(0))
+    }
+
+    async fn write_multi(&self, device: &str, addresses: &[u32], data: &[Vec<u8>]) -> Result<(), ConnectionError> {
+        self.measure_write(data.iter().map(|d| d.len()).sum(), self.inner.write_multi(device, addresses, data)).await
+    }
+
+    async fn write_single(&self, device: &str, address: u32, data: &[u8]) -> Result<(), ConnectionError> {
+        self.measure_write(data.len(), self.inner.write_single(device, address, data)).await
+    }
+
+    async fn memory_mapping(&self, device: &str) -> Result<MemoryMapping, ConnectionError> {
+        self.measure("memory_mapping", self.inner.memory_mapping(device)).await
+    }
+
+    async fn set_memory_mapping(&self, device: &str, mapping: MappingOverride) -> Result<(), ConnectionError> {
+        self.inner.set_memory_mapping(device, mapping).await
+    }
+
+    async fn read_multi_in(&self, device: &str, space: AddressSpace, address_info: &[u32]) -> Result<Vec<Vec<u8>>, ConnectionError> {
+        self.measure_read(self.inner.read_multi_in(device, space, address_info)).await
+    }
+
+    async fn write_multi_in(&self, device: &str, space: AddressSpace, addresses: &[u32], data: &[Vec<u8>]) -> Result<(), ConnectionError> {
+        self.measure_write(data.iter().map(|d| d.len()).sum(), self.inner.write_multi_in(device, space, addresses, data)).await
+    }
+
+    async fn recover(&self) {
+        self.metrics.record_reconnect();
+        self.inner.recover().await
+    }
+
+    fn set_timeout(&self, operation: Operation, timeout: Option<Duration>) -> Result<(), ConnectionError> {
+        self.inner.set_timeout(operation, timeout)
+    }
+
+    fn metrics(&self) -> Option<&Metrics> {
+        Some(&self.metrics)
+    }
+
+    fn filesystem(&self) -> Option<&dyn FilesystemConnection> {
+        self.inner.filesystem().map(|_| self as &dyn FilesystemConnection)
+    }
+
+    fn control(&self) -> Option<&dyn ControlConnection> {
+        self.inner.control().map(|_| self as &dyn ControlConnection)
+    }
+}
+
+#[async_trait(?Send)]
+impl<C: Connection> FilesystemConnection for MetricsConnection<C> {
+    async fn list_files(&self, device: &str, path: &str) -> Result<Vec<FileEntry>, ConnectionError> {
+        self.measure("filesystem", self.inner.require_filesystem()?.list_files(device, path)).await
+    }
+
+    async fn get_file(&self, device: &str, path: &str) -> Result<Vec<u8>, ConnectionError> {
+        let result = self.measure("filesystem", self.inner.require_filesystem()?.get_file(device, path)).await;
+        if let Ok(data) = &result {
+            self.metrics.record_bytes(data.len(), 0);
+        }
+        result
+    }
+
+    async fn put_file(&self, device: &str, path: &str, data: &[u8]) -> Result<(), ConnectionError> {
+        self.measure("filesystem", self.inner.require_filesystem()?.put_file(device, path, data)).await?;
+        self.metrics.record_bytes(0, data.len());
+        Ok(())
+    }
+
+    async fn remove_file(&self, device: &str, path: &str) -> Result<(), ConnectionError> {
+        self.measure("filesystem", self.inner.require_filesystem()?.remove_file(device, path)).await
+    }
+
+    async fn rename_file(&self, device: &str, path: &str, new_path: &str) -> Result<(), ConnectionError> {
+        self.measure("filesystem", self.inner.require_filesystem()?.rename_file(device, path, new_path)).await
+    }
+
+    async fn make_directory(&self, device: &str, path: &str) -> Result<(), ConnectionError> {
+        self.measure("filesystem", self.inner.require_filesystem()?.make_directory(device, path)).await
+    }
+
+    async fn put_file_with_progress(&self, device: &str, path: &str, data: &[u8], progress: &dyn Fn(usize, usize)) -> Result<(), ConnectionError> {
+        self.measure("filesystem", self.inner.require_filesystem()?.put_file_with_progress(device, path, data, progress)).await?;
+        self.metrics.record_bytes(0, data.len());
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl<C: Connection> ControlConnection for MetricsConnection<C> {
+    async fn reset(&self, device: &str) -> Result<(), ConnectionError> {
+        self.measure("control", self.inner.require_control()?.reset(device)).await
+    }
+
+    async fn reset_to_menu(&self, device: &str) -> Result<(), ConnectionError> {
+        self.measure("control", self.inner.require_control()?.reset_to_menu(device)).await
+    }
+
+    async fn boot(&self, device: &str, path: &str) -> Result<(), ConnectionError> {
+        self.measure("control", self.inner.require_control()?.boot(device, path)).await
+    }
+
+    async fn pause_emulation(&self, device: &str, paused: bool) -> Result<bool, ConnectionError> {
+        self.measure("control", self.inner.require_control()?.pause_emulation(device, paused)).await
+    }
+
+    async fn toggle_pause_emulation(&self, device: &str) -> Result<(), ConnectionError> {
+        self.measure("control", self.inner.require_control()?.toggle_pause_emulation(device)).await
+    }
+}
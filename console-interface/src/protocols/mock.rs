@@ -0,0 +1,267 @@
+use async_trait::async_trait;
+use std::sync::{Arc, Mutex};
+use crate::protocols::protocol::{AddressSpace, Capability, Device, Connection, ConnectionError};
+
+// In-memory emulated SNES device that models the FxPakPro address space, for testing game clients
+// and reconnect handling without any hardware or usb2snes server running.
+
+// FxPakPro linear address space regions as (name, start, size)
+const REGIONS: &[(&str, u32, u32)] = &[
+    ("ROM", 0x00_0000, 0xE0_0000),
+    ("SRAM", 0xE0_0000, 0x10_0000),
+    ("WRAM", 0xF5_0000, 0x02_0000),
+    ("VRAM", 0xF7_0000, 0x01_0000),
+    ("APU", 0xF8_0000, 0x01_0000),
+    ("CGRAM", 0xF9_0000, 0x200),
+    ("OAM", 0xF9_0200, 0x220),
+    ("MISC", 0xF9_0420, 0xE0),
+    ("PPUREG", 0xF9_0500, 0x200),
+    ("CPUREG", 0xF9_0700, 0x200),
+];
+
+#[derive(Debug, Clone, Copy)]
+pub enum Fault {
+    // Drop the connection after the given number of further operations, until `connect` is called again
+    Disconnect { after: usize },
+    // Return inconsistent data for the next read, as if the game wrote to memory while it was being read
+    TornRead,
+    // Only make the next write visible after the given number of further operations
+    DelayedWrite { operations: usize }
+}
+
+struct DelayedWrite {
+    remaining: usize,
+    device: String,
+    address: u32,
+    data: Vec<u8>
+}
+
+struct MockDevice {
+    name: String,
+    // Memory is grown on demand, anything past the end of a region buffer reads as zero
+    regions: Vec<Vec<u8>>
+}
+
+impl MockDevice {
+    fn locate(address: u32, size: usize) -> Result<(usize, usize), ConnectionError> {
+        let (index, (_, start, len)) = REGIONS.iter().enumerate()
+            .find(|(_, (_, start, len))| address >= *start && address < start + len)
+            .ok_or_else(|| ConnectionError::InvalidRequest(format!("Address {:06X} is not mapped", address).into()))?;
+
+        let offset = (address - start) as usize;
+        if offset + size > *len as usize {
+            return Err(ConnectionError::InvalidRequest(format!("Request at {:06X} with size {:X} crosses a region boundary", address, size).into()));
+        }
+
+        Ok((index, offset))
+    }
+
+    fn read(&self, address: u32, size: usize) -> Result<Vec<u8>, ConnectionError> {
+        let (index, offset) = Self::locate(address, size)?;
+        let region = &self.regions[index];
+        Ok((offset..offset + size).map(|i| region.get(i).copied().unwrap_or(0)).collect())
+    }
+
+    fn write(&mut self, address: u32, data: &[u8]) -> Result<(), ConnectionError> {
+        let (index, offset) = Self::locate(address, data.len())?;
+        let region = &mut self.regions[index];
+        if region.len() < offset + data.len() {
+            region.resize(offset + data.len(), 0);
+        }
+        region[offset..offset + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+}
+
+struct MockState {
+    connected: bool,
+    devices: Vec<MockDevice>,
+    disconnect_after: Option<usize>,
+    torn_reads: usize,
+    write_delay: Option<usize>,
+    delayed_writes: Vec<DelayedWrite>,
+    operations: usize
+}
+
+impl MockState {
+    fn device_mut(&mut self, device: &str) -> Result<&mut MockDevice, ConnectionError> {
+        self.devices.iter_mut().find(|d| d.name == device).ok_or_else(|| ConnectionError::DeviceNotFound(format!("Unknown device: {}", device).into()))
+    }
+
+    // Every operation advances pending faults and delayed writes before it runs
+    fn begin_operation(&mut self) -> Result<(), ConnectionError> {
+        self.operations += 1;
+
+        for write in self.delayed_writes.iter_mut() {
+            write.remaining = write.remaining.saturating_sub(1);
+        }
+
+        let (ready, pending): (Vec<_>, Vec<_>) = self.delayed_writes.drain(..).partition(|w| w.remaining == 0);
+        self.delayed_writes = pending;
+        for write in ready {
+            self.device_mut(&write.device)?.write(write.address, &write.data)?;
+        }
+
+        match self.disconnect_after {
+            Some(0) => {
+                self.disconnect_after = None;
+                self.connected = false;
+            },
+            Some(n) => self.disconnect_after = Some(n - 1),
+            None => ()
+        }
+
+        if self.connected {
+            Ok(())
+        } else {
+            Err(ConnectionError::Disconnected("Mock device disconnected".into()))
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct MockConnection {
+    state: Arc<Mutex<MockState>>
+}
+
+impl Default for MockConnection {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockConnection {
+    pub fn new() -> Self {
+        Self::with_devices(&["mock"])
+    }
+
+    pub fn with_devices(names: &[&str]) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(MockState {
+                connected: true,
+                devices: names.iter().map(|n| MockDevice { name: n.to_string(), regions: vec![Vec::new(); REGIONS.len()] }).collect(),
+                disconnect_after: None,
+                torn_reads: 0,
+                write_delay: None,
+                delayed_writes: Vec::new(),
+                operations: 0
+            }))
+        }
+    }
+
+    pub fn inject_fault(&self, fault: Fault) {
+        let mut state = self.state.lock().unwrap();
+        match fault {
+            Fault::Disconnect { after } => state.disconnect_after = Some(after),
+            Fault::TornRead => state.torn_reads += 1,
+            Fault::DelayedWrite { operations } => state.write_delay = Some(operations)
+        }
+    }
+
+    // Read device memory directly, bypassing connection state and faults
+    pub fn peek(&self, device: &str, address: u32, size: usize) -> Result<Vec<u8>, ConnectionError> {
+        let mut state = self.state.lock().unwrap();
+        state.device_mut(device)?.read(address, size)
+    }
+
+    // Write device memory directly, bypassing connection state and faults
+    pub fn poke(&self, device: &str, address: u32, data: &[u8]) -> Result<(), ConnectionError> {
+        let mut state = self.state.lock().unwrap();
+        state.device_mut(device)?.write(address, data)
+    }
+
+    // Number of operations issued through the Connection trait so far
+    pub fn operations(&self) -> usize {
+        self.state.lock().unwrap().operations
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.state.lock().unwrap().connected
+    }
+}
+
+#[async_trait(?Send)]
+impl Connection for MockConnection {
+    async fn connect(&self) -> Result<bool, ConnectionError> {
+        let mut state = self.state.lock().unwrap();
+        state.connected = true;
+        Ok(true)
+    }
+
+    async fn disconnect(&self) -> Result<bool, ConnectionError> {
+        let mut state = self.state.lock().unwrap();
+        state.connected = false;
+        Ok(true)
+    }
+
+    async fn list_devices(&self) -> Result<Vec<Device>, ConnectionError> {
+        let mut state = self.state.lock().unwrap();
+        state.begin_operation()?;
+        Ok(state.devices.iter().map(|d| Device {
+            name: d.name.to_string(),
+            uri: d.name.to_string(),
+            kind: "mock".into(),
+            firmware_version: None,
+            rom_name: None,
+            default_address_space: AddressSpace::FxPakPro,
+            capabilities: [Capability::ReadMemory, Capability::WriteMemory].iter().copied().collect()
+        }).collect())
+    }
+
+    async fn read_single(&self, device: &str, address: u32, size: u32) -> Result<Vec<u8>, ConnectionError> {
+        Ok(self.read_multi(device, &[address, size]).await?.remove(0))
+    }
+
+    async fn read_multi(&self, device: &str, address_info: &[u32]) -> Result<Vec<Vec<u8>>, ConnectionError> {
+        let mut state = self.state.lock().unwrap();
+        state.begin_operation()?;
+
+        let mut data = Vec::new();
+        {
+            let dev = state.device_mut(device)?;
+            for req in address_info.chunks(2) {
+                data.push(dev.read(req[0], *req.get(1).unwrap_or(&0) as usize)?);
+            }
+        }
+
+        // A torn read gets the upper half of every range flipped, so it never matches the real memory contents
+        if state.torn_reads > 0 {
+            state.torn_reads -= 1;
+            for d in data.iter_mut() {
+                let half = d.len() / 2;
+                for b in d[half..].iter_mut() {
+                    *b = !*b;
+                }
+            }
+        }
+
+        Ok(data)
+    }
+
+    async fn write_single(&self, device: &str, address: u32, data: &[u8]) -> Result<(), ConnectionError> {
+        self.write_multi(device, &[address], &[data.to_vec()]).await
+    }
+
+    async fn write_multi(&self, device: &str, addresses: &[u32], data: &[Vec<u8>]) -> Result<(), ConnectionError> {
+        let mut state = self.state.lock().unwrap();
+        state.begin_operation()?;
+
+        match state.write_delay.take() {
+            Some(operations) => {
+                state.device_mut(device)?;
+                for (address, data) in addresses.iter().zip(data.iter()) {
+                    MockDevice::locate(*address, data.len())?;
+                    state.delayed_writes.push(DelayedWrite { remaining: operations, device: device.to_string(), address: *address, data: data.to_vec() });
+                }
+                Ok(())
+            },
+            None => {
+                let dev = state.device_mut(device)?;
+                for (address, data) in addresses.iter().zip(data.iter()) {
+                    dev.write(*address, data)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
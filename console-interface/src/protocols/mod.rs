@@ -1,3 +1,19 @@
+pub mod address;
+pub mod discovery;
+pub mod fxpak;
+pub mod metrics;
+pub mod mock;
+pub mod nwa;
+pub mod planner;
 pub mod protocol;
+pub mod recording;
+pub mod retroarch;
+pub mod serial;
 pub mod sni;
-pub mod usb2snes;
\ No newline at end of file
+pub mod tcp;
+pub mod timeout;
+pub mod udp;
+pub mod usb2snes;
+pub mod verify;
+pub mod watch;
+pub mod websocket;
\ No newline at end of file
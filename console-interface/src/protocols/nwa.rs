@@ -0,0 +1,584 @@
+use async_trait::async_trait;
+use futures::lock::Mutex;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::protocols::planner::{Limits, ReadPlan, WritePlan};
+use crate::protocols::protocol::{AddressSpace, Capability, Connection, ConnectionError, ControlConnection, Device};
+use crate::protocols::tcp::TcpStream;
+use crate::protocols::timeout::deadline;
+
+// Emulator Network Access (emu-nwaccess) protocol, spoken over TCP by bsnes-plus, snes9x-nwa and others.
+//
+// Commands are text lines. Replies are either ASCII, a newline followed by key:value lines and an empty line,
+// or binary, a zero byte followed by a big endian 32-bit size and the data. Binary data sent along with a
+// command (for commands prefixed with "b") uses the same framing.
+//
+// Every emulator instance listens on its own port in the standard range, and shows up as a device.
+
+// Emulators take the first free port starting at 0xBEEF
+const FIRST_PORT: u16 = 0xBEEF;
+const PORT_COUNT: u16 = 10;
+
+// How long to wait for an emulator to answer when looking for them
+const PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+
+// FxPakPro regions and the NWA memory domains they correspond to, as (domain, start, size)
+const DOMAINS: &[(&str, u32, u32)] = &[
+    ("CARTROM", 0x00_0000, 0xE0_0000),
+    ("SRAM", 0xE0_0000, 0x10_0000),
+    ("WRAM", 0xF5_0000, 0x02_0000),
+    ("VRAM", 0xF7_0000, 0x01_0000),
+    ("APURAM", 0xF8_0000, 0x01_0000),
+    ("CGRAM", 0xF9_0000, 0x200),
+    ("OAM", 0xF9_0200, 0x220)
+];
+
+#[derive(Debug)]
+enum Reply {
+    Ascii(Vec<(String, String)>),
+    Binary(Vec<u8>)
+}
+
+impl Reply {
+    fn get(&self, key: &str) -> Option<&str> {
+        match self {
+            Reply::Ascii(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str()),
+            Reply::Binary(_) => None
+        }
+    }
+}
+
+// A piece of a request that lies within one memory domain, as (domain, offset in domain, offset in request, size)
+type DomainPiece = (&'static str, u32, u32, u32);
+
+// Split an FxPakPro range into the memory domains it covers
+fn domain_pieces(address: u32, size: u32) -> Result<Vec<DomainPiece>, ConnectionError> {
+    let mut pieces = Vec::new();
+    let mut offset = 0;
+    while offset < size {
+        let start = address + offset;
+        let (domain, domain_start, domain_size) = DOMAINS.iter()
+            .find(|(_, s, len)| start >= *s && start < s + len)
+            .ok_or_else(|| ConnectionError::InvalidRequest(format!("Address {:06X} has no matching NWA memory domain", start).into()))?;
+
+        let length = (domain_start + domain_size - start).min(size - offset);
+        pieces.push((*domain, start - domain_start, offset, length));
+        offset += length;
+    }
+    Ok(pieces)
+}
+
+// Group domain pieces by domain, keeping the order of the pieces within each domain
+fn by_domain(pieces: Vec<(usize, DomainPiece)>) -> Vec<(&'static str, Vec<(usize, DomainPiece)>)> {
+    let mut groups: Vec<(&'static str, Vec<(usize, DomainPiece)>)> = Vec::new();
+    for (index, piece) in pieces {
+        match groups.iter_mut().find(|(d, _)| *d == piece.0) {
+            Some((_, group)) => group.push((index, piece)),
+            None => groups.push((piece.0, vec![(index, piece)]))
+        }
+    }
+    groups
+}
+
+fn reply_error(entries: &[(String, String)]) -> ConnectionError {
+    let kind = entries.iter().find(|(k, _)| k == "error").map(|(_, v)| v.as_str()).unwrap_or_default();
+    let reason = entries.iter().find(|(k, _)| k == "reason").map(|(_, v)| v.to_string()).unwrap_or_else(|| kind.to_string());
+    match kind {
+        "invalid_command" => ConnectionError::Unsupported(format!("Emulator does not support the command: {}", reason).into()),
+        "not_allowed" => ConnectionError::Unsupported(format!("Emulator does not allow the command: {}", reason).into()),
+        "invalid_argument" | "command_error" => ConnectionError::InvalidRequest(format!("Emulator rejected the command: {}", reason).into()),
+        _ => ConnectionError::ProtocolViolation(format!("Emulator reported an error: {}", reason).into())
+    }
+}
+
+async fn read_reply(stream: &mut TcpStream) -> Result<Reply, ConnectionError> {
+    match stream.read_exact(1).await?[0] {
+        b'\n' => {
+            let mut entries = Vec::new();
+            loop {
+                let line = stream.read_line().await?;
+                if line.is_empty() {
+                    break;
+                }
+                let (key, value) = line.split_once(':').unwrap_or((line.as_str(), ""));
+                entries.push((key.to_string(), value.to_string()));
+            }
+
+            if entries.first().map(|(k, _)| k == "error").unwrap_or(false) {
+                return Err(reply_error(&entries));
+            }
+            Ok(Reply::Ascii(entries))
+        },
+        0 => {
+            let size = stream.read_exact(4).await?;
+            let size = u32::from_be_bytes([size[0], size[1], size[2], size[3]]) as usize;
+            Ok(Reply::Binary(stream.read_exact(size).await?))
+        },
+        b => Err(ConnectionError::ProtocolViolation(format!("Unexpected start of NWA reply: {:02X}", b).into()))
+    }
+}
+
+async fn exchange(stream: &mut TcpStream, command: &str, data: Option<&[u8]>) -> Result<Reply, ConnectionError> {
+    match data {
+        Some(d) => {
+            stream.send(format!("b{}\n", command).as_bytes()).await?;
+            let mut block = vec![0];
+            block.extend_from_slice(&(d.len() as u32).to_be_bytes());
+            block.extend_from_slice(d);
+            stream.send(&block).await?;
+        },
+        None => stream.send(format!("{}\n", command).as_bytes()).await?
+    }
+    read_reply(stream).await
+}
+
+type Slot = Arc<Mutex<Option<TcpStream>>>;
+
+pub struct NwaConnection {
+    host: String,
+    ports: Vec<u16>,
+    // One stream per emulator, each with its own lock so emulators can be talked to in parallel
+    streams: std::sync::Mutex<HashMap<String, Slot>>
+}
+
+impl NwaConnection {
+    // The uri is the host to look for emulators on, with a port to only use the emulator on that port
+    pub fn new(uri: &str) -> Self {
+        let address = uri.trim_start_matches("nwa://").trim_end_matches('/');
+        let (host, ports) = match address.rsplit_once(':').map(|(h, p)| (h, p.parse::<u16>())) {
+            Some((host, Ok(port))) => (host, vec![port]),
+            _ => (address, (FIRST_PORT..FIRST_PORT + PORT_COUNT).collect())
+        };
+
+        Self {
+            host: host.to_string(),
+            ports,
+            streams: std::sync::Mutex::new(HashMap::new())
+        }
+    }
+
+    fn slot(&self, device: &str) -> Slot {
+        self.streams.lock().unwrap().entry(device.to_string()).or_insert_with(|| Arc::new(Mutex::new(None))).clone()
+    }
+
+    // Send a command to an emulator, along with binary data for the commands that take it
+    async fn command(&self, device: &str, command: &str, data: Option<&[u8]>) -> Result<Reply, ConnectionError> {
+        let slot = self.slot(device);
+        let mut stream = slot.lock().await;
+        if stream.is_none() {
+            *stream = Some(TcpStream::connect(device).await?);
+            log::debug!("nwa: Connected to {}", device);
+        }
+        let s = stream.as_mut().ok_or_else(|| ConnectionError::Disconnected("Could not get stream".into()))?;
+
+        log::debug!("nwa: Sending command: {}", command);
+        let result = exchange(s, command, data).await;
+
+        // A command that failed half way leaves the stream out of step, errors reported by the emulator don't
+        if let Err(ConnectionError::Disconnected(_)) | Err(ConnectionError::ProtocolViolation(_)) = &result {
+            *stream = None;
+        }
+        result
+    }
+
+    async fn probe(&self, device: &str) -> Result<Device, ConnectionError> {
+        let info = self.command(device, "EMULATOR_INFO", None).await?;
+        let game = self.command(device, "GAME_INFO", None).await.ok();
+        let name = info.get("name").unwrap_or("NWA emulator");
+
+        Ok(Device {
+            name: format!("{} ({})", name, device),
+            uri: device.to_string(),
+            kind: name.to_lowercase(),
+            firmware_version: info.get("version").map(|v| v.to_string()),
+            rom_name: game.as_ref().and_then(|g| g.get("name")).filter(|n| !n.is_empty()).map(|n| n.to_string()),
+            default_address_space: AddressSpace::FxPakPro,
+            capabilities: [Capability::ReadMemory, Capability::WriteMemory, Capability::ResetSystem, Capability::BootFile,
+                           Capability::PauseUnpauseEmulation, Capability::PauseToggleEmulation].iter().copied().collect()
+        })
+    }
+
+    async fn paused(&self, device: &str) -> Result<bool, ConnectionError> {
+        let status = self.command(device, "EMULATION_STATUS", None).await?;
+        Ok(status.get("state") == Some("paused"))
+    }
+}
+
+#[async_trait(?Send)]
+impl Connection for NwaConnection {
+    async fn connect(&self) -> Result<bool, ConnectionError> {
+        if self.list_devices().await?.is_empty() {
+            return Err(ConnectionError::ConnectFailed(format!("No NWA emulators found on {}", self.host).into()));
+        }
+        Ok(true)
+    }
+
+    async fn disconnect(&self) -> Result<bool, ConnectionError> {
+        self.streams.lock().unwrap().clear();
+        Ok(true)
+    }
+
+    // Every port in the range is probed at the same time, emulators that don't answer are left out
+    async fn list_devices(&self) -> Result<Vec<Device>, ConnectionError> {
+        let addresses: Vec<String> = self.ports.iter().map(|p| format!("{}:{}", self.host, p)).collect();
+        let probes = addresses.iter().map(|a| async move {
+            let result = deadline(Some(PROBE_TIMEOUT), self.probe(a)).await;
+            if result.is_err() {
+                self.streams.lock().unwrap().remove(a);
+            }
+            result
+        });
+
+        Ok(futures::future::join_all(probes).await.into_iter().filter_map(|d| d.ok()).collect())
+    }
+
+    async fn read_single(&self, device: &str, address: u32, size: u32) -> Result<Vec<u8>, ConnectionError> {
+        Ok(self.read_multi(device, &[address, size]).await?.remove(0))
+    }
+
+    // All ranges in the same memory domain are read with a single CORE_READ
+    async fn read_multi(&self, device: &str, address_info: &[u32]) -> Result<Vec<Vec<u8>>, ConnectionError> {
+        let plan = ReadPlan::new(address_info, &Limits::UNLIMITED);
+        let mut responses = Vec::new();
+        for batch in plan.batches() {
+            let ranges: Vec<(u32, u32)> = batch.chunks(2).map(|r| (r[0], r[1])).collect();
+            let mut pieces = Vec::new();
+            for (index, (address, size)) in ranges.iter().enumerate() {
+                pieces.extend(domain_pieces(*address, *size)?.into_iter().map(|p| (index, p)));
+            }
+
+            let mut data: Vec<Vec<u8>> = ranges.iter().map(|(_, size)| vec![0; *size as usize]).collect();
+            for (domain, pieces) in by_domain(pieces) {
+                let operands: Vec<String> = pieces.iter().map(|(_, (_, offset, _, size))| format!("${:x};${:x}", offset, size)).collect();
+                let response = match self.command(device, &format!("CORE_READ {};{}", domain, operands.join(";")), None).await? {
+                    Reply::Binary(d) => d,
+                    Reply::Ascii(_) => return Err(ConnectionError::ProtocolViolation("Got ASCII reply when expecting binary data".into()))
+                };
+
+                let expected: u32 = pieces.iter().map(|(_, p)| p.3).sum();
+                if response.len() != expected as usize {
+                    return Err(ConnectionError::ProtocolViolation(format!("Expected {:X} bytes from {} but got {:X}", expected, domain, response.len()).into()));
+                }
+
+                let mut position = 0;
+                for (index, (_, _, offset, size)) in pieces {
+                    data[index][offset as usize..(offset + size) as usize].copy_from_slice(&response[position..position + size as usize]);
+                    position += size as usize;
+                }
+            }
+            responses.push(data);
+        }
+
+        plan.assemble(responses)
+    }
+
+    async fn write_single(&self, device: &str, address: u32, data: &[u8]) -> Result<(), ConnectionError> {
+        self.write_multi(device, &[address], &[data.to_vec()]).await
+    }
+
+    // All ranges in the same memory domain are written with a single CORE_WRITE
+    async fn write_multi(&self, device: &str, addresses: &[u32], data: &[Vec<u8>]) -> Result<(), ConnectionError> {
+        let plan = WritePlan::new(addresses, data, &Limits::UNLIMITED);
+        for (addresses, data) in plan.batches() {
+            let mut pieces = Vec::new();
+            for (index, (address, d)) in addresses.iter().zip(data.iter()).enumerate() {
+                pieces.extend(domain_pieces(*address, d.len() as u32)?.into_iter().map(|p| (index, p)));
+            }
+
+            for (domain, pieces) in by_domain(pieces) {
+                let operands: Vec<String> = pieces.iter().map(|(_, (_, offset, _, size))| format!("${:x};${:x}", offset, size)).collect();
+                let block: Vec<u8> = pieces.iter().flat_map(|(index, (_, _, offset, size))| data[*index][*offset as usize..(offset + size) as usize].to_vec()).collect();
+                self.command(device, &format!("CORE_WRITE {};{}", domain, operands.join(";")), Some(&block)).await?;
+            }
+        }
+        Ok(())
+    }
+
+    fn control(&self) -> Option<&dyn ControlConnection> {
+        Some(self)
+    }
+}
+
+#[async_trait(?Send)]
+impl ControlConnection for NwaConnection {
+    async fn reset(&self, device: &str) -> Result<(), ConnectionError> {
+        self.command(device, "EMULATION_RESET", None).await?;
+        Ok(())
+    }
+
+    async fn reset_to_menu(&self, _device: &str) -> Result<(), ConnectionError> {
+        Err(ConnectionError::Unsupported("NWA emulators do not have a menu to reset to".into()))
+    }
+
+    // The path is a file on the machine running the emulator
+    async fn boot(&self, device: &str, path: &str) -> Result<(), ConnectionError> {
+        self.command(device, &format!("LOAD_GAME {}", path), None).await?;
+        Ok(())
+    }
+
+    async fn pause_emulation(&self, device: &str, paused: bool) -> Result<bool, ConnectionError> {
+        self.command(device, if paused { "EMULATION_PAUSE" } else { "EMULATION_RESUME" }, None).await?;
+        self.paused(device).await
+    }
+
+    async fn toggle_pause_emulation(&self, device: &str) -> Result<(), ConnectionError> {
+        let paused = self.paused(device).await?;
+        self.command(device, if paused { "EMULATION_RESUME" } else { "EMULATION_PAUSE" }, None).await?;
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "native"))]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream as StdTcpStream};
+    use std::sync::Mutex as StdMutex;
+
+    // What unwritten memory on the stand-in reads as, different for every byte of a domain
+    fn pattern(offset: u32) -> u8 {
+        (offset ^ (offset >> 8) ^ (offset >> 16)) as u8
+    }
+
+    #[derive(Default)]
+    struct State {
+        memory: HashMap<(String, u32), u8>,
+        commands: Vec<String>,
+        paused: bool,
+        // Answer the next command with something that isn't an NWA reply
+        garbled: bool
+    }
+
+    // Stands in for an emulator listening for NWA commands on a local port
+    struct FakeEmulator {
+        port: u16,
+        state: Arc<StdMutex<State>>
+    }
+
+    impl FakeEmulator {
+        fn start() -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let state = Arc::new(StdMutex::new(State::default()));
+
+            let server = state.clone();
+            std::thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    let state = server.clone();
+                    std::thread::spawn(move || Self::serve(stream, state));
+                }
+            });
+
+            Self { port, state }
+        }
+
+        // Answer commands until the connection closes
+        fn serve(stream: StdTcpStream, state: Arc<StdMutex<State>>) {
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap_or(0) > 0 {
+                let command = line.trim_end().to_string();
+                line.clear();
+                let data = if command.starts_with('b') {
+                    let mut header = [0; 5];
+                    reader.read_exact(&mut header).unwrap();
+                    let mut data = vec![0; u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize];
+                    reader.read_exact(&mut data).unwrap();
+                    Some(data)
+                } else {
+                    None
+                };
+
+                let reply = Self::respond(&mut state.lock().unwrap(), &command, data);
+                if writer.write_all(&reply).is_err() {
+                    break;
+                }
+            }
+        }
+
+        fn respond(state: &mut State, command: &str, data: Option<Vec<u8>>) -> Vec<u8> {
+            state.commands.push(command.to_string());
+            if state.garbled {
+                state.garbled = false;
+                return b"garbled\n".to_vec();
+            }
+
+            let (name, arguments) = command.split_once(' ').unwrap_or((command, ""));
+            let mut operands = arguments.split(';');
+            let domain = operands.next().unwrap_or_default().to_string();
+            let ranges: Vec<(u32, u32)> = operands.map(|o| u32::from_str_radix(o.trim_start_matches('$'), 16).unwrap()).collect::<Vec<_>>()
+                .chunks(2).map(|r| (r[0], r[1])).collect();
+
+            match name {
+                "EMULATOR_INFO" => ascii(&[("name", "FakeNWA"), ("version", "1.0")]),
+                "GAME_INFO" => ascii(&[("name", "Test Game")]),
+                "EMULATION_STATUS" => ascii(&[("state", if state.paused { "paused" } else { "running" })]),
+                "EMULATION_PAUSE" | "EMULATION_RESUME" => {
+                    state.paused = name == "EMULATION_PAUSE";
+                    ascii(&[])
+                },
+                "CORE_READ" | "bCORE_WRITE" if domain == "CGRAM" => ascii(&[("error", "invalid_argument"), ("reason", "CGRAM is not available")]),
+                "CORE_READ" => {
+                    let bytes: Vec<u8> = ranges.iter()
+                        .flat_map(|(offset, size)| (*offset..offset + size).map(|o| state.memory.get(&(domain.clone(), o)).copied().unwrap_or_else(|| pattern(o))).collect::<Vec<_>>())
+                        .collect();
+                    let mut reply = vec![0];
+                    reply.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+                    reply.extend(bytes);
+                    reply
+                },
+                "bCORE_WRITE" => {
+                    let mut data = data.unwrap().into_iter();
+                    for (offset, size) in ranges {
+                        for o in offset..offset + size {
+                            state.memory.insert((domain.clone(), o), data.next().unwrap());
+                        }
+                    }
+                    ascii(&[])
+                },
+                _ => ascii(&[("error", "invalid_command"), ("reason", "Unknown command")])
+            }
+        }
+
+        fn connection(&self) -> NwaConnection {
+            NwaConnection::new(&format!("nwa://127.0.0.1:{}", self.port))
+        }
+
+        fn device(&self) -> String {
+            format!("127.0.0.1:{}", self.port)
+        }
+
+        fn memory(&self, domain: &str, offset: u32, size: u32) -> Vec<u8> {
+            let state = self.state.lock().unwrap();
+            (offset..offset + size).map(|o| state.memory.get(&(domain.to_string(), o)).copied().unwrap_or_else(|| pattern(o))).collect()
+        }
+
+        fn commands(&self) -> Vec<String> {
+            self.state.lock().unwrap().commands.clone()
+        }
+    }
+
+    fn ascii(entries: &[(&str, &str)]) -> Vec<u8> {
+        let mut reply = String::from("\n");
+        for (key, value) in entries {
+            reply.push_str(&format!("{}:{}\n", key, value));
+        }
+        reply.push('\n');
+        reply.into_bytes()
+    }
+
+    fn entries(entries: &[(&str, &str)]) -> Vec<(String, String)> {
+        entries.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn ranges_are_split_at_domain_boundaries() {
+        assert_eq!(domain_pieces(0xF5_0010, 0x20).unwrap(), vec![("WRAM", 0x10, 0, 0x20)]);
+        assert_eq!(domain_pieces(0xF6_FFF0, 0x20).unwrap(), vec![("WRAM", 0x1_FFF0, 0, 0x10), ("VRAM", 0, 0x10, 0x10)]);
+        assert_eq!(domain_pieces(0xF9_01FF, 2).unwrap(), vec![("CGRAM", 0x1FF, 0, 1), ("OAM", 0, 1, 1)]);
+        assert!(matches!(domain_pieces(0xF9_0420, 1), Err(ConnectionError::InvalidRequest(_))));
+        assert!(matches!(domain_pieces(0xF9_041F, 2), Err(ConnectionError::InvalidRequest(_))));
+    }
+
+    #[test]
+    fn reported_errors_are_mapped() {
+        assert!(matches!(reply_error(&entries(&[("error", "invalid_command")])), ConnectionError::Unsupported(_)));
+        assert!(matches!(reply_error(&entries(&[("error", "not_allowed"), ("reason", "Locked")])), ConnectionError::Unsupported(_)));
+        assert!(matches!(reply_error(&entries(&[("error", "invalid_argument")])), ConnectionError::InvalidRequest(_)));
+        assert!(matches!(reply_error(&entries(&[("error", "command_error")])), ConnectionError::InvalidRequest(_)));
+        assert!(matches!(reply_error(&entries(&[("error", "something_else")])), ConnectionError::ProtocolViolation(_)));
+    }
+
+    #[tokio::test]
+    async fn only_answering_ports_are_listed() {
+        let emulator = FakeEmulator::start();
+        // Nothing listens on a port that was closed again, and a listener that never accepts doesn't answer
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let connection = NwaConnection {
+            host: "127.0.0.1".into(),
+            ports: vec![closed, emulator.port, silent.local_addr().unwrap().port()],
+            streams: std::sync::Mutex::new(HashMap::new())
+        };
+
+        let devices = connection.list_devices().await.unwrap();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].uri, emulator.device());
+        assert_eq!(devices[0].kind, "fakenwa");
+        assert_eq!(devices[0].firmware_version.as_deref(), Some("1.0"));
+        assert_eq!(devices[0].rom_name.as_deref(), Some("Test Game"));
+        assert_eq!(connection.streams.lock().unwrap().keys().collect::<Vec<_>>(), vec![&emulator.device()]);
+    }
+
+    #[test]
+    fn a_port_in_the_uri_limits_the_search_to_it() {
+        assert_eq!(NwaConnection::new("nwa://localhost:1234").ports, vec![1234]);
+        assert_eq!(NwaConnection::new("nwa://localhost").ports.len(), PORT_COUNT as usize);
+        assert_eq!(NwaConnection::new("nwa://localhost/").ports[0], FIRST_PORT);
+    }
+
+    #[tokio::test]
+    async fn reads_one_domain_per_command() {
+        let emulator = FakeEmulator::start();
+        let connection = emulator.connection();
+        let device = emulator.device();
+
+        let data = connection.read_multi(&device, &[0xF5_0100, 4, 0xE0_0010, 2, 0xF6_FFFE, 4]).await.unwrap();
+        assert_eq!(data[0], emulator.memory("WRAM", 0x100, 4));
+        assert_eq!(data[1], emulator.memory("SRAM", 0x10, 2));
+        assert_eq!(data[2], [emulator.memory("WRAM", 0x1_FFFE, 2), emulator.memory("VRAM", 0, 2)].concat());
+        assert_eq!(emulator.commands(), vec!["CORE_READ SRAM;$10;$2", "CORE_READ WRAM;$100;$4;$1fffe;$2", "CORE_READ VRAM;$0;$2"]);
+    }
+
+    #[tokio::test]
+    async fn writes_send_the_data_as_a_binary_block() {
+        let emulator = FakeEmulator::start();
+        let connection = emulator.connection();
+        let device = emulator.device();
+
+        connection.write_multi(&device, &[0xF5_0010, 0xF6_FFFF], &[vec![1, 2, 3], vec![4, 5]]).await.unwrap();
+        assert_eq!(emulator.memory("WRAM", 0x10, 3), vec![1, 2, 3]);
+        assert_eq!(emulator.memory("WRAM", 0x1_FFFF, 1), vec![4]);
+        assert_eq!(emulator.memory("VRAM", 0, 1), vec![5]);
+        assert_eq!(connection.read_single(&device, 0xF5_0010, 3).await.unwrap(), vec![1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn reported_errors_keep_the_stream() {
+        let emulator = FakeEmulator::start();
+        let connection = emulator.connection();
+        let device = emulator.device();
+
+        assert!(matches!(connection.read_single(&device, 0xF9_0000, 2).await, Err(ConnectionError::InvalidRequest(_))));
+        assert!(connection.streams.lock().unwrap()[&device].try_lock().unwrap().is_some());
+        assert_eq!(connection.read_single(&device, 0xF5_0000, 2).await.unwrap(), emulator.memory("WRAM", 0, 2));
+    }
+
+    #[tokio::test]
+    async fn garbled_replies_drop_the_stream() {
+        let emulator = FakeEmulator::start();
+        let connection = emulator.connection();
+        let device = emulator.device();
+
+        emulator.state.lock().unwrap().garbled = true;
+        assert!(matches!(connection.read_single(&device, 0xF5_0000, 2).await, Err(ConnectionError::ProtocolViolation(_))));
+        assert!(connection.streams.lock().unwrap()[&device].try_lock().unwrap().is_none());
+        assert_eq!(connection.read_single(&device, 0xF5_0000, 2).await.unwrap(), emulator.memory("WRAM", 0, 2));
+    }
+
+    #[tokio::test]
+    async fn pause_reports_the_emulation_state() {
+        let emulator = FakeEmulator::start();
+        let connection = emulator.connection();
+        let device = emulator.device();
+
+        assert!(connection.pause_emulation(&device, true).await.unwrap());
+        connection.toggle_pause_emulation(&device).await.unwrap();
+        assert!(!emulator.state.lock().unwrap().paused);
+        assert!(matches!(connection.reset_to_menu(&device).await, Err(ConnectionError::Unsupported(_))));
+    }
+}
@@ -0,0 +1,333 @@
+use crate::protocols::protocol::ConnectionError;
+
+// Turns the ranges a caller asks for into as few device requests as the protocol allows.
+//
+// Overlapping and adjacent ranges are merged into spans, spans are packed into vectored requests
+// (VGET/VPUT for usb2snes) as long as they fit within the protocol limits, and spans that are too large
+// for a vectored request are sent on their own, split into chunks if the protocol needs it.
+// The results are put back together in the order and with the sizes the caller asked for.
+//
+// Reads can go out in any order, so they are sorted by address first. Writes go out in the order they were
+// given, since games use one write to signal that another one is done.
+
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    // Maximum number of ranges in one vectored request
+    pub max_ranges: usize,
+    // Maximum size of a range in a vectored request, larger spans are sent on their own
+    pub max_range_size: u32,
+    // Maximum total number of bytes in one vectored request
+    pub max_batch_size: u32,
+    // Maximum size of a request for a single range, None if there is no limit
+    pub max_single_size: Option<u32>
+}
+
+impl Limits {
+    // usb2snes VGET/VPUT take up to 8 ranges, and are only reliable up to 255 bytes in total.
+    // Plain GET/PUT requests have no size limit.
+    pub const USB2SNES: Limits = Limits { max_ranges: 8, max_range_size: 255, max_batch_size: 255, max_single_size: None };
+
+    // No limits at all, only merges ranges so the same bytes aren't transferred more than once
+    pub const UNLIMITED: Limits = Limits { max_ranges: usize::MAX, max_range_size: u32::MAX, max_batch_size: u32::MAX, max_single_size: None };
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Chunk {
+    span: usize,
+    offset: u32,
+    address: u32,
+    size: u32
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Span {
+    address: u32,
+    size: u32
+}
+
+// Split the spans into chunks and pack them into batches of chunk indexes
+fn pack(spans: &[Span], limits: &Limits) -> (Vec<Chunk>, Vec<Vec<usize>>) {
+    let mut chunks = Vec::new();
+    let mut batches: Vec<Vec<usize>> = Vec::new();
+    let mut current: Vec<usize> = Vec::new();
+    let mut current_size: u64 = 0;
+
+    for (index, span) in spans.iter().enumerate() {
+        if span.size > limits.max_range_size || span.size > limits.max_batch_size {
+            // Send what came before first, so the requests go out in the same order as the spans
+            if !current.is_empty() {
+                batches.push(std::mem::take(&mut current));
+                current_size = 0;
+            }
+            let chunk_size = limits.max_single_size.unwrap_or(span.size).max(1);
+            let mut offset = 0;
+            while offset < span.size {
+                let size = chunk_size.min(span.size - offset);
+                chunks.push(Chunk { span: index, offset, address: span.address + offset, size });
+                batches.push(vec![chunks.len() - 1]);
+                offset += size;
+            }
+            continue;
+        }
+
+        if !current.is_empty() && (current.len() >= limits.max_ranges || current_size + span.size as u64 > limits.max_batch_size as u64) {
+            batches.push(std::mem::take(&mut current));
+            current_size = 0;
+        }
+
+        chunks.push(Chunk { span: index, offset: 0, address: span.address, size: span.size });
+        current.push(chunks.len() - 1);
+        current_size += span.size as u64;
+    }
+
+    if !current.is_empty() {
+        batches.push(current);
+    }
+
+    (chunks, batches)
+}
+
+// Sorted list of spans covering the given (address, size) ranges, with overlapping and adjacent ranges merged.
+// Empty ranges are left out.
+fn merge(ranges: &[(u32, u32)]) -> Vec<Span> {
+    let mut sorted: Vec<(u64, u64)> = ranges.iter()
+        .filter(|(_, size)| *size > 0)
+        .map(|(address, size)| (*address as u64, *address as u64 + *size as u64))
+        .collect();
+    sorted.sort_unstable();
+
+    let mut spans: Vec<(u64, u64)> = Vec::new();
+    for (start, end) in sorted {
+        match spans.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => spans.push((start, end))
+        }
+    }
+
+    spans.iter().map(|(start, end)| Span { address: *start as u32, size: (end - start) as u32 }).collect()
+}
+
+fn find_span(spans: &[Span], address: u32) -> usize {
+    // The span starting at or before the address, which always contains it since the spans cover every range
+    match spans.binary_search_by_key(&address, |s| s.address) {
+        Ok(index) => index,
+        Err(index) => index - 1
+    }
+}
+
+pub struct ReadPlan {
+    requests: Vec<(u32, u32)>,
+    spans: Vec<Span>,
+    chunks: Vec<Chunk>,
+    batches: Vec<Vec<usize>>
+}
+
+impl ReadPlan {
+    // Plan a read of the ranges given as a flat list of (address, size) pairs, like read_multi takes them
+    pub fn new(address_info: &[u32], limits: &Limits) -> Self {
+        let requests: Vec<(u32, u32)> = address_info.chunks(2).map(|r| (r[0], *r.get(1).unwrap_or(&0))).collect();
+        let spans = merge(&requests);
+        let (chunks, batches) = pack(&spans, limits);
+        Self { requests, spans, chunks, batches }
+    }
+
+    // The requests to send, each as a flat list of (address, size) pairs
+    pub fn batches(&self) -> Vec<Vec<u32>> {
+        self.batches.iter().map(|batch| batch.iter().flat_map(|c| vec![self.chunks[*c].address, self.chunks[*c].size]).collect()).collect()
+    }
+
+    // Put the responses to each batch back together into the ranges that were asked for
+    pub fn assemble(&self, responses: Vec<Vec<Vec<u8>>>) -> Result<Vec<Vec<u8>>, ConnectionError> {
+        if responses.len() != self.batches.len() {
+            return Err(ConnectionError::ProtocolViolation(format!("Expected {} responses but got {}", self.batches.len(), responses.len()).into()));
+        }
+
+        let mut spans: Vec<Vec<u8>> = self.spans.iter().map(|s| vec![0; s.size as usize]).collect();
+        for (batch, response) in self.batches.iter().zip(responses.iter()) {
+            if batch.len() != response.len() {
+                return Err(ConnectionError::ProtocolViolation(format!("Expected {} ranges in response but got {}", batch.len(), response.len()).into()));
+            }
+
+            for (chunk, data) in batch.iter().map(|c| &self.chunks[*c]).zip(response.iter()) {
+                if data.len() != chunk.size as usize {
+                    return Err(ConnectionError::ProtocolViolation(format!("Expected {:X} bytes from {:06X} but got {:X}", chunk.size, chunk.address, data.len()).into()));
+                }
+                let offset = chunk.offset as usize;
+                spans[chunk.span][offset..offset + data.len()].copy_from_slice(data);
+            }
+        }
+
+        Ok(self.requests.iter().map(|(address, size)| {
+            if *size == 0 {
+                return Vec::new();
+            }
+            let index = find_span(&self.spans, *address);
+            let offset = (address - self.spans[index].address) as usize;
+            spans[index][offset..offset + *size as usize].to_vec()
+        }).collect())
+    }
+
+    // Same as assemble, for responses where each batch came back as one block of data
+    pub fn assemble_flat(&self, responses: Vec<Vec<u8>>) -> Result<Vec<Vec<u8>>, ConnectionError> {
+        let split = self.batches.iter().zip(responses).map(|(batch, response)| {
+            let size: usize = batch.iter().map(|c| self.chunks[*c].size as usize).sum();
+            if response.len() != size {
+                return Err(ConnectionError::ProtocolViolation(format!("Expected {:X} bytes in response but got {:X}", size, response.len()).into()));
+            }
+
+            let mut position = 0;
+            Ok(batch.iter().map(|c| {
+                let size = self.chunks[*c].size as usize;
+                position += size;
+                response[position - size..position].to_vec()
+            }).collect())
+        }).collect::<Result<Vec<Vec<Vec<u8>>>, ConnectionError>>()?;
+
+        self.assemble(split)
+    }
+}
+
+pub struct WritePlan {
+    batches: Vec<(Vec<u32>, Vec<Vec<u8>>)>
+}
+
+impl WritePlan {
+    // Plan a write of the given data. Where writes overlap the one that comes later in the list wins,
+    // the same as if they had been written one after another.
+    //
+    // A write is only merged into the one before it when it starts inside or right after it and reaches at
+    // least as far, so the merged bytes still go out in the order they were asked for. Everything else keeps
+    // its place in the list.
+    pub fn new(addresses: &[u32], data: &[Vec<u8>], limits: &Limits) -> Self {
+        let mut spans: Vec<Span> = Vec::new();
+        let mut span_data: Vec<Vec<u8>> = Vec::new();
+        for (address, data) in addresses.iter().zip(data.iter()).filter(|(_, d)| !d.is_empty()) {
+            let (start, end) = (*address as u64, *address as u64 + data.len() as u64);
+            match (spans.last_mut(), span_data.last_mut()) {
+                (Some(last), Some(last_data)) if start >= last.address as u64 && start <= last.address as u64 + last.size as u64 && end >= last.address as u64 + last.size as u64 => {
+                    let offset = (start - last.address as u64) as usize;
+                    last_data.truncate(offset);
+                    last_data.extend_from_slice(data);
+                    last.size = last_data.len() as u32;
+                },
+                _ => {
+                    spans.push(Span { address: *address, size: data.len() as u32 });
+                    span_data.push(data.clone());
+                }
+            }
+        }
+
+        let (chunks, batches) = pack(&spans, limits);
+        let batches = batches.iter().map(|batch| {
+            let addresses = batch.iter().map(|c| chunks[*c].address).collect();
+            let data = batch.iter().map(|c| {
+                let chunk = &chunks[*c];
+                span_data[chunk.span][chunk.offset as usize..(chunk.offset + chunk.size) as usize].to_vec()
+            }).collect();
+            (addresses, data)
+        }).collect();
+
+        Self { batches }
+    }
+
+    // The requests to send, each as a list of addresses and the data to write to them
+    pub fn batches(&self) -> &[(Vec<u32>, Vec<Vec<u8>>)] {
+        &self.batches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    const MEMORY_SIZE: u32 = 0x2000;
+
+    fn limits() -> impl Strategy<Value = Limits> {
+        prop_oneof![
+            Just(Limits::USB2SNES),
+            Just(Limits::UNLIMITED),
+            Just(Limits { max_ranges: 8, max_range_size: 255, max_batch_size: 8 * 255, max_single_size: None }),
+            Just(Limits { max_ranges: 1, max_range_size: 64, max_batch_size: 64, max_single_size: Some(64) })
+        ]
+    }
+
+    fn ranges() -> impl Strategy<Value = Vec<(u32, u32)>> {
+        prop::collection::vec((0..MEMORY_SIZE - 0x400, 0..0x400u32), 0..24)
+    }
+
+    fn check_limits(ranges: &[(u32, u32)], limits: &Limits) {
+        let total: u32 = ranges.iter().map(|(_, size)| size).sum();
+        if ranges.len() == 1 && (ranges[0].1 > limits.max_range_size || ranges[0].1 > limits.max_batch_size) {
+            assert!(ranges[0].1 <= limits.max_single_size.unwrap_or(u32::MAX));
+        } else {
+            assert!(ranges.len() <= limits.max_ranges);
+            assert!(total <= limits.max_batch_size);
+            assert!(ranges.iter().all(|(_, size)| *size <= limits.max_range_size));
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn read_plan_matches_naive_reads(memory in prop::collection::vec(any::<u8>(), MEMORY_SIZE as usize), requests in ranges(), limits in limits()) {
+            let address_info: Vec<u32> = requests.iter().flat_map(|(a, s)| vec![*a, *s]).collect();
+            let plan = ReadPlan::new(&address_info, &limits);
+
+            let batches = plan.batches();
+            let responses: Vec<Vec<Vec<u8>>> = batches.iter().map(|batch| {
+                let ranges: Vec<(u32, u32)> = batch.chunks(2).map(|r| (r[0], r[1])).collect();
+                check_limits(&ranges, &limits);
+                ranges.iter().map(|(a, s)| memory[*a as usize..(*a + *s) as usize].to_vec()).collect()
+            }).collect();
+            let flat: Vec<Vec<u8>> = responses.iter().map(|r| r.concat()).collect();
+
+            let expected: Vec<Vec<u8>> = requests.iter().map(|(a, s)| memory[*a as usize..(*a + *s) as usize].to_vec()).collect();
+            prop_assert_eq!(plan.assemble(responses).unwrap(), expected.clone());
+            prop_assert_eq!(plan.assemble_flat(flat).unwrap(), expected);
+        }
+
+        #[test]
+        fn write_plan_matches_naive_writes(memory in prop::collection::vec(any::<u8>(), MEMORY_SIZE as usize), writes in ranges(), seed in any::<u8>(), limits in limits()) {
+            let addresses: Vec<u32> = writes.iter().map(|(a, _)| *a).collect();
+            let data: Vec<Vec<u8>> = writes.iter().enumerate().map(|(i, (_, s))| (0..*s).map(|b| seed ^ (i as u8).wrapping_mul(31) ^ b as u8).collect()).collect();
+
+            let mut expected = memory.clone();
+            for (address, data) in addresses.iter().zip(data.iter()) {
+                expected[*address as usize..*address as usize + data.len()].copy_from_slice(data);
+            }
+
+            let mut planned = memory;
+            for (addresses, data) in WritePlan::new(&addresses, &data, &limits).batches() {
+                let ranges: Vec<(u32, u32)> = addresses.iter().zip(data.iter()).map(|(a, d)| (*a, d.len() as u32)).collect();
+                check_limits(&ranges, &limits);
+                for (address, data) in addresses.iter().zip(data.iter()) {
+                    planned[*address as usize..*address as usize + data.len()].copy_from_slice(data);
+                }
+            }
+
+            prop_assert_eq!(planned, expected);
+        }
+
+        #[test]
+        fn write_plan_keeps_caller_order(writes in ranges(), limits in limits()) {
+            // Fill every write with its own index, the planned bytes must then come out in increasing order
+            let addresses: Vec<u32> = writes.iter().map(|(a, _)| *a).collect();
+            let data: Vec<Vec<u8>> = writes.iter().enumerate().map(|(i, (_, s))| vec![i as u8; *s as usize]).collect();
+
+            let order: Vec<u8> = WritePlan::new(&addresses, &data, &limits).batches().iter().flat_map(|(_, d)| d.concat()).collect();
+            prop_assert!(order.windows(2).all(|w| w[0] <= w[1]), "writes out of order: {:?}", order);
+        }
+    }
+
+    #[test]
+    fn flag_written_after_data_at_lower_address_stays_last() {
+        let plan = WritePlan::new(&[0x110, 0x100], &[vec![1, 2, 3, 4], vec![0xFF]], &Limits::USB2SNES);
+        assert_eq!(plan.batches(), &[(vec![0x110, 0x100], vec![vec![1, 2, 3, 4], vec![0xFF]])]);
+    }
+
+    #[test]
+    fn adjacent_writes_in_order_are_merged() {
+        let plan = WritePlan::new(&[0x100, 0x104, 0x106], &[vec![1, 2, 3, 4], vec![5, 6], vec![7]], &Limits::USB2SNES);
+        assert_eq!(plan.batches(), &[(vec![0x100], vec![vec![1, 2, 3, 4, 5, 6, 7]])]);
+    }
+}
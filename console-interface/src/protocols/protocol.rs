@@ -1,35 +1,251 @@
 use core::fmt;
+use std::collections::BTreeSet;
 
 use async_trait::async_trait;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::prelude::*;
 
+use std::time::Duration;
+
+use crate::protocols::address;
+use crate::protocols::metrics::{Metrics, MetricsConnection};
+use crate::protocols::timeout::{Operation, TimeoutConnection};
+
+pub type ErrorSource = Box<dyn std::error::Error + Send + Sync>;
+
+// Description of what went wrong, optionally with the underlying error that caused it
 #[derive(Debug)]
-pub struct ConnectionError(pub String);
+pub struct ErrorDetail {
+    pub message: String,
+    pub source: Option<ErrorSource>
+}
 
-impl std::error::Error for ConnectionError { }
-impl fmt::Display for ConnectionError {
+impl ErrorDetail {
+    pub fn with_source<E: Into<ErrorSource>>(message: &str, source: E) -> Self {
+        Self { message: message.to_string(), source: Some(source.into()) }
+    }
+}
+
+impl From<&str> for ErrorDetail {
+    fn from(message: &str) -> Self {
+        Self { message: message.to_string(), source: None }
+    }
+}
+
+impl From<String> for ErrorDetail {
+    fn from(message: String) -> Self {
+        Self { message, source: None }
+    }
+}
+
+impl fmt::Display for ErrorDetail {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "ConnectionError: {}", self.0)
+        match &self.source {
+            Some(source) => write!(f, "{}: {}", self.message, source),
+            None => write!(f, "{}", self.message)
+        }
     }
 }
 
+#[derive(Debug)]
+pub enum ConnectionError {
+    // Could not establish a connection to the bridge
+    ConnectFailed(ErrorDetail),
+    // An established connection to the bridge or device was lost
+    Disconnected(ErrorDetail),
+    // The bridge or device did not respond in time
+    Timeout(ErrorDetail),
+    // The bridge sent something we did not expect
+    ProtocolViolation(ErrorDetail),
+    // The operation is not supported by this protocol or device
+    Unsupported(ErrorDetail),
+    // The requested device is not available
+    DeviceNotFound(ErrorDetail),
+    // The memory mapping of the running game could not be detected
+    MappingDetectFailed(ErrorDetail),
+    // The request itself was invalid, like an unmapped address
+    InvalidRequest(ErrorDetail),
+    // Memory never read back the same or as written, no matter how many times it was tried
+    VerificationFailed(ErrorDetail),
+    // The caller gave up on the request before it finished
+    Cancelled(ErrorDetail)
+}
 
-//pub type ConnectionError = Box<dyn std::error::Error>;
+impl ConnectionError {
+    // Stable identifier for the kind of error, exposed to JS as the `code` field
+    pub fn code(&self) -> &'static str {
+        match self {
+            ConnectionError::ConnectFailed(_) => "CONNECT_FAILED",
+            ConnectionError::Disconnected(_) => "DISCONNECTED",
+            ConnectionError::Timeout(_) => "TIMEOUT",
+            ConnectionError::ProtocolViolation(_) => "PROTOCOL_VIOLATION",
+            ConnectionError::Unsupported(_) => "UNSUPPORTED",
+            ConnectionError::DeviceNotFound(_) => "DEVICE_NOT_FOUND",
+            ConnectionError::MappingDetectFailed(_) => "MAPPING_DETECT_FAILED",
+            ConnectionError::InvalidRequest(_) => "INVALID_REQUEST",
+            ConnectionError::VerificationFailed(_) => "VERIFICATION_FAILED",
+            ConnectionError::Cancelled(_) => "CANCELLED"
+        }
+    }
 
+    // The error for a code from code(), for errors that were stored somewhere as their code
+    pub fn from_code(code: &str, detail: ErrorDetail) -> Self {
+        match code {
+            "CONNECT_FAILED" => ConnectionError::ConnectFailed(detail),
+            "DISCONNECTED" => ConnectionError::Disconnected(detail),
+            "TIMEOUT" => ConnectionError::Timeout(detail),
+            "UNSUPPORTED" => ConnectionError::Unsupported(detail),
+            "DEVICE_NOT_FOUND" => ConnectionError::DeviceNotFound(detail),
+            "MAPPING_DETECT_FAILED" => ConnectionError::MappingDetectFailed(detail),
+            "INVALID_REQUEST" => ConnectionError::InvalidRequest(detail),
+            "VERIFICATION_FAILED" => ConnectionError::VerificationFailed(detail),
+            "CANCELLED" => ConnectionError::Cancelled(detail),
+            _ => ConnectionError::ProtocolViolation(detail)
+        }
+    }
 
+    pub fn detail(&self) -> &ErrorDetail {
+        match self {
+            ConnectionError::ConnectFailed(d) |
+            ConnectionError::Disconnected(d) |
+            ConnectionError::Timeout(d) |
+            ConnectionError::ProtocolViolation(d) |
+            ConnectionError::Unsupported(d) |
+            ConnectionError::DeviceNotFound(d) |
+            ConnectionError::MappingDetectFailed(d) |
+            ConnectionError::InvalidRequest(d) |
+            ConnectionError::VerificationFailed(d) |
+            ConnectionError::Cancelled(d) => d
+        }
+    }
 
-#[derive(Debug)]
+    // Errors that leave the connection in an unknown state, so the only way forward is to reconnect
+    pub fn requires_reconnect(&self) -> bool {
+        matches!(self,
+            ConnectionError::ConnectFailed(_) |
+            ConnectionError::Disconnected(_) |
+            ConnectionError::Timeout(_) |
+            ConnectionError::ProtocolViolation(_) |
+            ConnectionError::DeviceNotFound(_))
+    }
+}
+
+impl std::error::Error for ConnectionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.detail().source.as_ref().map(|s| s.as_ref() as &(dyn std::error::Error + 'static))
+    }
+}
+
+impl fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.code(), self.detail())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Protocol {
     Sni,
-    Usb2Snes
+    Usb2Snes,
+    RetroArch,
+    Nwa,
+    FxPak
+}
+
+impl Protocol {
+    // Look up a protocol by the name used for it in JS, like "sni" or "usb2snes"
+    pub fn from_name(name: &str) -> Option<Protocol> {
+        match name.to_lowercase().as_str() {
+            "sni" => Some(Protocol::Sni),
+            "usb2snes" => Some(Protocol::Usb2Snes),
+            "retroarch" => Some(Protocol::RetroArch),
+            "nwa" => Some(Protocol::Nwa),
+            "fxpak" => Some(Protocol::FxPak),
+            _ => None
+        }
+    }
+}
+
+// Things a device can do, so callers can check up front instead of running into Unsupported errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Capability {
+    ReadMemory,
+    WriteMemory,
+    ExecuteAsm,
+    ResetSystem,
+    ResetToMenu,
+    PauseUnpauseEmulation,
+    PauseToggleEmulation,
+    ReadDirectory,
+    MakeDirectory,
+    RemoveFile,
+    RenameFile,
+    PutFile,
+    GetFile,
+    BootFile
+}
+
+// Address space used to interpret memory addresses, FxPakPro is the linear address space used by usb2snes
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AddressSpace {
+    FxPakPro = 0,
+    SnesABus = 1,
+    Raw = 2
+}
+
+// Memory mapping of the ROM running on a device, needed to translate SNES A-bus addresses
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MemoryMapping {
+    Unknown = 0,
+    HiRom = 1,
+    LoRom = 2,
+    ExHiRom = 3
+}
+
+// How a connection should determine the memory mapping of a device
+#[derive(Debug, Clone, PartialEq)]
+pub enum MappingOverride {
+    // Detect the mapping from the running ROM, the default
+    Detect,
+    // Always use this mapping without asking the device
+    Fixed(MemoryMapping),
+    // Detect the mapping, using the fallback if detection fails and the given $00:FFB0 ROM header
+    // instead of reading it from the device
+    Hint { fallback: Option<MemoryMapping>, rom_header: Option<Vec<u8>> }
 }
 
-#[derive(Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Device {
     pub name: String,
     pub uri: String,
-    pub info: Option<Vec<String>>
-} 
+    // Kind of device, e.g. "fxpakpro", "retroarch" or "sd2snes"
+    pub kind: String,
+    pub firmware_version: Option<String>,
+    // Currently running ROM, if the device reports it
+    pub rom_name: Option<String>,
+    pub default_address_space: AddressSpace,
+    pub capabilities: BTreeSet<Capability>
+}
+
+impl Device {
+    pub fn supports(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum FileType {
+    Directory = 0,
+    File = 1
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FileEntry {
+    pub name: String,
+    pub file_type: FileType
+}
 
 #[async_trait(?Send)]
 pub trait Connection {
@@ -40,18 +256,135 @@ pub trait Connection {
     async fn read_single(&self, device: &str, address: u32, size: u32) -> Result<Vec<u8>, ConnectionError>;
     async fn write_multi(&self, device: &str, addresses: &[u32], data: &[Vec<u8>]) -> Result<(), ConnectionError>;
     async fn write_single(&self, device: &str, address: u32, data: &[u8]) -> Result<(), ConnectionError>;
+
+    // Detect the memory mapping from the ROM header, protocols that can ask the device directly should override this
+    async fn memory_mapping(&self, device: &str) -> Result<MemoryMapping, ConnectionError> {
+        let headers = self.read_multi(device, &[
+            address::LOROM_HEADER, address::HEADER_SIZE,
+            address::HIROM_HEADER, address::HEADER_SIZE,
+            address::EXHIROM_HEADER, address::HEADER_SIZE
+        ]).await?;
+
+        match address::detect_mapping(&headers) {
+            MemoryMapping::Unknown => Err(ConnectionError::MappingDetectFailed("No valid ROM header found".into())),
+            mapping => Ok(mapping)
+        }
+    }
+
+    // Called after an operation was abandoned part way through, because of a timeout or cancellation, so a half
+    // finished exchange with the server doesn't get mixed up with the next operation
+    async fn recover(&self) {
+        let _ = self.disconnect().await;
+    }
+
+    // Change the timeout for a kind of operation, None means wait forever
+    fn set_timeout(&self, _operation: Operation, _timeout: Option<Duration>) -> Result<(), ConnectionError> {
+        Err(ConnectionError::Unsupported("Timeouts are not supported by this connection".into()))
+    }
+
+    // Change how the memory mapping is determined, for protocols that support it
+    async fn set_memory_mapping(&self, _device: &str, _mapping: MappingOverride) -> Result<(), ConnectionError> {
+        Err(ConnectionError::Unsupported("Memory mapping can not be overridden for this connection".into()))
+    }
+
+    // Same as read_multi, with the addresses given in the requested address space.
+    // Raw addresses are only supported by connections that can hand them to the device as they are.
+    async fn read_multi_in(&self, device: &str, space: AddressSpace, address_info: &[u32]) -> Result<Vec<Vec<u8>>, ConnectionError> {
+        match space {
+            AddressSpace::FxPakPro => self.read_multi(device, address_info).await,
+            AddressSpace::SnesABus => {
+                let mapping = self.memory_mapping(device).await?;
+                self.read_multi(device, &address::ranges_to_fxpak(address_info, space, mapping)?).await
+            },
+            AddressSpace::Raw => Err(ConnectionError::Unsupported("Raw addresses are not supported by this connection".into()))
+        }
+    }
+
+    // Same as write_multi, with the addresses given in the requested address space
+    async fn write_multi_in(&self, device: &str, space: AddressSpace, addresses: &[u32], data: &[Vec<u8>]) -> Result<(), ConnectionError> {
+        match space {
+            AddressSpace::FxPakPro => self.write_multi(device, addresses, data).await,
+            AddressSpace::SnesABus => {
+                let mapping = self.memory_mapping(device).await?;
+                self.write_multi(device, &address::writes_to_fxpak(addresses, data, space, mapping)?, data).await
+            },
+            AddressSpace::Raw => Err(ConnectionError::Unsupported("Raw addresses are not supported by this connection".into()))
+        }
+    }
+
+    // Connection quality metrics, for connections that keep them
+    fn metrics(&self) -> Option<&Metrics> {
+        None
+    }
+
+    // Access to the device SD card, for the protocols and devices that have one
+    fn filesystem(&self) -> Option<&dyn FilesystemConnection> {
+        None
+    }
+
+    // Reset, boot and pause control, for the protocols and devices that support it
+    fn control(&self) -> Option<&dyn ControlConnection> {
+        None
+    }
+
+    // Same as filesystem and control, with an Unsupported error for the connections that don't have them
+    fn require_filesystem(&self) -> Result<&dyn FilesystemConnection, ConnectionError> {
+        self.filesystem().ok_or_else(|| ConnectionError::Unsupported("Filesystem access is not supported by this connection".into()))
+    }
+
+    fn require_control(&self) -> Result<&dyn ControlConnection, ConnectionError> {
+        self.control().ok_or_else(|| ConnectionError::Unsupported("Device control is not supported by this connection".into()))
+    }
+}
+
+#[async_trait(?Send)]
+pub trait FilesystemConnection {
+    async fn list_files(&self, device: &str, path: &str) -> Result<Vec<FileEntry>, ConnectionError>;
+    async fn get_file(&self, device: &str, path: &str) -> Result<Vec<u8>, ConnectionError>;
+    async fn put_file(&self, device: &str, path: &str, data: &[u8]) -> Result<(), ConnectionError>;
+    async fn remove_file(&self, device: &str, path: &str) -> Result<(), ConnectionError>;
+    async fn rename_file(&self, device: &str, path: &str, new_path: &str) -> Result<(), ConnectionError>;
+    async fn make_directory(&self, device: &str, path: &str) -> Result<(), ConnectionError>;
+
+    // Same as put_file, calling progress with (bytes sent, total bytes) as the upload goes along
+    async fn put_file_with_progress(&self, device: &str, path: &str, data: &[u8], progress: &dyn Fn(usize, usize)) -> Result<(), ConnectionError> {
+        self.put_file(device, path, data).await?;
+        progress(data.len(), data.len());
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+pub trait ControlConnection {
+    async fn reset(&self, device: &str) -> Result<(), ConnectionError>;
+    async fn reset_to_menu(&self, device: &str) -> Result<(), ConnectionError>;
+    async fn boot(&self, device: &str, path: &str) -> Result<(), ConnectionError>;
+    // Returns the paused state reported back by the device
+    async fn pause_emulation(&self, device: &str, paused: bool) -> Result<bool, ConnectionError>;
+    async fn toggle_pause_emulation(&self, device: &str) -> Result<(), ConnectionError>;
 }
 
 pub fn create_connection(protocol: &Protocol) -> Box<dyn Connection> {
     match protocol {
         Protocol::Sni => create_connection_with_uri(protocol, "http://127.0.0.1:8190"),
         Protocol::Usb2Snes => create_connection_with_uri(protocol, "ws://127.0.0.1:23074"),
+        Protocol::RetroArch => create_connection_with_uri(protocol, "udp://127.0.0.1:55355"),
+        Protocol::Nwa => create_connection_with_uri(protocol, "nwa://127.0.0.1"),
+        Protocol::FxPak => create_connection_with_uri(protocol, "fxpak://"),
     }
 }
 
+// Every connection gets timeouts, with metrics on the outside so they include the operations that timed out
+fn wrap<C: Connection + 'static>(connection: C) -> Box<dyn Connection> {
+    Box::new(MetricsConnection::new(TimeoutConnection::new(connection)))
+}
+
 pub fn create_connection_with_uri(protocol: &Protocol, uri: &str) -> Box<dyn Connection> {
     match protocol {
-        Protocol::Sni => Box::new(crate::protocols::sni::SNIConnection::new(uri)),
-        Protocol::Usb2Snes => Box::new(crate::protocols::usb2snes::Usb2SnesConnection::new(uri))
+        Protocol::Sni => wrap(crate::protocols::sni::SNIConnection::new(uri)),
+        Protocol::Usb2Snes => wrap(crate::protocols::usb2snes::Usb2SnesConnection::new(uri)),
+        Protocol::RetroArch => wrap(crate::protocols::retroarch::RetroArchConnection::new(uri)),
+        Protocol::Nwa => wrap(crate::protocols::nwa::NwaConnection::new(uri)),
+        Protocol::FxPak => wrap(crate::protocols::fxpak::FxPakConnection::new(uri))
     }
 }
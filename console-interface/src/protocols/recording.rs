@@ -0,0 +1,510 @@
+// Recording and replaying connection traffic. RecordingConnection wraps any other connection and, while its
+// Recorder is recording, keeps a trace of every call, what came back and how long it took. The trace serializes
+// to JSON, so players can attach it to a bug report, and ReplayConnection serves it back call by call so the
+// exact same session can be run locally. Other services the session talks to can record into the same Recorder
+// and replay from the same TracePlayer, so their calls stay in order with the connection traffic.
+
+use async_trait::async_trait;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::protocols::metrics::Metrics;
+use crate::protocols::protocol::{AddressSpace, Connection, ConnectionError, ControlConnection, Device, ErrorDetail, FileEntry, FilesystemConnection, MappingOverride, MemoryMapping};
+use crate::protocols::timeout::{now_ms, Operation};
+
+// Bumped whenever the trace format changes in a way older traces can't be read with
+pub const TRACE_VERSION: u32 = 1;
+
+// A connection call with its arguments. File uploads only keep the size of the data so traces stay small.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "call", rename_all = "snake_case")]
+pub enum Call {
+    Connect,
+    Disconnect,
+    Recover,
+    ListDevices,
+    ReadSingle { device: String, address: u32, size: u32 },
+    ReadMulti { device: String, address_info: Vec<u32> },
+    ReadMultiIn { device: String, space: AddressSpace, address_info: Vec<u32> },
+    WriteSingle { device: String, address: u32, data: Vec<u8> },
+    WriteMulti { device: String, addresses: Vec<u32>, data: Vec<Vec<u8>> },
+    WriteMultiIn { device: String, space: AddressSpace, addresses: Vec<u32>, data: Vec<Vec<u8>> },
+    MemoryMapping { device: String },
+    ListFiles { device: String, path: String },
+    GetFile { device: String, path: String },
+    PutFile { device: String, path: String, size: usize },
+    RemoveFile { device: String, path: String },
+    RenameFile { device: String, path: String, new_path: String },
+    MakeDirectory { device: String, path: String },
+    Reset { device: String },
+    ResetToMenu { device: String },
+    Boot { device: String, path: String },
+    PauseEmulation { device: String, paused: bool },
+    TogglePauseEmulation { device: String },
+    // A call to some other service that is part of the session, with its request as JSON
+    Service { method: String, request: serde_json::Value }
+}
+
+// What a call came back with. Errors keep the ConnectionError code, or the status code for service calls.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Outcome {
+    Ok { value: serde_json::Value },
+    Err { code: String, message: String }
+}
+
+impl Outcome {
+    pub fn of<T: Serialize>(result: &Result<T, ConnectionError>) -> Self {
+        match result {
+            Ok(value) => Outcome::Ok { value: serde_json::to_value(value).unwrap_or_default() },
+            Err(e) => Outcome::Err { code: e.code().to_string(), message: e.detail().to_string() }
+        }
+    }
+
+    pub fn into_result<T: DeserializeOwned>(self) -> Result<T, ConnectionError> {
+        match self {
+            Outcome::Ok { value } => serde_json::from_value(value).map_err(|e| ConnectionError::ProtocolViolation(ErrorDetail::with_source("Could not read recorded result", e))),
+            Outcome::Err { code, message } => Err(ConnectionError::from_code(&code, message.into()))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TraceEntry {
+    // Milliseconds since the recording started
+    pub at_ms: f64,
+    pub duration_ms: f64,
+    pub call: Call,
+    pub outcome: Outcome
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Trace {
+    pub version: u32,
+    pub entries: Vec<TraceEntry>
+}
+
+impl Trace {
+    pub fn to_json(&self) -> Result<String, ConnectionError> {
+        serde_json::to_string(self).map_err(|e| ConnectionError::InvalidRequest(ErrorDetail::with_source("Could not serialize trace", e)))
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, ConnectionError> {
+        let trace: Trace = serde_json::from_str(json).map_err(|e| ConnectionError::InvalidRequest(ErrorDetail::with_source("Could not read trace", e)))?;
+        if trace.version != TRACE_VERSION {
+            return Err(ConnectionError::InvalidRequest(format!("Trace version {} is not supported", trace.version).into()));
+        }
+        Ok(trace)
+    }
+}
+
+#[derive(Default)]
+struct RecorderState {
+    recording: bool,
+    started_ms: f64,
+    entries: Vec<TraceEntry>
+}
+
+// Collects a trace while recording. It starts out idle, so it can sit in front of a connection for the whole
+// session and only cost anything once a recording is started.
+#[derive(Default)]
+pub struct Recorder {
+    state: Mutex<RecorderState>
+}
+
+impl Recorder {
+    // Start over with an empty trace
+    pub fn start(&self) {
+        *self.state.lock().unwrap() = RecorderState { recording: true, started_ms: now_ms(), entries: Vec::new() };
+    }
+
+    // Stop recording, returning everything that was recorded
+    pub fn stop(&self) -> Trace {
+        let mut state = self.state.lock().unwrap();
+        state.recording = false;
+        Trace { version: TRACE_VERSION, entries: std::mem::take(&mut state.entries) }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.state.lock().unwrap().recording
+    }
+
+    // Everything recorded so far
+    pub fn trace(&self) -> Trace {
+        Trace { version: TRACE_VERSION, entries: self.state.lock().unwrap().entries.clone() }
+    }
+
+    // Add a call that was started at start_ms, as given by now_ms, and just finished
+    pub fn add(&self, start_ms: f64, call: Call, outcome: Outcome) {
+        let mut state = self.state.lock().unwrap();
+        if state.recording {
+            let at_ms = start_ms - state.started_ms;
+            state.entries.push(TraceEntry { at_ms, duration_ms: now_ms() - start_ms, call, outcome });
+        }
+    }
+
+    // Run the call, adding it to the trace if recording. The call is only built when it's going to be kept.
+    pub async fn record<T, F>(&self, call: impl FnOnce() -> Call, future: F) -> Result<T, ConnectionError>
+    where
+        T: Serialize,
+        F: Future<Output = Result<T, ConnectionError>>
+    {
+        if !self.is_recording() {
+            return future.await;
+        }
+
+        let start = now_ms();
+        let result = future.await;
+        self.add(start, call(), Outcome::of(&result));
+        result
+    }
+}
+
+pub struct RecordingConnection<C: ?Sized> {
+    inner: Box<C>,
+    recorder: Arc<Recorder>
+}
+
+impl<C: Connection + ?Sized> RecordingConnection<C> {
+    pub fn new(inner: Box<C>) -> Self {
+        Self::with_recorder(inner, Arc::new(Recorder::default()))
+    }
+
+    // Record into a recorder that other connections or services record into as well
+    pub fn with_recorder(inner: Box<C>, recorder: Arc<Recorder>) -> Self {
+        Self { inner, recorder }
+    }
+
+    pub fn recorder(&self) -> Arc<Recorder> {
+        self.recorder.clone()
+    }
+
+    async fn record<T, F>(&self, call: impl FnOnce() -> Call, future: F) -> Result<T, ConnectionError>
+    where
+        T: Serialize,
+        F: Future<Output = Result<T, ConnectionError>>
+    {
+        self.recorder.record(call, future).await
+    }
+}
+
+#[async_trait(?Send)]
+impl<C: Connection + ?Sized> Connection for RecordingConnection<C> {
+    async fn connect(&self) -> Result<bool, ConnectionError> {
+        self.record(|| Call::Connect, self.inner.connect()).await
+    }
+
+    async fn disconnect(&self) -> Result<bool, ConnectionError> {
+        self.record(|| Call::Disconnect, self.inner.disconnect()).await
+    }
+
+    async fn list_devices(&self) -> Result<Vec<Device>, ConnectionError> {
+        self.record(|| Call::ListDevices, self.inner.list_devices()).await
+    }
+
+    async fn read_multi(&self, device: &str, address_info: &[u32]) -> Result<Vec<Vec<u8>>, ConnectionError> {
+        let call = || Call::ReadMulti { device: device.to_string(), address_info: address_info.to_vec() };
+        self.record(call, self.inner.read_multi(device, address_info)).await
+    }
+
+    async fn read_single(&self, device: &str, address: u32, size: u32) -> Result<Vec<u8>, ConnectionError> {
+        let call = || Call::ReadSingle { device: device.to_string(), address, size };
+        self.record(call, self.inner.read_single(device, address, size)).await
+    }
+
+    async fn write_multi(&self, device: &str, addresses: &[u32], data: &[Vec<u8>]) -> Result<(), ConnectionError> {
+        let call = || Call::WriteMulti { device: device.to_string(), addresses: addresses.to_vec(), data: data.to_vec() };
+        self.record(call, self.inner.write_multi(device, addresses, data)).await
+    }
+
+    async fn write_single(&self, device: &str, address: u32, data: &[u8]) -> Result<(), ConnectionError> {
+        let call = || Call::WriteSingle { device: device.to_string(), address, data: data.to_vec() };
+        self.record(call, self.inner.write_single(device, address, data)).await
+    }
+
+    async fn memory_mapping(&self, device: &str) -> Result<MemoryMapping, ConnectionError> {
+        self.record(|| Call::MemoryMapping { device: device.to_string() }, self.inner.memory_mapping(device)).await
+    }
+
+    async fn set_memory_mapping(&self, device: &str, mapping: MappingOverride) -> Result<(), ConnectionError> {
+        self.inner.set_memory_mapping(device, mapping).await
+    }
+
+    async fn read_multi_in(&self, device: &str, space: AddressSpace, address_info: &[u32]) -> Result<Vec<Vec<u8>>, ConnectionError> {
+        let call = || Call::ReadMultiIn { device: device.to_string(), space, address_info: address_info.to_vec() };
+        self.record(call, self.inner.read_multi_in(device, space, address_info)).await
+    }
+
+    async fn write_multi_in(&self, device: &str, space: AddressSpace, addresses: &[u32], data: &[Vec<u8>]) -> Result<(), ConnectionError> {
+        let call = || Call::WriteMultiIn { device: device.to_string(), space, addresses: addresses.to_vec(), data: data.to_vec() };
+        self.record(call, self.inner.write_multi_in(device, space, addresses, data)).await
+    }
+
+    async fn recover(&self) {
+        let _ = self.record(|| Call::Recover, async { self.inner.recover().await; Ok(()) }).await;
+    }
+
+    fn set_timeout(&self, operation: Operation, timeout: Option<Duration>) -> Result<(), ConnectionError> {
+        self.inner.set_timeout(operation, timeout)
+    }
+
+    fn metrics(&self) -> Option<&Metrics> {
+        self.inner.metrics()
+    }
+
+    fn filesystem(&self) -> Option<&dyn FilesystemConnection> {
+        self.inner.filesystem().map(|_| self as &dyn FilesystemConnection)
+    }
+
+    fn control(&self) -> Option<&dyn ControlConnection> {
+        self.inner.control().map(|_| self as &dyn ControlConnection)
+    }
+}
+
+#[async_trait(?Send)]
+impl<C: Connection + ?Sized> FilesystemConnection for RecordingConnection<C> {
+    async fn list_files(&self, device: &str, path: &str) -> Result<Vec<FileEntry>, ConnectionError> {
+        let call = || Call::ListFiles { device: device.to_string(), path: path.to_string() };
+        self.record(call, self.inner.require_filesystem()?.list_files(device, path)).await
+    }
+
+    async fn get_file(&self, device: &str, path: &str) -> Result<Vec<u8>, ConnectionError> {
+        let call = || Call::GetFile { device: device.to_string(), path: path.to_string() };
+        self.record(call, self.inner.require_filesystem()?.get_file(device, path)).await
+    }
+
+    async fn put_file(&self, device: &str, path: &str, data: &[u8]) -> Result<(), ConnectionError> {
+        let call = || Call::PutFile { device: device.to_string(), path: path.to_string(), size: data.len() };
+        self.record(call, self.inner.require_filesystem()?.put_file(device, path, data)).await
+    }
+
+    async fn remove_file(&self, device: &str, path: &str) -> Result<(), ConnectionError> {
+        let call = || Call::RemoveFile { device: device.to_string(), path: path.to_string() };
+        self.record(call, self.inner.require_filesystem()?.remove_file(device, path)).await
+    }
+
+    async fn rename_file(&self, device: &str, path: &str, new_path: &str) -> Result<(), ConnectionError> {
+        let call = || Call::RenameFile { device: device.to_string(), path: path.to_string(), new_path: new_path.to_string() };
+        self.record(call, self.inner.require_filesystem()?.rename_file(device, path, new_path)).await
+    }
+
+    async fn make_directory(&self, device: &str, path: &str) -> Result<(), ConnectionError> {
+        let call = || Call::MakeDirectory { device: device.to_string(), path: path.to_string() };
+        self.record(call, self.inner.require_filesystem()?.make_directory(device, path)).await
+    }
+
+    async fn put_file_with_progress(&self, device: &str, path: &str, data: &[u8], progress: &dyn Fn(usize, usize)) -> Result<(), ConnectionError> {
+        let call = || Call::PutFile { device: device.to_string(), path: path.to_string(), size: data.len() };
+        self.record(call, self.inner.require_filesystem()?.put_file_with_progress(device, path, data, progress)).await
+    }
+}
+
+#[async_trait(?Send)]
+impl<C: Connection + ?Sized> ControlConnection for RecordingConnection<C> {
+    async fn reset(&self, device: &str) -> Result<(), ConnectionError> {
+        self.record(|| Call::Reset { device: device.to_string() }, self.inner.require_control()?.reset(device)).await
+    }
+
+    async fn reset_to_menu(&self, device: &str) -> Result<(), ConnectionError> {
+        self.record(|| Call::ResetToMenu { device: device.to_string() }, self.inner.require_control()?.reset_to_menu(device)).await
+    }
+
+    async fn boot(&self, device: &str, path: &str) -> Result<(), ConnectionError> {
+        let call = || Call::Boot { device: device.to_string(), path: path.to_string() };
+        self.record(call, self.inner.require_control()?.boot(device, path)).await
+    }
+
+    async fn pause_emulation(&self, device: &str, paused: bool) -> Result<bool, ConnectionError> {
+        let call = || Call::PauseEmulation { device: device.to_string(), paused };
+        self.record(call, self.inner.require_control()?.pause_emulation(device, paused)).await
+    }
+
+    async fn toggle_pause_emulation(&self, device: &str) -> Result<(), ConnectionError> {
+        self.record(|| Call::TogglePauseEmulation { device: device.to_string() }, self.inner.require_control()?.toggle_pause_emulation(device)).await
+    }
+}
+
+// Hands out the recorded outcomes in order. Every call has to match the next recorded one exactly, anything else
+// means the session went differently than the recorded one and fails with a ProtocolViolation.
+pub struct TracePlayer {
+    entries: Mutex<VecDeque<TraceEntry>>
+}
+
+impl TracePlayer {
+    pub fn new(trace: Trace) -> Self {
+        Self { entries: Mutex::new(trace.entries.into()) }
+    }
+
+    // Number of recorded calls that haven't been made yet
+    pub fn remaining(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    // The recorded outcome of the call, which has to be the next one in the trace
+    pub fn next(&self, call: &Call) -> Result<Outcome, ConnectionError> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.front() {
+            Some(entry) if entry.call == *call => Ok(entries.pop_front().unwrap().outcome),
+            Some(entry) => Err(ConnectionError::ProtocolViolation(format!("Replay expected {:?} but got {:?}", entry.call, call).into())),
+            None => Err(ConnectionError::Disconnected(format!("Replay trace ended before {:?}", call).into()))
+        }
+    }
+
+    // Skip the call if it's the next one, for calls that are only recorded when they were needed
+    fn skip(&self, call: &Call) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.front().is_some_and(|e| e.call == *call) {
+            entries.pop_front();
+        }
+    }
+}
+
+// Serves a recorded trace back as a connection
+pub struct ReplayConnection {
+    player: Arc<TracePlayer>
+}
+
+impl ReplayConnection {
+    pub fn new(trace: Trace) -> Self {
+        Self::with_player(Arc::new(TracePlayer::new(trace)))
+    }
+
+    // Replay from a player that other services replay their part of the trace from as well
+    pub fn with_player(player: Arc<TracePlayer>) -> Self {
+        Self { player }
+    }
+
+    pub fn player(&self) -> Arc<TracePlayer> {
+        self.player.clone()
+    }
+
+    // Number of recorded calls that haven't been made yet
+    pub fn remaining(&self) -> usize {
+        self.player.remaining()
+    }
+
+    fn replay<T: DeserializeOwned>(&self, call: Call) -> Result<T, ConnectionError> {
+        self.player.next(&call)?.into_result()
+    }
+}
+
+#[async_trait(?Send)]
+impl Connection for ReplayConnection {
+    async fn connect(&self) -> Result<bool, ConnectionError> {
+        self.replay(Call::Connect)
+    }
+
+    async fn disconnect(&self) -> Result<bool, ConnectionError> {
+        self.replay(Call::Disconnect)
+    }
+
+    async fn list_devices(&self) -> Result<Vec<Device>, ConnectionError> {
+        self.replay(Call::ListDevices)
+    }
+
+    async fn read_multi(&self, device: &str, address_info: &[u32]) -> Result<Vec<Vec<u8>>, ConnectionError> {
+        self.replay(Call::ReadMulti { device: device.to_string(), address_info: address_info.to_vec() })
+    }
+
+    async fn read_single(&self, device: &str, address: u32, size: u32) -> Result<Vec<u8>, ConnectionError> {
+        self.replay(Call::ReadSingle { device: device.to_string(), address, size })
+    }
+
+    async fn write_multi(&self, device: &str, addresses: &[u32], data: &[Vec<u8>]) -> Result<(), ConnectionError> {
+        self.replay(Call::WriteMulti { device: device.to_string(), addresses: addresses.to_vec(), data: data.to_vec() })
+    }
+
+    async fn write_single(&self, device: &str, address: u32, data: &[u8]) -> Result<(), ConnectionError> {
+        self.replay(Call::WriteSingle { device: device.to_string(), address, data: data.to_vec() })
+    }
+
+    async fn memory_mapping(&self, device: &str) -> Result<MemoryMapping, ConnectionError> {
+        self.replay(Call::MemoryMapping { device: device.to_string() })
+    }
+
+    // The recorded results already reflect whatever mapping was used
+    async fn set_memory_mapping(&self, _device: &str, _mapping: MappingOverride) -> Result<(), ConnectionError> {
+        Ok(())
+    }
+
+    async fn read_multi_in(&self, device: &str, space: AddressSpace, address_info: &[u32]) -> Result<Vec<Vec<u8>>, ConnectionError> {
+        self.replay(Call::ReadMultiIn { device: device.to_string(), space, address_info: address_info.to_vec() })
+    }
+
+    async fn write_multi_in(&self, device: &str, space: AddressSpace, addresses: &[u32], data: &[Vec<u8>]) -> Result<(), ConnectionError> {
+        self.replay(Call::WriteMultiIn { device: device.to_string(), space, addresses: addresses.to_vec(), data: data.to_vec() })
+    }
+
+    // Only recorded when the original session had to recover, so it's skipped if it isn't next
+    async fn recover(&self) {
+        self.player.skip(&Call::Recover);
+    }
+
+    fn filesystem(&self) -> Option<&dyn FilesystemConnection> {
+        Some(self)
+    }
+
+    fn control(&self) -> Option<&dyn ControlConnection> {
+        Some(self)
+    }
+}
+
+#[async_trait(?Send)]
+impl FilesystemConnection for ReplayConnection {
+    async fn list_files(&self, device: &str, path: &str) -> Result<Vec<FileEntry>, ConnectionError> {
+        self.replay(Call::ListFiles { device: device.to_string(), path: path.to_string() })
+    }
+
+    async fn get_file(&self, device: &str, path: &str) -> Result<Vec<u8>, ConnectionError> {
+        self.replay(Call::GetFile { device: device.to_string(), path: path.to_string() })
+    }
+
+    async fn put_file(&self, device: &str, path: &str, data: &[u8]) -> Result<(), ConnectionError> {
+        self.replay(Call::PutFile { device: device.to_string(), path: path.to_string(), size: data.len() })
+    }
+
+    async fn remove_file(&self, device: &str, path: &str) -> Result<(), ConnectionError> {
+        self.replay(Call::RemoveFile { device: device.to_string(), path: path.to_string() })
+    }
+
+    async fn rename_file(&self, device: &str, path: &str, new_path: &str) -> Result<(), ConnectionError> {
+        self.replay(Call::RenameFile { device: device.to_string(), path: path.to_string(), new_path: new_path.to_string() })
+    }
+
+    async fn make_directory(&self, device: &str, path: &str) -> Result<(), ConnectionError> {
+        self.replay(Call::MakeDirectory { device: device.to_string(), path: path.to_string() })
+    }
+
+    async fn put_file_with_progress(&self, device: &str, path: &str, data: &[u8], progress: &dyn Fn(usize, usize)) -> Result<(), ConnectionError> {
+        self.put_file(device, path, data).await?;
+        progress(data.len(), data.len());
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl ControlConnection for ReplayConnection {
+    async fn reset(&self, device: &str) -> Result<(), ConnectionError> {
+        self.replay(Call::Reset { device: device.to_string() })
+    }
+
+    async fn reset_to_menu(&self, device: &str) -> Result<(), ConnectionError> {
+        self.replay(Call::ResetToMenu { device: device.to_string() })
+    }
+
+    async fn boot(&self, device: &str, path: &str) -> Result<(), ConnectionError> {
+        self.replay(Call::Boot { device: device.to_string(), path: path.to_string() })
+    }
+
+    async fn pause_emulation(&self, device: &str, paused: bool) -> Result<bool, ConnectionError> {
+        self.replay(Call::PauseEmulation { device: device.to_string(), paused })
+    }
+
+    async fn toggle_pause_emulation(&self, device: &str) -> Result<(), ConnectionError> {
+        self.replay(Call::TogglePauseEmulation { device: device.to_string() })
+    }
+}
@@ -0,0 +1,587 @@
+use async_trait::async_trait;
+use futures::lock::Mutex;
+use std::sync::Arc;
+
+use crate::protocols::address;
+use crate::protocols::planner::{Limits, ReadPlan, WritePlan};
+use crate::protocols::protocol::{AddressSpace, Capability, Connection, ConnectionError, ControlConnection, Device, MappingOverride, MemoryMapping};
+use crate::protocols::udp::UdpSocket;
+
+// RetroArch network command interface, a line based text protocol over UDP.
+//
+// Memory is accessed with READ_CORE_MEMORY/WRITE_CORE_MEMORY, which use the memory map of the running core.
+// For the SNES cores that is the A-bus, so FxPakPro addresses are translated using the memory mapping of
+// the running ROM, which is detected from the ROM header.
+
+// RetroArch answers every command with a single datagram, so keep requests small enough for the
+// hex encoded response to fit comfortably
+const LIMITS: Limits = Limits { max_ranges: 1, max_range_size: 2048, max_batch_size: 2048, max_single_size: Some(2048) };
+
+// Granularity of the memory mappings, a range that doesn't cross one of these boundaries is contiguous on the bus
+const MAPPING_BLOCK_SIZE: u32 = 0x2000;
+
+// RetroArch only knows about the game it is running, so it shows up as a single device
+const DEVICE_NAME: &str = "RetroArch";
+
+#[derive(Debug, PartialEq)]
+enum Status {
+    Contentless,
+    Paused(String),
+    Playing(String)
+}
+
+// GET_STATUS responds with "GET_STATUS PLAYING system,game,crc32=..." or "GET_STATUS CONTENTLESS"
+fn parse_status(response: &str) -> Result<Status, ConnectionError> {
+    let mut parts = response.trim().splitn(3, ' ');
+    let state = parts.nth(1).unwrap_or_default();
+    let game = parts.next().and_then(|g| g.split(',').nth(1)).unwrap_or_default().to_string();
+    match state {
+        "CONTENTLESS" => Ok(Status::Contentless),
+        "PAUSED" => Ok(Status::Paused(game)),
+        "PLAYING" => Ok(Status::Playing(game)),
+        _ => Err(ConnectionError::ProtocolViolation(format!("Unexpected GET_STATUS response: {}", response.trim()).into()))
+    }
+}
+
+// Memory responses start with the command and the address, followed by the data or -1 and an error message
+fn parse_memory_response<'a>(response: &'a str, command: &str, address: u32) -> Result<Option<Vec<&'a str>>, ConnectionError> {
+    let mut parts = response.split_whitespace();
+    if parts.next() != Some(command) || parts.next().and_then(|a| u32::from_str_radix(a, 16).ok()) != Some(address) {
+        // An answer to an earlier request that we gave up waiting for
+        return Ok(None);
+    }
+
+    let values: Vec<&str> = parts.collect();
+    if values.first() == Some(&"-1") {
+        return Err(ConnectionError::InvalidRequest(format!("RetroArch could not access {:06X}: {}", address, values[1..].join(" ")).into()));
+    }
+    Ok(Some(values))
+}
+
+// Split an FxPakPro range into the pieces that are contiguous on the bus, as (bus address, offset, size)
+fn bus_pieces(address: u32, size: u32, mapping: MemoryMapping) -> Result<Vec<(u32, u32, u32)>, ConnectionError> {
+    let mut pieces: Vec<(u32, u32, u32)> = Vec::new();
+    let mut offset = 0;
+    while offset < size {
+        let start = address + offset;
+        let length = (MAPPING_BLOCK_SIZE - start % MAPPING_BLOCK_SIZE).min(size - offset);
+        let bus = address::fxpak_to_bus(start, mapping)?;
+        match pieces.last_mut() {
+            Some(last) if last.0 + last.2 == bus => last.2 += length,
+            _ => pieces.push((bus, offset, length))
+        }
+        offset += length;
+    }
+    Ok(pieces)
+}
+
+pub struct RetroArchConnection {
+    address: String,
+    socket: Arc<Mutex<Option<UdpSocket>>>,
+    mapping: Arc<Mutex<Option<MemoryMapping>>>,
+    mapping_override: Arc<Mutex<MappingOverride>>
+}
+
+impl RetroArchConnection {
+    pub fn new(uri: &str) -> Self {
+        Self {
+            address: uri.trim_start_matches("udp://").trim_end_matches('/').to_string(),
+            socket: Arc::new(Mutex::new(None)),
+            mapping: Arc::new(Mutex::new(None)),
+            mapping_override: Arc::new(Mutex::new(MappingOverride::Detect))
+        }
+    }
+
+    // Send a command and wait for the response that the accept function takes, skipping any leftover
+    // responses to earlier commands
+    async fn request<T, F>(&self, command: &str, accept: F) -> Result<T, ConnectionError>
+    where
+        F: Fn(&str) -> Result<Option<T>, ConnectionError>
+    {
+        let mut socket = self.socket.lock().await;
+        if socket.is_none() {
+            *socket = Some(UdpSocket::connect(&self.address).await?);
+            log::debug!("retroarch: Connected to {}", &self.address);
+        }
+        let s = socket.as_ref().ok_or_else(|| ConnectionError::Disconnected("Could not get socket".into()))?;
+
+        log::debug!("retroarch: Sending command: {}", command.split(' ').next().unwrap_or_default());
+        let result = Self::exchange(s, command, &accept).await;
+        if let Err(ConnectionError::Disconnected(_)) = &result {
+            *socket = None;
+        }
+        result
+    }
+
+    async fn exchange<T, F>(socket: &UdpSocket, command: &str, accept: &F) -> Result<T, ConnectionError>
+    where
+        F: Fn(&str) -> Result<Option<T>, ConnectionError>
+    {
+        socket.send(format!("{}\n", command).as_bytes()).await?;
+        loop {
+            let response = socket.receive().await?;
+            let response = String::from_utf8_lossy(&response);
+            if let Some(result) = accept(&response)? {
+                return Ok(result);
+            }
+            log::debug!("retroarch: Skipping stale response: {}", response.trim());
+        }
+    }
+
+    // Send a command that RetroArch doesn't respond to
+    async fn notify(&self, command: &str) -> Result<(), ConnectionError> {
+        let mut socket = self.socket.lock().await;
+        if socket.is_none() {
+            *socket = Some(UdpSocket::connect(&self.address).await?);
+        }
+        let s = socket.as_ref().ok_or_else(|| ConnectionError::Disconnected("Could not get socket".into()))?;
+        log::debug!("retroarch: Sending command: {}", command);
+        s.send(format!("{}\n", command).as_bytes()).await
+    }
+
+    async fn version(&self) -> Result<String, ConnectionError> {
+        self.request("VERSION", |r| Ok(Some(r.trim().to_string()).filter(|v| v.starts_with(|c: char| c.is_ascii_digit())))).await
+    }
+
+    async fn status(&self) -> Result<Status, ConnectionError> {
+        self.request("GET_STATUS", |r| if r.starts_with("GET_STATUS") { parse_status(r).map(Some) } else { Ok(None) }).await
+    }
+
+    async fn read_bus(&self, address: u32, size: u32) -> Result<Vec<u8>, ConnectionError> {
+        let data = self.request(&format!("READ_CORE_MEMORY {:X} {}", address, size), |r| {
+            match parse_memory_response(r, "READ_CORE_MEMORY", address)? {
+                Some(values) => values.iter()
+                    .map(|v| u8::from_str_radix(v, 16).map_err(|_| ConnectionError::ProtocolViolation(format!("Invalid byte in READ_CORE_MEMORY response: {}", v).into())))
+                    .collect::<Result<Vec<u8>, ConnectionError>>()
+                    .map(Some),
+                None => Ok(None)
+            }
+        }).await?;
+
+        if data.len() != size as usize {
+            return Err(ConnectionError::InvalidRequest(format!("RetroArch returned {:X} bytes from {:06X} but {:X} were requested", data.len(), address, size).into()));
+        }
+        Ok(data)
+    }
+
+    async fn write_bus(&self, address: u32, data: &[u8]) -> Result<(), ConnectionError> {
+        let bytes: Vec<String> = data.iter().map(|b| format!("{:02X}", b)).collect();
+        let written = self.request(&format!("WRITE_CORE_MEMORY {:X} {}", address, bytes.join(" ")), |r| {
+            match parse_memory_response(r, "WRITE_CORE_MEMORY", address)? {
+                Some(values) => Ok(Some(values.first().and_then(|v| v.parse::<usize>().ok()).unwrap_or_default())),
+                None => Ok(None)
+            }
+        }).await?;
+
+        if written != data.len() {
+            return Err(ConnectionError::InvalidRequest(format!("RetroArch wrote {:X} bytes to {:06X} but {:X} were sent", written, address, data.len()).into()));
+        }
+        Ok(())
+    }
+
+    // Translate an FxPakPro range to pieces on the bus. WRAM doesn't depend on the mapping, so the mapping
+    // is only detected when the range is somewhere else and it isn't known yet.
+    async fn translate(&self, device: &str, address: u32, size: u32) -> Result<Vec<(u32, u32, u32)>, ConnectionError> {
+        let mapping = self.mapping.lock().await.unwrap_or(MemoryMapping::Unknown);
+        match bus_pieces(address, size, mapping) {
+            Err(ConnectionError::MappingDetectFailed(_)) if mapping == MemoryMapping::Unknown => bus_pieces(address, size, self.memory_mapping(device).await?),
+            result => result
+        }
+    }
+
+    async fn read_fxpak(&self, device: &str, address: u32, size: u32) -> Result<Vec<u8>, ConnectionError> {
+        let mut data = Vec::with_capacity(size as usize);
+        for (bus, _, length) in self.translate(device, address, size).await? {
+            data.extend(self.read_bus(bus, length).await?);
+        }
+        Ok(data)
+    }
+
+    async fn write_fxpak(&self, device: &str, address: u32, data: &[u8]) -> Result<(), ConnectionError> {
+        for (bus, offset, length) in self.translate(device, address, data.len() as u32).await? {
+            self.write_bus(bus, &data[offset as usize..(offset + length) as usize]).await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl Connection for RetroArchConnection {
+    // UDP has no connection to set up, so make sure RetroArch answers instead
+    async fn connect(&self) -> Result<bool, ConnectionError> {
+        let version = self.version().await.map_err(|e| ConnectionError::ConnectFailed(format!("RetroArch did not respond: {}", e).into()))?;
+        log::debug!("retroarch: Found RetroArch {}", version);
+        Ok(true)
+    }
+
+    async fn disconnect(&self) -> Result<bool, ConnectionError> {
+        *self.socket.lock().await = None;
+        *self.mapping.lock().await = None;
+        Ok(true)
+    }
+
+    // A late response is skipped when it arrives, but the mapping may belong to a game that isn't running anymore
+    async fn recover(&self) {
+        *self.mapping.lock().await = None;
+    }
+
+    async fn list_devices(&self) -> Result<Vec<Device>, ConnectionError> {
+        let version = self.version().await?;
+        let rom_name = match self.status().await? {
+            Status::Playing(game) | Status::Paused(game) if !game.is_empty() => Some(game),
+            _ => None
+        };
+
+        Ok(vec![Device {
+            name: DEVICE_NAME.to_string(),
+            uri: DEVICE_NAME.to_string(),
+            kind: "retroarch".into(),
+            firmware_version: Some(version),
+            rom_name,
+            default_address_space: AddressSpace::SnesABus,
+            capabilities: [Capability::ReadMemory, Capability::WriteMemory, Capability::ResetSystem,
+                           Capability::PauseUnpauseEmulation, Capability::PauseToggleEmulation].iter().copied().collect()
+        }])
+    }
+
+    async fn read_single(&self, device: &str, address: u32, size: u32) -> Result<Vec<u8>, ConnectionError> {
+        Ok(self.read_multi(device, &[address, size]).await?.remove(0))
+    }
+
+    async fn read_multi(&self, device: &str, address_info: &[u32]) -> Result<Vec<Vec<u8>>, ConnectionError> {
+        let plan = ReadPlan::new(address_info, &LIMITS);
+        let mut responses = Vec::new();
+        for batch in plan.batches() {
+            responses.push(self.read_fxpak(device, batch[0], batch[1]).await?);
+        }
+        plan.assemble_flat(responses)
+    }
+
+    async fn write_single(&self, device: &str, address: u32, data: &[u8]) -> Result<(), ConnectionError> {
+        self.write_multi(device, &[address], &[data.to_vec()]).await
+    }
+
+    async fn write_multi(&self, device: &str, addresses: &[u32], data: &[Vec<u8>]) -> Result<(), ConnectionError> {
+        let plan = WritePlan::new(addresses, data, &LIMITS);
+        for (addresses, data) in plan.batches() {
+            for (address, data) in addresses.iter().zip(data.iter()) {
+                self.write_fxpak(device, *address, data).await?;
+            }
+        }
+        Ok(())
+    }
+
+    // The SNES cores expose the A-bus, so the ROM header of every mapping shows up at $00:FFC0
+    async fn memory_mapping(&self, _device: &str) -> Result<MemoryMapping, ConnectionError> {
+        if let Some(mapping) = *self.mapping.lock().await {
+            return Ok(mapping);
+        }
+
+        let (fallback, header) = match self.mapping_override.lock().await.clone() {
+            MappingOverride::Fixed(mapping) => return Ok(mapping),
+            MappingOverride::Hint { fallback, rom_header } => (fallback, rom_header),
+            MappingOverride::Detect => (None, None)
+        };
+
+        let header = match header {
+            // The hint is the header from $00:FFB0, the part we need starts 0x10 bytes in
+            Some(h) => h.get(0x10..).map(|h| h.to_vec()).unwrap_or_default(),
+            None => self.read_bus(0x00_FFC0, address::HEADER_SIZE).await?
+        };
+
+        let mapping = match (address::detect_mapping(&[header.clone(), header.clone(), header]), fallback) {
+            (MemoryMapping::Unknown, Some(fallback)) => fallback,
+            (MemoryMapping::Unknown, None) => return Err(ConnectionError::MappingDetectFailed("No valid ROM header found".into())),
+            (mapping, _) => mapping
+        };
+
+        log::debug!("retroarch: Detected memory mapping {:?}", mapping);
+        *self.mapping.lock().await = Some(mapping);
+        Ok(mapping)
+    }
+
+    async fn set_memory_mapping(&self, _device: &str, mapping: MappingOverride) -> Result<(), ConnectionError> {
+        *self.mapping_override.lock().await = mapping;
+        *self.mapping.lock().await = None;
+        Ok(())
+    }
+
+    // Addresses on the bus are what the core uses already, so they don't need any translation
+    async fn read_multi_in(&self, device: &str, space: AddressSpace, address_info: &[u32]) -> Result<Vec<Vec<u8>>, ConnectionError> {
+        match space {
+            AddressSpace::FxPakPro => return self.read_multi(device, address_info).await,
+            AddressSpace::Raw => return Err(ConnectionError::Unsupported("Raw addresses are not supported by this connection".into())),
+            AddressSpace::SnesABus => {}
+        }
+
+        let plan = ReadPlan::new(address_info, &LIMITS);
+        let mut responses = Vec::new();
+        for batch in plan.batches() {
+            responses.push(self.read_bus(batch[0], batch[1]).await?);
+        }
+        plan.assemble_flat(responses)
+    }
+
+    async fn write_multi_in(&self, device: &str, space: AddressSpace, addresses: &[u32], data: &[Vec<u8>]) -> Result<(), ConnectionError> {
+        match space {
+            AddressSpace::FxPakPro => return self.write_multi(device, addresses, data).await,
+            AddressSpace::Raw => return Err(ConnectionError::Unsupported("Raw addresses are not supported by this connection".into())),
+            AddressSpace::SnesABus => {}
+        }
+
+        let plan = WritePlan::new(addresses, data, &LIMITS);
+        for (addresses, data) in plan.batches() {
+            for (address, data) in addresses.iter().zip(data.iter()) {
+                self.write_bus(*address, data).await?;
+            }
+        }
+        Ok(())
+    }
+
+    fn control(&self) -> Option<&dyn ControlConnection> {
+        Some(self)
+    }
+}
+
+#[async_trait(?Send)]
+impl ControlConnection for RetroArchConnection {
+    async fn reset(&self, _device: &str) -> Result<(), ConnectionError> {
+        self.notify("RESET").await
+    }
+
+    async fn reset_to_menu(&self, _device: &str) -> Result<(), ConnectionError> {
+        Err(ConnectionError::Unsupported("RetroArch does not have a menu to reset to".into()))
+    }
+
+    async fn boot(&self, _device: &str, _path: &str) -> Result<(), ConnectionError> {
+        Err(ConnectionError::Unsupported("RetroArch can not boot files over the network".into()))
+    }
+
+    // RetroArch can only toggle pause, so check the current state first
+    async fn pause_emulation(&self, _device: &str, paused: bool) -> Result<bool, ConnectionError> {
+        match self.status().await? {
+            Status::Contentless => Err(ConnectionError::InvalidRequest("RetroArch is not running a game".into())),
+            Status::Paused(_) if paused => Ok(true),
+            Status::Playing(_) if !paused => Ok(false),
+            _ => {
+                self.notify("PAUSE_TOGGLE").await?;
+                Ok(paused)
+            }
+        }
+    }
+
+    async fn toggle_pause_emulation(&self, _device: &str) -> Result<(), ConnectionError> {
+        self.notify("PAUSE_TOGGLE").await
+    }
+}
+
+#[cfg(all(test, feature = "native"))]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::net::UdpSocket as StdUdpSocket;
+    use std::sync::Mutex as StdMutex;
+
+    // What unwritten memory on the stand-in reads as, different for every byte of a bank
+    fn pattern(address: u32) -> u8 {
+        (address ^ (address >> 8) ^ (address >> 16)) as u8
+    }
+
+    #[derive(Default)]
+    struct State {
+        memory: HashMap<u32, u8>,
+        commands: Vec<String>,
+        paused: bool,
+        // Send an answer to some earlier read before every memory response
+        stale: bool
+    }
+
+    // Stands in for RetroArch's network command interface, running a LoROM game
+    struct FakeRetroArch {
+        address: String,
+        state: Arc<StdMutex<State>>
+    }
+
+    impl FakeRetroArch {
+        fn start() -> Self {
+            let socket = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+            let address = socket.local_addr().unwrap().to_string();
+            let state = Arc::new(StdMutex::new(State::default()));
+
+            let mut header = vec![0; address::HEADER_SIZE as usize];
+            header[0x15] = 0x20;
+            header[0x1C..0x1E].copy_from_slice(&0xEDCBu16.to_le_bytes());
+            header[0x1E..0x20].copy_from_slice(&0x1234u16.to_le_bytes());
+            state.lock().unwrap().memory.extend(header.iter().enumerate().map(|(i, b)| (0x00_FFC0 + i as u32, *b)));
+
+            let server = state.clone();
+            std::thread::spawn(move || {
+                let mut buffer = [0; 65536];
+                while let Ok((size, peer)) = socket.recv_from(&mut buffer) {
+                    let command = String::from_utf8_lossy(&buffer[..size]).trim().to_string();
+                    for response in Self::respond(&mut server.lock().unwrap(), &command) {
+                        socket.send_to(response.as_bytes(), peer).unwrap();
+                    }
+                }
+            });
+
+            Self { address, state }
+        }
+
+        fn respond(state: &mut State, command: &str) -> Vec<String> {
+            state.commands.push(command.to_string());
+            let parts: Vec<&str> = command.split(' ').collect();
+            let address = parts.get(1).and_then(|a| u32::from_str_radix(a, 16).ok()).unwrap_or_default();
+            let mut responses = Vec::new();
+            if state.stale && parts[0].ends_with("_CORE_MEMORY") {
+                responses.push(format!("READ_CORE_MEMORY {:x} 00\n", address.wrapping_add(1)));
+            }
+
+            match parts[0] {
+                "VERSION" => responses.push("1.9.0\n".into()),
+                "GET_STATUS" => responses.push(format!("GET_STATUS {} super_nes,Test Game,crc32=1234abcd\n", if state.paused { "PAUSED" } else { "PLAYING" })),
+                "PAUSE_TOGGLE" => state.paused = !state.paused,
+                // Nothing is mapped in bank $FF
+                "READ_CORE_MEMORY" | "WRITE_CORE_MEMORY" if address >= 0xFF_0000 => responses.push(format!("{} {:x} -1 no memory descriptor available for address\n", parts[0], address)),
+                "READ_CORE_MEMORY" => {
+                    let size: u32 = parts[2].parse().unwrap();
+                    let bytes: Vec<String> = (address..address + size).map(|a| format!("{:02x}", state.memory.get(&a).copied().unwrap_or_else(|| pattern(a)))).collect();
+                    responses.push(format!("READ_CORE_MEMORY {:x} {}\n", address, bytes.join(" ")));
+                },
+                "WRITE_CORE_MEMORY" => {
+                    for (i, b) in parts[2..].iter().enumerate() {
+                        state.memory.insert(address + i as u32, u8::from_str_radix(b, 16).unwrap());
+                    }
+                    responses.push(format!("WRITE_CORE_MEMORY {:x} {}\n", address, parts.len() - 2));
+                },
+                _ => ()
+            }
+            responses
+        }
+
+        fn connection(&self) -> RetroArchConnection {
+            RetroArchConnection::new(&format!("udp://{}", self.address))
+        }
+
+        fn memory(&self, address: u32, size: u32) -> Vec<u8> {
+            let state = self.state.lock().unwrap();
+            (address..address + size).map(|a| state.memory.get(&a).copied().unwrap_or_else(|| pattern(a))).collect()
+        }
+
+        fn commands(&self) -> Vec<String> {
+            self.state.lock().unwrap().commands.clone()
+        }
+    }
+
+    #[tokio::test]
+    async fn lists_the_running_game() {
+        let retroarch = FakeRetroArch::start();
+        let connection = retroarch.connection();
+
+        assert!(connection.connect().await.unwrap());
+        let devices = connection.list_devices().await.unwrap();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].name, DEVICE_NAME);
+        assert_eq!(devices[0].firmware_version.as_deref(), Some("1.9.0"));
+        assert_eq!(devices[0].rom_name.as_deref(), Some("Test Game"));
+        assert_eq!(devices[0].default_address_space, AddressSpace::SnesABus);
+    }
+
+    #[tokio::test]
+    async fn connect_fails_when_nothing_listens() {
+        let address = StdUdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let connection = RetroArchConnection::new(&format!("udp://{}", address));
+        assert!(matches!(connection.connect().await, Err(ConnectionError::ConnectFailed(_))));
+    }
+
+    #[tokio::test]
+    async fn translates_fxpak_addresses_with_the_detected_mapping() {
+        let retroarch = FakeRetroArch::start();
+        let connection = retroarch.connection();
+
+        connection.write_multi(DEVICE_NAME, &[0xE0_0010], &[vec![1, 2, 3, 4]]).await.unwrap();
+        assert_eq!(connection.memory_mapping(DEVICE_NAME).await.unwrap(), MemoryMapping::LoRom);
+        assert_eq!(retroarch.memory(0x70_0010, 4), vec![1, 2, 3, 4]);
+        assert_eq!(connection.read_single(DEVICE_NAME, 0xE0_0010, 4).await.unwrap(), vec![1, 2, 3, 4]);
+
+        // The header is only read once
+        assert_eq!(retroarch.commands().iter().filter(|c| c.starts_with("READ_CORE_MEMORY FFC0 ")).count(), 1);
+    }
+
+    #[tokio::test]
+    async fn wram_does_not_need_the_mapping() {
+        let retroarch = FakeRetroArch::start();
+        let connection = retroarch.connection();
+
+        assert_eq!(connection.read_single(DEVICE_NAME, 0xF5_0100, 8).await.unwrap(), retroarch.memory(0x7E_0100, 8));
+        assert_eq!(retroarch.commands(), vec!["READ_CORE_MEMORY 7E0100 8"]);
+    }
+
+    #[tokio::test]
+    async fn large_reads_are_split_at_bank_boundaries() {
+        let retroarch = FakeRetroArch::start();
+        let connection = retroarch.connection();
+
+        let data = connection.read_single(DEVICE_NAME, 0x00_A000, 0x7000).await.unwrap();
+        let expected: Vec<u8> = (0x00_A000..0x01_1000).map(|a| pattern(address::fxpak_to_bus(a, MemoryMapping::LoRom).unwrap())).collect();
+        assert_eq!(data, expected);
+
+        for command in retroarch.commands().iter().skip(1) {
+            let size: u32 = command.rsplit(' ').next().unwrap().parse().unwrap();
+            assert!(size <= LIMITS.max_range_size);
+        }
+    }
+
+    #[tokio::test]
+    async fn bus_addresses_are_used_as_they_are() {
+        let retroarch = FakeRetroArch::start();
+        let connection = retroarch.connection();
+
+        connection.write_multi_in(DEVICE_NAME, AddressSpace::SnesABus, &[0x7E_F340], &[vec![9, 8]]).await.unwrap();
+        assert_eq!(connection.read_multi_in(DEVICE_NAME, AddressSpace::SnesABus, &[0x7E_F340, 2]).await.unwrap(), vec![vec![9, 8]]);
+        assert!(retroarch.commands().iter().all(|c| !c.contains("FFC0")));
+    }
+
+    #[tokio::test]
+    async fn raw_addresses_are_refused() {
+        let retroarch = FakeRetroArch::start();
+        let connection = retroarch.connection();
+
+        let read = connection.read_multi_in(DEVICE_NAME, AddressSpace::Raw, &[0x7E_F340, 2]).await;
+        assert!(matches!(read, Err(ConnectionError::Unsupported(_))));
+        let write = connection.write_multi_in(DEVICE_NAME, AddressSpace::Raw, &[0x7E_F340], &[vec![9, 8]]).await;
+        assert!(matches!(write, Err(ConnectionError::Unsupported(_))));
+        assert!(retroarch.commands().is_empty());
+    }
+
+    #[tokio::test]
+    async fn skips_stale_responses() {
+        let retroarch = FakeRetroArch::start();
+        retroarch.state.lock().unwrap().stale = true;
+        let connection = retroarch.connection();
+
+        connection.write_single(DEVICE_NAME, 0xF5_0000, &[0xAA, 0xBB]).await.unwrap();
+        assert_eq!(connection.read_single(DEVICE_NAME, 0xF5_0000, 2).await.unwrap(), vec![0xAA, 0xBB]);
+    }
+
+    #[tokio::test]
+    async fn refused_memory_access_is_an_invalid_request() {
+        let retroarch = FakeRetroArch::start();
+        let connection = retroarch.connection();
+
+        let result = connection.read_multi_in(DEVICE_NAME, AddressSpace::SnesABus, &[0xFF_0000, 4]).await;
+        assert!(matches!(result, Err(ConnectionError::InvalidRequest(_))));
+    }
+
+    #[tokio::test]
+    async fn pause_only_toggles_when_needed() {
+        let retroarch = FakeRetroArch::start();
+        let connection = retroarch.connection();
+
+        assert!(!connection.pause_emulation(DEVICE_NAME, false).await.unwrap());
+        assert!(connection.pause_emulation(DEVICE_NAME, true).await.unwrap());
+        assert!(connection.pause_emulation(DEVICE_NAME, true).await.unwrap());
+        assert_eq!(retroarch.commands().iter().filter(|c| *c == "PAUSE_TOGGLE").count(), 1);
+        assert!(retroarch.state.lock().unwrap().paused);
+    }
+}
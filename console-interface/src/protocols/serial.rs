@@ -0,0 +1,68 @@
+// Thin serial port wrapper for talking to devices over USB-CDC. Browsers can't open serial ports through
+// this crate, so the browser build only has a stand-in that refuses to connect.
+
+use crate::protocols::protocol::ConnectionError;
+#[cfg(feature = "native")]
+use crate::protocols::protocol::ErrorDetail;
+
+// USB vendor and product id of the FX Pak Pro and sd2snes
+#[cfg(feature = "native")]
+const FXPAK_USB_ID: (u16, u16) = (0x1209, 0x5A22);
+
+#[cfg(feature = "wasm")]
+pub struct SerialPort;
+
+#[cfg(feature = "wasm")]
+impl SerialPort {
+    pub fn available() -> Vec<String> {
+        Vec::new()
+    }
+
+    pub async fn open(_path: &str) -> Result<Self, ConnectionError> {
+        Err(ConnectionError::Unsupported("Serial ports are not available in the browser".into()))
+    }
+
+    pub async fn send(&mut self, _data: &[u8]) -> Result<(), ConnectionError> {
+        Err(ConnectionError::Unsupported("Serial ports are not available in the browser".into()))
+    }
+
+    pub async fn read_exact(&mut self, _size: usize) -> Result<Vec<u8>, ConnectionError> {
+        Err(ConnectionError::Unsupported("Serial ports are not available in the browser".into()))
+    }
+}
+
+#[cfg(feature = "native")]
+pub struct SerialPort {
+    port: tokio_serial::SerialStream
+}
+
+#[cfg(feature = "native")]
+impl SerialPort {
+    // Paths of the serial ports that belong to an FX Pak Pro
+    pub fn available() -> Vec<String> {
+        tokio_serial::available_ports().unwrap_or_default().into_iter()
+            .filter(|p| matches!(&p.port_type, tokio_serial::SerialPortType::UsbPort(usb) if (usb.vid, usb.pid) == FXPAK_USB_ID))
+            .map(|p| p.port_name)
+            .collect()
+    }
+
+    // The baud rate doesn't matter for USB-CDC, but it has to be set to something
+    pub async fn open(path: &str) -> Result<Self, ConnectionError> {
+        use tokio_serial::SerialPortBuilderExt;
+        let port = tokio_serial::new(path, 9600).open_native_async().map_err(|e| ConnectionError::ConnectFailed(ErrorDetail::with_source("Could not open serial port", e)))?;
+        Ok(Self { port })
+    }
+
+    pub async fn send(&mut self, data: &[u8]) -> Result<(), ConnectionError> {
+        use tokio::io::AsyncWriteExt;
+        self.port.write_all(data).await.map_err(|e| ConnectionError::Disconnected(ErrorDetail::with_source("Could not write to serial port", e)))?;
+        self.port.flush().await.map_err(|e| ConnectionError::Disconnected(ErrorDetail::with_source("Could not flush serial port", e)))
+    }
+
+    pub async fn read_exact(&mut self, size: usize) -> Result<Vec<u8>, ConnectionError> {
+        use tokio::io::AsyncReadExt;
+        let mut data = vec![0; size];
+        self.port.read_exact(&mut data).await.map_err(|e| ConnectionError::Disconnected(ErrorDetail::with_source("Could not read from serial port", e)))?;
+        Ok(data)
+    }
+}
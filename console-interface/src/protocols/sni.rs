@@ -1,44 +1,353 @@
 tonic::include_proto!("_");
 
-use grpc_web_client::Client;
 use async_trait::async_trait;
 use std::sync::Arc;
 use std::collections::HashMap;
 use futures::lock::Mutex;
 
-use crate::protocols::protocol::{Device, Connection, ConnectionError};
+use std::collections::BTreeSet;
+use crate::protocols::address;
+use crate::protocols::planner::{Limits, ReadPlan, WritePlan};
+use crate::protocols::protocol::{self, Capability, Device, Connection, ConnectionError, ControlConnection, ErrorDetail, FileEntry, FileType, FilesystemConnection, MappingOverride};
+
+// The browser build talks gRPC-web through fetch, the native build uses a regular HTTP/2 channel
+#[cfg(feature = "wasm")]
+type Client = grpc_web_client::Client;
+#[cfg(feature = "native")]
+type Client = tonic::transport::Channel;
+
+#[cfg(feature = "wasm")]
+fn create_client(uri: &str) -> Result<Client, ConnectionError> {
+    Ok(Client::new(uri.to_string()))
+}
+
+// The channel connects lazily on the first request, but it still has to be created inside a tokio runtime
+#[cfg(feature = "native")]
+fn create_client(uri: &str) -> Result<Client, ConnectionError> {
+    let endpoint = tonic::transport::Endpoint::from_shared(uri.to_string())
+        .map_err(|e| ConnectionError::ConnectFailed(ErrorDetail::with_source(&format!("Invalid SNI uri {}", uri), e)))?;
+    Ok(endpoint.connect_lazy())
+}
+
+fn capability(capability: i32) -> Option<Capability> {
+    match DeviceCapability::from_i32(capability)? {
+        DeviceCapability::None => None,
+        DeviceCapability::ReadMemory => Some(Capability::ReadMemory),
+        DeviceCapability::WriteMemory => Some(Capability::WriteMemory),
+        DeviceCapability::ExecuteAsm => Some(Capability::ExecuteAsm),
+        DeviceCapability::ResetSystem => Some(Capability::ResetSystem),
+        DeviceCapability::PauseUnpauseEmulation => Some(Capability::PauseUnpauseEmulation),
+        DeviceCapability::PauseToggleEmulation => Some(Capability::PauseToggleEmulation),
+        DeviceCapability::ResetToMenu => Some(Capability::ResetToMenu),
+        DeviceCapability::ReadDirectory => Some(Capability::ReadDirectory),
+        DeviceCapability::MakeDirectory => Some(Capability::MakeDirectory),
+        DeviceCapability::RemoveFile => Some(Capability::RemoveFile),
+        DeviceCapability::RenameFile => Some(Capability::RenameFile),
+        DeviceCapability::PutFile => Some(Capability::PutFile),
+        DeviceCapability::GetFile => Some(Capability::GetFile),
+        DeviceCapability::BootFile => Some(Capability::BootFile)
+    }
+}
+
+fn memory_mapping(mapping: i32) -> protocol::MemoryMapping {
+    match MemoryMapping::from_i32(mapping) {
+        Some(MemoryMapping::HiRom) => protocol::MemoryMapping::HiRom,
+        Some(MemoryMapping::LoRom) => protocol::MemoryMapping::LoRom,
+        Some(MemoryMapping::ExHiRom) => protocol::MemoryMapping::ExHiRom,
+        _ => protocol::MemoryMapping::Unknown
+    }
+}
+
+fn address_space(space: i32) -> protocol::AddressSpace {
+    match AddressSpace::from_i32(space) {
+        Some(AddressSpace::SnesABus) => protocol::AddressSpace::SnesABus,
+        Some(AddressSpace::Raw) => protocol::AddressSpace::Raw,
+        _ => protocol::AddressSpace::FxPakPro
+    }
+}
+
+// Translate a failed gRPC call into the matching connection error
+fn status_error(message: &str, status: tonic::Status) -> ConnectionError {
+    let code = status.code();
+    let detail = ErrorDetail::with_source(message, status);
+    match code {
+        tonic::Code::DeadlineExceeded => ConnectionError::Timeout(detail),
+        tonic::Code::NotFound => ConnectionError::DeviceNotFound(detail),
+        tonic::Code::Unimplemented => ConnectionError::Unsupported(detail),
+        tonic::Code::InvalidArgument | tonic::Code::OutOfRange => ConnectionError::InvalidRequest(detail),
+        _ => ConnectionError::Disconnected(detail)
+    }
+}
+
+// A long running StreamRead/StreamWrite call, where every request sent is answered by exactly one response.
+// gRPC-web has no support for client streaming, so this is only available for the native build and the
+// browser build keeps using the unary MultiRead/MultiWrite calls.
+#[cfg(feature = "native")]
+struct StreamSession<Req, Resp> {
+    requests: futures::channel::mpsc::UnboundedSender<Req>,
+    responses: tonic::codec::Streaming<Resp>
+}
+
+#[cfg(feature = "native")]
+impl<Req: Send + Sync + 'static, Resp> StreamSession<Req, Resp> {
+    fn send(&self, request: Req) -> Result<(), ConnectionError> {
+        self.requests.unbounded_send(request).map_err(|e| ConnectionError::Disconnected(ErrorDetail::with_source("Could not send stream request", e)))
+    }
+
+    async fn receive(&mut self) -> Result<Resp, ConnectionError> {
+        self.responses.message().await
+            .map_err(|e| status_error("Stream request failed", e))?
+            .ok_or_else(|| ConnectionError::Disconnected("Stream was closed by SNI".into()))
+    }
+}
+
+#[cfg(feature = "native")]
+type ReadSession = StreamSession<MultiReadMemoryRequest, MultiReadMemoryResponse>;
+#[cfg(feature = "native")]
+type WriteSession = StreamSession<MultiWriteMemoryRequest, MultiWriteMemoryResponse>;
+
+// The ROM header at $00:FFB0 is used to notice when a different ROM has been loaded
+const ROM_HEADER_ADDRESS: u32 = 0x00_FFB0;
+const ROM_HEADER_SIZE: u32 = 0x50;
+
+// Number of memory requests that can use a cached mapping before the ROM header is checked again
+const MAPPING_RECHECK_INTERVAL: u32 = 60;
+
+struct CachedMapping {
+    mapping: i32,
+    header: Vec<u8>,
+    uses: u32
+}
 
 pub struct SNIConnection {
-    client: Client,
-    mappings: Arc<Mutex<HashMap<String, i32>>>
+    // The description of the error if the uri was not usable, every operation fails with it the same way
+    // connecting to an address nothing is listening on would
+    client: Result<Client, String>,
+    mappings: Arc<Mutex<HashMap<String, CachedMapping>>>,
+    overrides: Arc<Mutex<HashMap<String, MappingOverride>>>,
+    capabilities: Arc<Mutex<HashMap<String, BTreeSet<Capability>>>>,
+    #[cfg(feature = "native")]
+    read_session: Arc<Mutex<Option<ReadSession>>>,
+    #[cfg(feature = "native")]
+    write_session: Arc<Mutex<Option<WriteSession>>>
 }
 
 impl SNIConnection {
     pub fn new(uri: &str) -> Self {
         Self {
-            client: Client::new(uri.to_string()),
-            mappings: Arc::new(Mutex::new(HashMap::new()))
+            client: create_client(uri).map_err(|e| e.detail().to_string()),
+            mappings: Arc::new(Mutex::new(HashMap::new())),
+            overrides: Arc::new(Mutex::new(HashMap::new())),
+            capabilities: Arc::new(Mutex::new(HashMap::new())),
+            #[cfg(feature = "native")]
+            read_session: Arc::new(Mutex::new(None)),
+            #[cfg(feature = "native")]
+            write_session: Arc::new(Mutex::new(None))
+        }
+    }
+
+    fn client(&self) -> Result<Client, ConnectionError> {
+        self.client.clone().map_err(|e| ConnectionError::ConnectFailed(e.into()))
+    }
+
+    #[cfg(feature = "wasm")]
+    async fn multi_read_request(&self, request: MultiReadMemoryRequest) -> Result<MultiReadMemoryResponse, ConnectionError> {
+        let mut client = device_memory_client::DeviceMemoryClient::new(self.client()?);
+        Ok(client.multi_read(tonic::Request::new(request)).await.map_err(|e| status_error("Multi-read failed", e))?.into_inner())
+    }
+
+    #[cfg(feature = "wasm")]
+    async fn multi_write_request(&self, request: MultiWriteMemoryRequest) -> Result<MultiWriteMemoryResponse, ConnectionError> {
+        let mut client = device_memory_client::DeviceMemoryClient::new(self.client()?);
+        Ok(client.multi_write(tonic::Request::new(request)).await.map_err(|e| status_error("Multi-write failed", e))?.into_inner())
+    }
+
+    // Reads go through a persistent StreamRead call that is opened on first use. The first request is queued
+    // before the call is made since SNI doesn't send the response headers until it has something to respond to.
+    // Any error drops the session so the next request starts a new one.
+    #[cfg(feature = "native")]
+    async fn multi_read_request(&self, request: MultiReadMemoryRequest) -> Result<MultiReadMemoryResponse, ConnectionError> {
+        let mut session = self.read_session.lock().await;
+        let response = match session.as_mut() {
+            Some(s) => match s.send(request) {
+                Ok(()) => s.receive().await,
+                Err(e) => Err(e)
+            },
+            None => {
+                let (tx, rx) = futures::channel::mpsc::unbounded();
+                tx.unbounded_send(request).map_err(|e| ConnectionError::Disconnected(ErrorDetail::with_source("Could not send stream request", e)))?;
+                let mut client = device_memory_client::DeviceMemoryClient::new(self.client()?);
+                let responses = client.stream_read(rx).await.map_err(|e| status_error("Could not open read stream", e))?.into_inner();
+                let s = session.insert(ReadSession { requests: tx, responses });
+                log::debug!("sni: Opened memory read stream");
+                s.receive().await
+            }
+        };
+
+        if response.is_err() {
+            *session = None;
+        }
+        response
+    }
+
+    #[cfg(feature = "native")]
+    async fn multi_write_request(&self, request: MultiWriteMemoryRequest) -> Result<MultiWriteMemoryResponse, ConnectionError> {
+        let mut session = self.write_session.lock().await;
+        let response = match session.as_mut() {
+            Some(s) => match s.send(request) {
+                Ok(()) => s.receive().await,
+                Err(e) => Err(e)
+            },
+            None => {
+                let (tx, rx) = futures::channel::mpsc::unbounded();
+                tx.unbounded_send(request).map_err(|e| ConnectionError::Disconnected(ErrorDetail::with_source("Could not send stream request", e)))?;
+                let mut client = device_memory_client::DeviceMemoryClient::new(self.client()?);
+                let responses = client.stream_write(rx).await.map_err(|e| status_error("Could not open write stream", e))?.into_inner();
+                let s = session.insert(WriteSession { requests: tx, responses });
+                log::debug!("sni: Opened memory write stream");
+                s.receive().await
+            }
+        };
+
+        if response.is_err() {
+            *session = None;
         }
+        response
     }
 
+    // Check the capabilities reported by SNI for the device, refreshing the device list if we haven't seen it yet
+    async fn require_capability(&self, device: &str, capability: Capability) -> Result<(), ConnectionError> {
+        let known = self.capabilities.lock().await.get(device).cloned();
+        let capabilities = match known {
+            Some(c) => c,
+            None => {
+                self.list_devices().await?;
+                self.capabilities.lock().await.get(device).cloned().ok_or_else(|| ConnectionError::DeviceNotFound(format!("Unknown device: {}", device).into()))?
+            }
+        };
+
+        if capabilities.contains(&capability) {
+            Ok(())
+        } else {
+            Err(ConnectionError::Unsupported(format!("Device does not support {:?}", capability).into()))
+        }
+    }
+
+    async fn read_rom_header(&self, device: &str, mapping: i32) -> Result<Vec<u8>, ConnectionError> {
+        let request = MultiReadMemoryRequest {
+            requests: vec![ReadMemoryRequest {
+                request_address: ROM_HEADER_ADDRESS,
+                request_address_space: AddressSpace::SnesABus.into(),
+                request_memory_mapping: mapping,
+                size: ROM_HEADER_SIZE
+            }],
+            uri: device.into()
+        };
+
+        let response = self.multi_read_request(request).await?;
+        Ok(response.responses.into_iter().next().map(|r| r.data).unwrap_or_default())
+    }
+
+    // The detected mapping is cached per device, but the ROM header is checked every now and then so loading
+    // another ROM doesn't leave us using the mapping of the previous one
     async fn get_mapping(&self, device: &str) -> Result<i32, ConnectionError> {
-        let mut client = device_memory_client::DeviceMemoryClient::new(self.client.clone());
+        let (fallback, rom_header) = match self.overrides.lock().await.get(device).cloned() {
+            Some(MappingOverride::Fixed(mapping)) => return Ok(mapping as i32),
+            Some(MappingOverride::Hint { fallback, rom_header }) => (fallback.map(|m| m as i32), rom_header),
+            Some(MappingOverride::Detect) | None => (None, None)
+        };
+
         let map_lock = self.mappings.clone();
         let mut mappings = map_lock.lock().await;
-        match mappings.get(device) {
-            Some(m) => Ok(*m),
-            _ => {
-                let mapping_request = tonic::Request::new(DetectMemoryMappingRequest {
-                    fallback_memory_mapping: None,
-                    rom_header00_ffb0: None,
-                    uri: device.into()
-                });
-
-                let mapping_response = client.mapping_detect(mapping_request).await.map_err(|_| ConnectionError("Mapping detection failed".into()))?.into_inner();
-                mappings.insert(device.to_string(), mapping_response.memory_mapping);
-                Ok(mapping_response.memory_mapping)
+        if let Some(cached) = mappings.get_mut(device) {
+            cached.uses += 1;
+            if cached.uses < MAPPING_RECHECK_INTERVAL {
+                return Ok(cached.mapping);
+            }
+
+            if self.read_rom_header(device, cached.mapping).await? == cached.header {
+                cached.uses = 0;
+                return Ok(cached.mapping);
+            }
+
+            log::debug!("sni: ROM header changed on {}, detecting memory mapping again", device);
+            mappings.remove(device);
+        }
+
+        let mut client = device_memory_client::DeviceMemoryClient::new(self.client()?);
+        let mapping_request = tonic::Request::new(DetectMemoryMappingRequest {
+            fallback_memory_mapping: fallback,
+            rom_header00_ffb0: rom_header,
+            uri: device.into()
+        });
+
+        let mapping_response = client.mapping_detect(mapping_request).await.map_err(|e| ConnectionError::MappingDetectFailed(ErrorDetail::with_source("Mapping detection failed", e)))?.into_inner();
+        let mapping = mapping_response.memory_mapping;
+        // Nothing to cache if SNI couldn't tell, the game may just not be running yet so try again next time
+        if memory_mapping(mapping) == protocol::MemoryMapping::Unknown {
+            return Err(ConnectionError::MappingDetectFailed(format!("SNI could not detect the memory mapping of {}", device).into()));
+        }
+        let header = self.read_rom_header(device, mapping).await?;
+        mappings.insert(device.to_string(), CachedMapping { mapping, header, uses: 0 });
+        Ok(mapping)
+    }
+
+    // SNI does its own batching for the device, so the plan only merges overlapping and adjacent ranges
+    // so the same memory isn't read more than once
+    async fn read_in(&self, device: &str, space: AddressSpace, address_info: &[u32]) -> Result<Vec<Vec<u8>>, ConnectionError> {
+        let memory_mapping = self.get_mapping(device).await?;
+        let plan = ReadPlan::new(address_info, &Limits::UNLIMITED);
+        let mut responses = Vec::new();
+        for batch in plan.batches() {
+            let request = MultiReadMemoryRequest {
+                requests: batch.chunks(2).map(|req| ReadMemoryRequest {
+                    request_address: req[0],
+                    request_address_space: space.into(),
+                    request_memory_mapping: memory_mapping,
+                    size: req[1]
+                }).collect(),
+                uri: device.into()
+            };
+
+            // A failed request might mean the device was reset or another ROM was loaded
+            let mut response = match self.multi_read_request(request).await {
+                Ok(r) => r,
+                Err(e) => {
+                    self.invalidate_mapping(device).await;
+                    return Err(e);
+                }
+            };
+            responses.push(response.responses.drain(..).map(|r| r.data).collect());
+        }
+
+        plan.assemble(responses)
+    }
+
+    async fn write_in(&self, device: &str, space: AddressSpace, addresses: &[u32], data: &[Vec<u8>]) -> Result<(), ConnectionError> {
+        let memory_mapping = self.get_mapping(device).await?;
+        let plan = WritePlan::new(addresses, data, &Limits::UNLIMITED);
+        for (addresses, data) in plan.batches() {
+            let request = MultiWriteMemoryRequest {
+                requests: addresses.iter().zip(data.iter()).map(|(address, data)| WriteMemoryRequest {
+                    data: data.to_vec(),
+                    request_address: *address,
+                    request_address_space: space.into(),
+                    request_memory_mapping: memory_mapping
+                }).collect(),
+                uri: device.into()
+            };
+
+            if let Err(e) = self.multi_write_request(request).await {
+                self.invalidate_mapping(device).await;
+                return Err(e);
             }
         }
+        Ok(())
+    }
+
+    // Forget the cached mapping, so it's detected again on the next request
+    async fn invalidate_mapping(&self, device: &str) {
+        self.mappings.lock().await.remove(device);
     }
 }
 
@@ -56,22 +365,51 @@ impl Connection for SNIConnection {
         Ok(true)
     }
 
+    // An abandoned stream request would leave its response in the stream, so start over with new streams
+    async fn recover(&self) {
+        #[cfg(feature = "native")]
+        {
+            *self.read_session.lock().await = None;
+            *self.write_session.lock().await = None;
+        }
+        self.mappings.lock().await.clear();
+    }
+
+    async fn memory_mapping(&self, device: &str) -> Result<protocol::MemoryMapping, ConnectionError>
+    {
+        match memory_mapping(self.get_mapping(device).await?) {
+            protocol::MemoryMapping::Unknown => Err(ConnectionError::MappingDetectFailed("SNI could not detect the memory mapping".into())),
+            mapping => Ok(mapping)
+        }
+    }
+
     async fn list_devices(&self) -> Result<Vec<Device>, ConnectionError>
     {
-        let mut client = devices_client::DevicesClient::new(self.client.clone());
+        let mut client = devices_client::DevicesClient::new(self.client()?);
         let mut devices = Vec::new();
         
         let request = tonic::Request::new(DevicesRequest {
             kinds: vec![]
         });
     
-        let response = client.list_devices(request).await.map_err(|_| ConnectionError("Could not list devices".into()))?;
+        let response = client.list_devices(request).await.map_err(|e| status_error("Could not list devices", e))?;
         let response = response.into_inner();
+        {
+            let mut capabilities = self.capabilities.lock().await;
+            for d in &response.devices {
+                capabilities.insert(d.uri.to_string(), d.capabilities.iter().filter_map(|c| capability(*c)).collect());
+            }
+        }
+
         for d in &response.devices {
             devices.push(Device {
                 name: d.display_name.to_string(),
                 uri: d.uri.to_string(),
-                info: None
+                kind: d.kind.to_string(),
+                firmware_version: None,
+                rom_name: None,
+                default_address_space: address_space(d.default_address_space),
+                capabilities: d.capabilities.iter().filter_map(|c| capability(*c)).collect()
             });
         }
 
@@ -82,22 +420,8 @@ impl Connection for SNIConnection {
         Ok(self.read_multi(device, &[address, size]).await?.remove(0))
     }
 
-    async fn read_multi(&self, device: &str, address_info: &[u32]) -> Result<Vec<Vec<u8>>, ConnectionError> 
-    {
-        let mut client = device_memory_client::DeviceMemoryClient::new(self.client.clone());
-        let memory_mapping = self.get_mapping(device).await?;
-        let request = tonic::Request::new(MultiReadMemoryRequest {
-            requests: address_info.chunks(2).map(|req| ReadMemoryRequest {
-                request_address: req[0],
-                request_address_space: AddressSpace::FxPakPro.into(),
-                request_memory_mapping: memory_mapping,
-                size: req[1]
-            }).collect(),
-            uri: device.into()
-        });
-
-        let mut response = client.multi_read(request).await.map_err(|_| ConnectionError("Multi-read failed".into()))?.into_inner();
-        Ok(response.responses.drain(..).map(|r| r.data).collect())
+    async fn read_multi(&self, device: &str, address_info: &[u32]) -> Result<Vec<Vec<u8>>, ConnectionError> {
+        self.read_in(device, AddressSpace::FxPakPro, address_info).await
     }
 
     async fn write_single(&self, device: &str, address: u32, data: &[u8])-> Result<(), ConnectionError> {
@@ -105,19 +429,203 @@ impl Connection for SNIConnection {
     }
 
     async fn write_multi(&self, device: &str, addresses: &[u32], data: &[Vec<u8>]) -> Result<(), ConnectionError> {
-        let mut client = device_memory_client::DeviceMemoryClient::new(self.client.clone());
-        let memory_mapping = self.get_mapping(device).await?;        
-        let request = tonic::Request::new(MultiWriteMemoryRequest {
-            requests: addresses.iter().zip(data.iter()).map(|(address, data)| WriteMemoryRequest {
-                data: data.to_vec(),
-                request_address: *address,
-                request_address_space: AddressSpace::FxPakPro.into(),
-                request_memory_mapping: memory_mapping
-            }).collect(),
-            uri: device.into()        
+        self.write_in(device, AddressSpace::FxPakPro, addresses, data).await
+    }
+
+    // SNI can hand raw addresses to the device itself, A-bus addresses are translated the same way as for
+    // every other connection so ranges crossing into differently mapped banks are caught
+    async fn read_multi_in(&self, device: &str, space: protocol::AddressSpace, address_info: &[u32]) -> Result<Vec<Vec<u8>>, ConnectionError> {
+        match space {
+            protocol::AddressSpace::FxPakPro => self.read_in(device, AddressSpace::FxPakPro, address_info).await,
+            protocol::AddressSpace::SnesABus => {
+                let mapping = self.memory_mapping(device).await?;
+                self.read_in(device, AddressSpace::FxPakPro, &address::ranges_to_fxpak(address_info, space, mapping)?).await
+            },
+            protocol::AddressSpace::Raw => self.read_in(device, AddressSpace::Raw, address_info).await
+        }
+    }
+
+    async fn write_multi_in(&self, device: &str, space: protocol::AddressSpace, addresses: &[u32], data: &[Vec<u8>]) -> Result<(), ConnectionError> {
+        match space {
+            protocol::AddressSpace::FxPakPro => self.write_in(device, AddressSpace::FxPakPro, addresses, data).await,
+            protocol::AddressSpace::SnesABus => {
+                let mapping = self.memory_mapping(device).await?;
+                self.write_in(device, AddressSpace::FxPakPro, &address::writes_to_fxpak(addresses, data, space, mapping)?, data).await
+            },
+            protocol::AddressSpace::Raw => self.write_in(device, AddressSpace::Raw, addresses, data).await
+        }
+    }
+
+    async fn set_memory_mapping(&self, device: &str, mapping: MappingOverride) -> Result<(), ConnectionError> {
+        self.overrides.lock().await.insert(device.to_string(), mapping);
+        self.invalidate_mapping(device).await;
+        Ok(())
+    }
+
+    fn filesystem(&self) -> Option<&dyn FilesystemConnection> {
+        Some(self)
+    }
+
+    fn control(&self) -> Option<&dyn ControlConnection> {
+        Some(self)
+    }
+}
+
+#[async_trait(?Send)]
+impl FilesystemConnection for SNIConnection {
+    async fn list_files(&self, device: &str, path: &str) -> Result<Vec<FileEntry>, ConnectionError> {
+        self.require_capability(device, Capability::ReadDirectory).await?;
+        let mut client = device_filesystem_client::DeviceFilesystemClient::new(self.client()?);
+        let request = tonic::Request::new(ReadDirectoryRequest {
+            uri: device.into(),
+            path: path.into()
+        });
+
+        let response = client.read_directory(request).await.map_err(|e| status_error("Could not read directory", e))?.into_inner();
+        Ok(response.entries.iter().filter(|e| e.name != "." && e.name != "..").map(|e| FileEntry {
+            name: e.name.to_string(),
+            file_type: if e.r#type == DirEntryType::Directory as i32 { FileType::Directory } else { FileType::File }
+        }).collect())
+    }
+
+    async fn get_file(&self, device: &str, path: &str) -> Result<Vec<u8>, ConnectionError> {
+        self.require_capability(device, Capability::GetFile).await?;
+        let mut client = device_filesystem_client::DeviceFilesystemClient::new(self.client()?);
+        let request = tonic::Request::new(GetFileRequest {
+            uri: device.into(),
+            path: path.into()
+        });
+
+        let response = client.get_file(request).await.map_err(|e| status_error("Could not get file", e))?.into_inner();
+        Ok(response.data)
+    }
+
+    async fn put_file(&self, device: &str, path: &str, data: &[u8]) -> Result<(), ConnectionError> {
+        self.require_capability(device, Capability::PutFile).await?;
+        let mut client = device_filesystem_client::DeviceFilesystemClient::new(self.client()?);
+        let request = tonic::Request::new(PutFileRequest {
+            uri: device.into(),
+            path: path.into(),
+            data: data.to_vec()
+        });
+
+        let response = client.put_file(request).await.map_err(|e| status_error("Could not put file", e))?.into_inner();
+        if response.size as usize != data.len() {
+            return Err(ConnectionError::ProtocolViolation(format!("Wrote {:X} bytes but expected to write {:X}", response.size, data.len()).into()));
+        }
+        Ok(())
+    }
+
+    async fn remove_file(&self, device: &str, path: &str) -> Result<(), ConnectionError> {
+        self.require_capability(device, Capability::RemoveFile).await?;
+        let mut client = device_filesystem_client::DeviceFilesystemClient::new(self.client()?);
+        let request = tonic::Request::new(RemoveFileRequest {
+            uri: device.into(),
+            path: path.into()
+        });
+
+        let _ = client.remove_file(request).await.map_err(|e| status_error("Could not remove file", e))?.into_inner();
+        Ok(())
+    }
+
+    async fn rename_file(&self, device: &str, path: &str, new_path: &str) -> Result<(), ConnectionError> {
+        self.require_capability(device, Capability::RenameFile).await?;
+        let mut client = device_filesystem_client::DeviceFilesystemClient::new(self.client()?);
+        let request = tonic::Request::new(RenameFileRequest {
+            uri: device.into(),
+            path: path.into(),
+            new_filename: new_path.into()
+        });
+
+        let _ = client.rename_file(request).await.map_err(|e| status_error("Could not rename file", e))?.into_inner();
+        Ok(())
+    }
+
+    async fn make_directory(&self, device: &str, path: &str) -> Result<(), ConnectionError> {
+        self.require_capability(device, Capability::MakeDirectory).await?;
+        let mut client = device_filesystem_client::DeviceFilesystemClient::new(self.client()?);
+        let request = tonic::Request::new(MakeDirectoryRequest {
+            uri: device.into(),
+            path: path.into()
+        });
+
+        let _ = client.make_directory(request).await.map_err(|e| status_error("Could not make directory", e))?.into_inner();
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl ControlConnection for SNIConnection {
+    async fn reset(&self, device: &str) -> Result<(), ConnectionError> {
+        self.require_capability(device, Capability::ResetSystem).await?;
+        let mut client = device_control_client::DeviceControlClient::new(self.client()?);
+        let request = tonic::Request::new(ResetSystemRequest {
+            uri: device.into()
+        });
+
+        let _ = client.reset_system(request).await.map_err(|e| status_error("Could not reset system", e))?.into_inner();
+        Ok(())
+    }
+
+    async fn reset_to_menu(&self, device: &str) -> Result<(), ConnectionError> {
+        self.require_capability(device, Capability::ResetToMenu).await?;
+        let mut client = device_control_client::DeviceControlClient::new(self.client()?);
+        let request = tonic::Request::new(ResetToMenuRequest {
+            uri: device.into()
+        });
+
+        let _ = client.reset_to_menu(request).await.map_err(|e| status_error("Could not reset to menu", e))?.into_inner();
+        Ok(())
+    }
+
+    // Booting a ROM is part of the filesystem service in SNI
+    async fn boot(&self, device: &str, path: &str) -> Result<(), ConnectionError> {
+        self.require_capability(device, Capability::BootFile).await?;
+        let mut client = device_filesystem_client::DeviceFilesystemClient::new(self.client()?);
+        let request = tonic::Request::new(BootFileRequest {
+            uri: device.into(),
+            path: path.into()
         });
 
-        let _ = client.multi_write(request).await.map_err(|_| ConnectionError("Multi-write failed".into()))?.into_inner();
+        let _ = client.boot_file(request).await.map_err(|e| status_error("Could not boot file", e))?.into_inner();
         Ok(())
     }
-}
\ No newline at end of file
+
+    async fn pause_emulation(&self, device: &str, paused: bool) -> Result<bool, ConnectionError> {
+        self.require_capability(device, Capability::PauseUnpauseEmulation).await?;
+        let mut client = device_control_client::DeviceControlClient::new(self.client()?);
+        let request = tonic::Request::new(PauseEmulationRequest {
+            uri: device.into(),
+            paused
+        });
+
+        let response = client.pause_unpause_emulation(request).await.map_err(|e| status_error("Could not pause emulation", e))?.into_inner();
+        Ok(response.paused)
+    }
+
+    async fn toggle_pause_emulation(&self, device: &str) -> Result<(), ConnectionError> {
+        self.require_capability(device, Capability::PauseToggleEmulation).await?;
+        let mut client = device_control_client::DeviceControlClient::new(self.client()?);
+        let request = tonic::Request::new(PauseToggleEmulationRequest {
+            uri: device.into()
+        });
+
+        let _ = client.pause_toggle_emulation(request).await.map_err(|e| status_error("Could not toggle emulation pause", e))?.into_inner();
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "native"))]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    #[test]
+    fn invalid_uri_fails_to_connect() {
+        let connection = SNIConnection::new("not a uri");
+        let error = block_on(connection.connect()).unwrap_err();
+        assert!(matches!(error, ConnectionError::ConnectFailed(_)));
+        assert!(error.to_string().contains("not a uri"));
+        assert!(matches!(block_on(connection.read_single("device", 0xF50000, 1)), Err(ConnectionError::ConnectFailed(_))));
+    }
+}
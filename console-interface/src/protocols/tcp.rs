@@ -0,0 +1,65 @@
+// Thin TCP stream wrapper for the protocols that talk to emulators over plain TCP. Browsers can't open
+// raw TCP connections, so the browser build only has a stand-in that refuses to connect.
+
+use crate::protocols::protocol::ConnectionError;
+#[cfg(feature = "native")]
+use crate::protocols::protocol::ErrorDetail;
+
+#[cfg(feature = "wasm")]
+pub struct TcpStream;
+
+#[cfg(feature = "wasm")]
+impl TcpStream {
+    pub async fn connect(_address: &str) -> Result<Self, ConnectionError> {
+        Err(ConnectionError::Unsupported("TCP connections are not available in the browser".into()))
+    }
+
+    pub async fn send(&mut self, _data: &[u8]) -> Result<(), ConnectionError> {
+        Err(ConnectionError::Unsupported("TCP connections are not available in the browser".into()))
+    }
+
+    pub async fn read_exact(&mut self, _size: usize) -> Result<Vec<u8>, ConnectionError> {
+        Err(ConnectionError::Unsupported("TCP connections are not available in the browser".into()))
+    }
+
+    pub async fn read_line(&mut self) -> Result<String, ConnectionError> {
+        Err(ConnectionError::Unsupported("TCP connections are not available in the browser".into()))
+    }
+}
+
+#[cfg(feature = "native")]
+pub struct TcpStream {
+    stream: tokio::io::BufReader<tokio::net::TcpStream>
+}
+
+#[cfg(feature = "native")]
+impl TcpStream {
+    pub async fn connect(address: &str) -> Result<Self, ConnectionError> {
+        let stream = tokio::net::TcpStream::connect(address).await.map_err(|e| ConnectionError::ConnectFailed(ErrorDetail::with_source("Could not connect to TCP socket", e)))?;
+        let _ = stream.set_nodelay(true);
+        Ok(Self { stream: tokio::io::BufReader::new(stream) })
+    }
+
+    pub async fn send(&mut self, data: &[u8]) -> Result<(), ConnectionError> {
+        use tokio::io::AsyncWriteExt;
+        self.stream.get_mut().write_all(data).await.map_err(|e| ConnectionError::Disconnected(ErrorDetail::with_source("Could not send data", e)))
+    }
+
+    pub async fn read_exact(&mut self, size: usize) -> Result<Vec<u8>, ConnectionError> {
+        use tokio::io::AsyncReadExt;
+        let mut data = vec![0; size];
+        self.stream.read_exact(&mut data).await.map_err(|e| ConnectionError::Disconnected(ErrorDetail::with_source("Could not read data", e)))?;
+        Ok(data)
+    }
+
+    // Read up to and including the next newline, which is stripped from the result
+    pub async fn read_line(&mut self) -> Result<String, ConnectionError> {
+        use tokio::io::AsyncBufReadExt;
+        let mut line = String::new();
+        match self.stream.read_line(&mut line).await {
+            Ok(0) => Err(ConnectionError::Disconnected("Connection was closed".into())),
+            Ok(_) => Ok(line.trim_end_matches('\n').to_string()),
+            Err(e) => Err(ConnectionError::Disconnected(ErrorDetail::with_source("Could not read data", e)))
+        }
+    }
+}
@@ -0,0 +1,314 @@
+// Deadlines for connection operations, so a stalled server or device can't hang the caller forever.
+// TimeoutConnection wraps any other connection and gives up on operations that take longer than the
+// configured timeout for their kind, resetting the inner connection so the next operation starts clean.
+
+use async_trait::async_trait;
+use futures::future::{self, Either};
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::Duration;
+use wasm_bindgen::prelude::*;
+
+use crate::protocols::metrics::Metrics;
+use crate::protocols::protocol::{AddressSpace, Connection, ConnectionError, ControlConnection, Device, FileEntry, FilesystemConnection, MappingOverride, MemoryMapping};
+
+#[cfg(feature = "wasm")]
+pub async fn sleep(duration: Duration) {
+    gloo_timers::future::TimeoutFuture::new(duration.as_millis() as u32).await;
+}
+
+#[cfg(feature = "native")]
+pub async fn sleep(duration: Duration) {
+    tokio::time::sleep(duration).await;
+}
+
+// Wall clock time in milliseconds, for measuring how long operations take
+#[cfg(feature = "wasm")]
+pub fn now_ms() -> f64 {
+    js_sys::Date::now()
+}
+
+#[cfg(feature = "native")]
+pub fn now_ms() -> f64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.as_secs_f64() * 1000.0).unwrap_or_default()
+}
+
+// Run the future, failing with a Timeout error if it doesn't finish in time
+pub async fn deadline<T, F>(timeout: Option<Duration>, future: F) -> Result<T, ConnectionError>
+where
+    F: Future<Output = Result<T, ConnectionError>>
+{
+    let duration = match timeout {
+        Some(d) => d,
+        None => return future.await
+    };
+
+    futures::pin_mut!(future);
+    let timer = sleep(duration);
+    futures::pin_mut!(timer);
+
+    match future::select(future, timer).await {
+        Either::Left((result, _)) => result,
+        Either::Right(_) => Err(ConnectionError::Timeout(format!("Operation did not finish within {} ms", duration.as_millis()).into()))
+    }
+}
+
+// Kinds of operations that can have their own timeout
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Connect = 0,
+    ListDevices = 1,
+    Memory = 2,
+    Filesystem = 3,
+    Control = 4
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    pub connect: Option<Duration>,
+    pub list_devices: Option<Duration>,
+    pub memory: Option<Duration>,
+    pub filesystem: Option<Duration>,
+    pub control: Option<Duration>
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: Some(Duration::from_secs(5)),
+            list_devices: Some(Duration::from_secs(5)),
+            memory: Some(Duration::from_secs(2)),
+            // Uploading a full ROM over usb2snes can take a while
+            filesystem: Some(Duration::from_secs(120)),
+            control: Some(Duration::from_secs(5))
+        }
+    }
+}
+
+impl Timeouts {
+    pub fn get(&self, operation: Operation) -> Option<Duration> {
+        match operation {
+            Operation::Connect => self.connect,
+            Operation::ListDevices => self.list_devices,
+            Operation::Memory => self.memory,
+            Operation::Filesystem => self.filesystem,
+            Operation::Control => self.control
+        }
+    }
+
+    pub fn set(&mut self, operation: Operation, timeout: Option<Duration>) {
+        match operation {
+            Operation::Connect => self.connect = timeout,
+            Operation::ListDevices => self.list_devices = timeout,
+            Operation::Memory => self.memory = timeout,
+            Operation::Filesystem => self.filesystem = timeout,
+            Operation::Control => self.control = timeout
+        }
+    }
+}
+
+pub struct TimeoutConnection<C> {
+    inner: C,
+    timeouts: Mutex<Timeouts>
+}
+
+impl<C: Connection> TimeoutConnection<C> {
+    pub fn new(inner: C) -> Self {
+        Self::with_timeouts(inner, Timeouts::default())
+    }
+
+    pub fn with_timeouts(inner: C, timeouts: Timeouts) -> Self {
+        Self { inner, timeouts: Mutex::new(timeouts) }
+    }
+
+    async fn run<T, F>(&self, operation: Operation, future: F) -> Result<T, ConnectionError>
+    where
+        F: Future<Output = Result<T, ConnectionError>>
+    {
+        let timeout = self.timeouts.lock().unwrap().get(operation);
+        let result = deadline(timeout, future).await;
+        // Only a timeout leaves the inner connection in the middle of a request, other errors including
+        // Cancelled are passed on without touching it
+        if let Err(ConnectionError::Timeout(e)) = &result {
+            log::debug!("timeout: {:?} failed: {}", operation, e);
+            self.inner.recover().await;
+        }
+        result
+    }
+}
+
+#[async_trait(?Send)]
+impl<C: Connection> Connection for TimeoutConnection<C> {
+    async fn connect(&self) -> Result<bool, ConnectionError> {
+        self.run(Operation::Connect, self.inner.connect()).await
+    }
+
+    async fn disconnect(&self) -> Result<bool, ConnectionError> {
+        self.run(Operation::Connect, self.inner.disconnect()).await
+    }
+
+    async fn list_devices(&self) -> Result<Vec<Device>, ConnectionError> {
+        self.run(Operation::ListDevices, self.inner.list_devices()).await
+    }
+
+    async fn read_multi(&self, device: &str, address_info: &[u32]) -> Result<Vec<Vec<u8>>, ConnectionError> {
+        self.run(Operation::Memory, self.inner.read_multi(device, address_info)).await
+    }
+
+    async fn read_single(&self, device: &str, address: u32, size: u32) -> Result<Vec<u8>, ConnectionError> {
+        self.run(Operation::Memory, self.inner.read_single(device, address, size)).await
+    }
+
+    async fn write_multi(&self, device: &str, addresses: &[u32], data: &[Vec<u8>]) -> Result<(), ConnectionError> {
+        self.run(Operation::Memory, self.inner.write_multi(device, addresses, data)).await
+    }
+
+    async fn write_single(&self, device: &str, address: u32, data: &[u8]) -> Result<(), ConnectionError> {
+        self.run(Operation::Memory, self.inner.write_single(device, address, data)).await
+    }
+
+    async fn memory_mapping(&self, device: &str) -> Result<MemoryMapping, ConnectionError> {
+        self.run(Operation::Memory, self.inner.memory_mapping(device)).await
+    }
+
+    async fn set_memory_mapping(&self, device: &str, mapping: MappingOverride) -> Result<(), ConnectionError> {
+        self.inner.set_memory_mapping(device, mapping).await
+    }
+
+    async fn read_multi_in(&self, device: &str, space: AddressSpace, address_info: &[u32]) -> Result<Vec<Vec<u8>>, ConnectionError> {
+        self.run(Operation::Memory, self.inner.read_multi_in(device, space, address_info)).await
+    }
+
+    async fn write_multi_in(&self, device: &str, space: AddressSpace, addresses: &[u32], data: &[Vec<u8>]) -> Result<(), ConnectionError> {
+        self.run(Operation::Memory, self.inner.write_multi_in(device, space, addresses, data)).await
+    }
+
+    async fn recover(&self) {
+        self.inner.recover().await
+    }
+
+    fn set_timeout(&self, operation: Operation, timeout: Option<Duration>) -> Result<(), ConnectionError> {
+        self.timeouts.lock().unwrap().set(operation, timeout);
+        Ok(())
+    }
+
+    fn metrics(&self) -> Option<&Metrics> {
+        self.inner.metrics()
+    }
+
+    fn filesystem(&self) -> Option<&dyn FilesystemConnection> {
+        self.inner.filesystem().map(|_| self as &dyn FilesystemConnection)
+    }
+
+    fn control(&self) -> Option<&dyn ControlConnection> {
+        self.inner.control().map(|_| self as &dyn ControlConnection)
+    }
+}
+
+#[async_trait(?Send)]
+impl<C: Connection> FilesystemConnection for TimeoutConnection<C> {
+    async fn list_files(&self, device: &str, path: &str) -> Result<Vec<FileEntry>, ConnectionError> {
+        self.run(Operation::Filesystem, self.inner.require_filesystem()?.list_files(device, path)).await
+    }
+
+    async fn get_file(&self, device: &str, path: &str) -> Result<Vec<u8>, ConnectionError> {
+        self.run(Operation::Filesystem, self.inner.require_filesystem()?.get_file(device, path)).await
+    }
+
+    async fn put_file(&self, device: &str, path: &str, data: &[u8]) -> Result<(), ConnectionError> {
+        self.run(Operation::Filesystem, self.inner.require_filesystem()?.put_file(device, path, data)).await
+    }
+
+    async fn remove_file(&self, device: &str, path: &str) -> Result<(), ConnectionError> {
+        self.run(Operation::Filesystem, self.inner.require_filesystem()?.remove_file(device, path)).await
+    }
+
+    async fn rename_file(&self, device: &str, path: &str, new_path: &str) -> Result<(), ConnectionError> {
+        self.run(Operation::Filesystem, self.inner.require_filesystem()?.rename_file(device, path, new_path)).await
+    }
+
+    async fn make_directory(&self, device: &str, path: &str) -> Result<(), ConnectionError> {
+        self.run(Operation::Filesystem, self.inner.require_filesystem()?.make_directory(device, path)).await
+    }
+
+    async fn put_file_with_progress(&self, device: &str, path: &str, data: &[u8], progress: &dyn Fn(usize, usize)) -> Result<(), ConnectionError> {
+        self.run(Operation::Filesystem, self.inner.require_filesystem()?.put_file_with_progress(device, path, data, progress)).await
+    }
+}
+
+#[async_trait(?Send)]
+impl<C: Connection> ControlConnection for TimeoutConnection<C> {
+    async fn reset(&self, device: &str) -> Result<(), ConnectionError> {
+        self.run(Operation::Control, self.inner.require_control()?.reset(device)).await
+    }
+
+    async fn reset_to_menu(&self, device: &str) -> Result<(), ConnectionError> {
+        self.run(Operation::Control, self.inner.require_control()?.reset_to_menu(device)).await
+    }
+
+    async fn boot(&self, device: &str, path: &str) -> Result<(), ConnectionError> {
+        self.run(Operation::Control, self.inner.require_control()?.boot(device, path)).await
+    }
+
+    async fn pause_emulation(&self, device: &str, paused: bool) -> Result<bool, ConnectionError> {
+        self.run(Operation::Control, self.inner.require_control()?.pause_emulation(device, paused)).await
+    }
+
+    async fn toggle_pause_emulation(&self, device: &str) -> Result<(), ConnectionError> {
+        self.run(Operation::Control, self.inner.require_control()?.toggle_pause_emulation(device)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Fails every read with the error for the given code, and counts how often it was recovered
+    struct Failing {
+        code: &'static str,
+        recovered: AtomicUsize
+    }
+
+    #[async_trait(?Send)]
+    impl Connection for Failing {
+        async fn connect(&self) -> Result<bool, ConnectionError> { Ok(true) }
+        async fn disconnect(&self) -> Result<bool, ConnectionError> { Ok(true) }
+        async fn list_devices(&self) -> Result<Vec<Device>, ConnectionError> { Ok(Vec::new()) }
+        async fn read_multi(&self, _device: &str, _address_info: &[u32]) -> Result<Vec<Vec<u8>>, ConnectionError> {
+            Err(ConnectionError::from_code(self.code, "Failed".into()))
+        }
+        async fn read_single(&self, device: &str, address: u32, size: u32) -> Result<Vec<u8>, ConnectionError> {
+            Ok(self.read_multi(device, &[address, size]).await?.remove(0))
+        }
+        async fn write_multi(&self, _device: &str, _addresses: &[u32], _data: &[Vec<u8>]) -> Result<(), ConnectionError> { Ok(()) }
+        async fn write_single(&self, _device: &str, _address: u32, _data: &[u8]) -> Result<(), ConnectionError> { Ok(()) }
+        async fn recover(&self) {
+            self.recovered.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn recoveries(code: &'static str) -> usize {
+        let connection = TimeoutConnection::new(Failing { code, recovered: AtomicUsize::new(0) });
+        connection.set_timeout(Operation::Memory, None).unwrap();
+        let error = block_on(connection.read_single("device", 0xF5_0000, 1)).unwrap_err();
+        assert_eq!(error.code(), code);
+        connection.inner.recovered.load(Ordering::SeqCst)
+    }
+
+    #[test]
+    fn only_timeouts_are_recovered() {
+        assert_eq!(recoveries("TIMEOUT"), 1);
+        assert_eq!(recoveries("CANCELLED"), 0);
+        assert_eq!(recoveries("DISCONNECTED"), 0);
+    }
+
+    #[test]
+    fn cancelling_does_not_need_a_reconnect() {
+        let error = ConnectionError::from_code("CANCELLED", "Request was cancelled".into());
+        assert!(matches!(error, ConnectionError::Cancelled(_)));
+        assert!(!error.requires_reconnect());
+    }
+}
@@ -0,0 +1,56 @@
+// Thin UDP socket wrapper for the protocols that talk to emulators over UDP. Browsers can't send UDP
+// datagrams at all, so the browser build only has a stand-in that refuses to connect.
+
+use crate::protocols::protocol::ConnectionError;
+#[cfg(feature = "native")]
+use crate::protocols::protocol::ErrorDetail;
+
+// Largest datagram we expect to receive
+#[cfg(feature = "native")]
+const MAX_DATAGRAM_SIZE: usize = 65536;
+
+#[cfg(feature = "wasm")]
+pub struct UdpSocket;
+
+#[cfg(feature = "wasm")]
+impl UdpSocket {
+    pub async fn connect(_address: &str) -> Result<Self, ConnectionError> {
+        Err(ConnectionError::Unsupported("UDP connections are not available in the browser".into()))
+    }
+
+    pub async fn send(&self, _data: &[u8]) -> Result<(), ConnectionError> {
+        Err(ConnectionError::Unsupported("UDP connections are not available in the browser".into()))
+    }
+
+    pub async fn receive(&self) -> Result<Vec<u8>, ConnectionError> {
+        Err(ConnectionError::Unsupported("UDP connections are not available in the browser".into()))
+    }
+}
+
+#[cfg(feature = "native")]
+pub struct UdpSocket {
+    socket: tokio::net::UdpSocket
+}
+
+#[cfg(feature = "native")]
+impl UdpSocket {
+    // Bind to a local port and only accept datagrams from the given address
+    pub async fn connect(address: &str) -> Result<Self, ConnectionError> {
+        let socket = tokio::net::UdpSocket::bind("0.0.0.0:0").await.map_err(|e| ConnectionError::ConnectFailed(ErrorDetail::with_source("Could not bind UDP socket", e)))?;
+        socket.connect(address).await.map_err(|e| ConnectionError::ConnectFailed(ErrorDetail::with_source("Could not connect UDP socket", e)))?;
+        Ok(Self { socket })
+    }
+
+    pub async fn send(&self, data: &[u8]) -> Result<(), ConnectionError> {
+        self.socket.send(data).await.map_err(|e| ConnectionError::Disconnected(ErrorDetail::with_source("Could not send datagram", e)))?;
+        Ok(())
+    }
+
+    // A refused connection only shows up here, when nothing is listening on the other end
+    pub async fn receive(&self) -> Result<Vec<u8>, ConnectionError> {
+        let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+        let size = self.socket.recv(&mut buffer).await.map_err(|e| ConnectionError::Disconnected(ErrorDetail::with_source("Could not receive datagram", e)))?;
+        buffer.truncate(size);
+        Ok(buffer)
+    }
+}
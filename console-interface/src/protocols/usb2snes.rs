@@ -1,10 +1,11 @@
-use ws_stream_wasm::*;
 use serde::{Serialize, Deserialize};
 use async_trait::async_trait;
-use futures::{stream::StreamExt, SinkExt};
 use std::{sync::Arc};
+use std::collections::{BTreeSet, HashMap};
 use futures::lock::Mutex;
-use crate::protocols::protocol::{Device, Connection, ConnectionError};
+use crate::protocols::protocol::{AddressSpace, Capability, Device, Connection, ConnectionError, ControlConnection, ErrorDetail, FileEntry, FileType, FilesystemConnection};
+use crate::protocols::planner::{Limits, ReadPlan, WritePlan};
+use crate::protocols::websocket::{WebSocket, WsFrame};
 
 #[allow(non_snake_case)]
 #[derive(Debug, Serialize)]
@@ -21,6 +22,85 @@ struct SnesResponse {
     pub Results: Vec<String>
 }
 
+// Info responds with [firmware version, version string, running ROM, flags...], where the flags
+// list the command groups that the device does not support
+fn parse_device_info(device: &str, info: &[String]) -> Device {
+    let flags = info.get(3..).unwrap_or(&[]);
+    let mut capabilities = BTreeSet::new();
+    capabilities.insert(Capability::ReadMemory);
+    capabilities.insert(Capability::WriteMemory);
+
+    if !flags.iter().any(|f| f == "NO_CONTROL_CMD") {
+        capabilities.extend(&[Capability::ResetSystem, Capability::ResetToMenu, Capability::BootFile]);
+    }
+
+    if !flags.iter().any(|f| f == "NO_FILE_CMD") {
+        capabilities.extend(&[Capability::ReadDirectory, Capability::MakeDirectory, Capability::RemoveFile,
+                              Capability::RenameFile, Capability::PutFile, Capability::GetFile]);
+    }
+
+    Device {
+        name: device.to_string(),
+        uri: device.to_string(),
+        kind: info.get(1).filter(|k| !k.is_empty()).map(|k| k.to_lowercase()).unwrap_or_else(|| "usb2snes".into()),
+        firmware_version: info.first().cloned(),
+        rom_name: info.get(2).filter(|r| !r.is_empty() && r.as_str() != "No Info").cloned(),
+        default_address_space: AddressSpace::FxPakPro,
+        capabilities
+    }
+}
+
+// The usb2snes servers around, told apart by what they answer to AppVersion
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerKind {
+    QUsb2Snes,
+    Usb2Snes,
+    Sni
+}
+
+// What the server we're connected to supports, found out when connecting
+#[derive(Debug, Clone)]
+pub struct ServerInfo {
+    pub kind: ServerKind,
+    pub version: String,
+    pub limits: Limits
+}
+
+// The name we register with, shown in the server's list of connected apps
+const APP_NAME: &str = "SMZ3 Randomizer";
+
+// QUsb2snes hands VGET/VPUT to the firmware as they are, which takes up to 255 bytes per range
+// rather than 255 bytes in total
+const QUSB2SNES_LIMITS: Limits = Limits { max_ranges: 8, max_range_size: 255, max_batch_size: 8 * 255, max_single_size: None };
+
+impl ServerInfo {
+    // QUsb2snes answers "QUsb2Snes-<version>", SNI's usb2snes shim "SNI-<version>",
+    // and the original usb2snes server just its version number
+    fn from_app_version(version: &str) -> Self {
+        let lower = version.to_lowercase();
+        let kind = if lower.starts_with("qusb2snes") {
+            ServerKind::QUsb2Snes
+        } else if lower.starts_with("sni") {
+            ServerKind::Sni
+        } else {
+            ServerKind::Usb2Snes
+        };
+
+        // Only QUsb2snes is known to pass larger VGET/VPUT requests on, the others get the limits of the original server
+        let limits = match kind {
+            ServerKind::QUsb2Snes => QUSB2SNES_LIMITS,
+            ServerKind::Usb2Snes | ServerKind::Sni => Limits::USB2SNES
+        };
+
+        Self { kind, version: version.to_string(), limits }
+    }
+
+    // The original server drops the connection on commands it doesn't know, and Name is one of them
+    pub fn supports_name(&self) -> bool {
+        self.kind != ServerKind::Usb2Snes
+    }
+}
+
 #[derive(Clone)]
 enum ConnectionState {
     Disconnected,
@@ -28,13 +108,6 @@ enum ConnectionState {
     Attached
 }
 
-#[allow(dead_code, non_camel_case_types)]
-#[derive(Debug)]
-pub enum FileType {
-    Directory = 0,
-    File = 1
-}
-
 #[allow(dead_code)]
 #[derive(Debug)]
 enum Command {
@@ -65,9 +138,15 @@ enum Command {
 enum CommandResponseType {
     Text,
     Binary(usize),
+    File,
     None
 }
 
+// Binary file uploads are sent in chunks, larger websocket messages are not handled by all usb2snes servers
+const PUT_FILE_CHUNK_SIZE: usize = 1024;
+// Number of PutFile chunks between progress reports
+const PROGRESS_INTERVAL: usize = 64;
+
 #[derive(Debug)]
 enum CommandResponse {
     Response(SnesResponse),
@@ -76,157 +155,288 @@ enum CommandResponse {
 }
 
 pub struct Socket {
-    ws: Option<WsMeta>,
-    wsio: Option<WsStream>,
+    ws: Option<WebSocket>,
     state: ConnectionState,
-    device: String
+    server: Option<ServerInfo>
+}
+
+impl Socket {
+    fn new() -> Self {
+        Self { ws: None, state: ConnectionState::Disconnected, server: None }
+    }
+
+    fn reset(&mut self) {
+        self.ws = None;
+        self.state = ConnectionState::Disconnected;
+        self.server = None;
+    }
+}
+
+type Slot = Arc<Mutex<Socket>>;
+
+fn get_size(addrs: &[String]) -> Result<usize, ConnectionError>  {
+    addrs
+    .iter()
+    .skip(1)
+    .step_by(2)
+    .fold(Ok(0), |acc, size| {
+        match acc {
+            Ok(acc) => Ok(usize::from_str_radix(size, 16).map_err(|e| ConnectionError::InvalidRequest(ErrorDetail::with_source("Could not get data size for request", e)))? + acc),
+            Err(e) => Err(e)
+        }
+    })
+}
+
+// Ask the server who it is and register our name with it if it knows how
+async fn negotiate(sock: &mut Socket) -> Result<ServerInfo, ConnectionError> {
+    let version = match exchange(sock, Command::AppVersion, None).await? {
+        CommandResponse::Response(r) => r.Results.first().cloned().unwrap_or_default(),
+        _ => return Err(ConnectionError::ProtocolViolation("Unexpected AppVersion response".into()))
+    };
+
+    let server = ServerInfo::from_app_version(&version);
+    if server.supports_name() {
+        exchange(sock, Command::Name(APP_NAME.to_string()), None).await?;
+    }
+
+    log::debug!("usb2snes: Server is {:?} version {}", server.kind, server.version);
+    Ok(server)
+}
+
+// Attach has no response, the server only complains about a missing device on the next command
+async fn attach(sock: &mut Socket, device: &str) -> Result<(), ConnectionError> {
+    let ws = sock.ws.as_mut().ok_or_else(|| ConnectionError::Disconnected("Could not get websocket".into()))?;
+    let _ = ws.send(WsFrame::Text(
+        serde_json::to_string(&SnesRequest { Opcode: "Attach".into(), Space: "SNES".into(), Flags: None, Operands: Some(vec![device.to_string()]) })
+        .map_err(|e| ConnectionError::InvalidRequest(ErrorDetail::with_source("Could not serialize attach request", e)))?
+    )).await?;
+
+    sock.state = ConnectionState::Attached;
+    log::debug!("usb2snes: Attached to device: {}", device);
+    Ok(())
+}
+
+// Send a command over a connected socket and read its response.
+// Progress is reported as (bytes sent, total bytes) while the binary data of a command is being sent
+async fn exchange(sock: &mut Socket, command: Command, progress: Option<&dyn Fn(usize, usize)>) -> Result<CommandResponse, ConnectionError> {
+    match &command {
+        Command::PutFile(args, d) => log::debug!("usb2snes: Sending command: PutFile({:?}, {} bytes)", args, d.len()),
+        _ => log::debug!("usb2snes: Sending command: {:?}", &command)
+    }
+    let (opcode, operands, flags, space, response_type) = match &command {
+        Command::DeviceList =>                  ("DeviceList", None, None, "SNES", CommandResponseType::Text),
+        Command::Info =>                        ("Info", None, None, "SNES", CommandResponseType::Text),
+        Command::AppVersion =>                  ("AppVersion", None, None, "SNES", CommandResponseType::Text),
+        Command::Name(name) =>                  ("Name", Some(vec![name.clone()]), None, "SNES", CommandResponseType::None),
+        Command::PutAddress(addrs, _) =>        ("PutAddress", Some(addrs.clone()), None, "SNES", CommandResponseType::None),
+        Command::GetAddress(addrs) =>           { let size = get_size(addrs)?; ("GetAddress", Some(addrs.clone()), None, "SNES", CommandResponseType::Binary(size)) },
+        Command::List(path) =>                  ("List", Some(vec![path.clone()]), None, "SNES", CommandResponseType::Text),
+        Command::GetFile(path) =>               ("GetFile", Some(vec![path.clone()]), None, "SNES", CommandResponseType::File),
+        Command::PutFile(args, _) =>            ("PutFile", Some(args.clone()), None, "SNES", CommandResponseType::None),
+        Command::Remove(path) =>                ("Remove", Some(vec![path.clone()]), None, "SNES", CommandResponseType::None),
+        Command::Rename(paths) =>               ("Rename", Some(paths.clone()), None, "SNES", CommandResponseType::None),
+        Command::MakeDir(path) =>               ("MakeDir", Some(vec![path.clone()]), None, "SNES", CommandResponseType::None),
+        Command::Boot(path) =>                  ("Boot", Some(vec![path.clone()]), None, "SNES", CommandResponseType::None),
+        Command::Menu =>                        ("Menu", None, None, "SNES", CommandResponseType::None),
+        Command::Reset =>                       ("Reset", None, None, "SNES", CommandResponseType::None),
+        _ => return Err(ConnectionError::Unsupported(format!("Attempted to use unsupported command: {:?}", &command).into()))
+    };
+
+    let ws = sock.ws.as_mut().ok_or_else(|| ConnectionError::Disconnected("Could not get websocket".into()))?;
+
+    let _ = ws.send(WsFrame::Text(
+        serde_json::to_string(
+            &SnesRequest {
+                Opcode: opcode.into(),
+                Space: space.into(),
+                Flags: flags,
+                Operands: operands
+            }
+        ).map_err(|e| ConnectionError::InvalidRequest(ErrorDetail::with_source("Could not serialize device command", e)))?
+    )).await?;
+
+    let _ = ws.flush().await?;
+
+    // Read the response if needed
+    let response = match response_type {
+        CommandResponseType::None => CommandResponse::Empty,
+        CommandResponseType::Text => {
+            let response = ws.next().await.ok_or_else(|| ConnectionError::Disconnected("Could not read response data".into()))?;
+            match response {
+                WsFrame::Text(t) => CommandResponse::Response(serde_json::from_str(&t).map_err(|e| ConnectionError::ProtocolViolation(ErrorDetail::with_source("Could not read command response", e)))?),
+                _ => return Err(ConnectionError::ProtocolViolation("Got binary response when expecting a text response".into()))
+            }
+        },
+        CommandResponseType::Binary(size) => {
+            log::debug!("usb2snes: Reading binary data with size: {:X}", size);
+            let mut resp_data: Vec<u8> = Vec::new();
+            while resp_data.len() < size {
+                let response = ws.next().await.ok_or_else(|| ConnectionError::Disconnected("Error while reading binary response".into()))?;    
+                match response {
+                    WsFrame::Binary(mut d) => resp_data.append(&mut d),
+                    _ => return Err(ConnectionError::ProtocolViolation("Got text data when expecting binary data".into()))
+                }
+            }
+            CommandResponse::Data(resp_data)
+        },
+        CommandResponseType::File => {
+            // File transfers first respond with the file size as text, followed by the binary data
+            let size = match ws.next().await.ok_or_else(|| ConnectionError::Disconnected("Could not read response data".into()))? {
+                WsFrame::Text(t) => {
+                    let response: SnesResponse = serde_json::from_str(&t).map_err(|e| ConnectionError::ProtocolViolation(ErrorDetail::with_source("Could not read command response", e)))?;
+                    let size = response.Results.first().ok_or_else(|| ConnectionError::ProtocolViolation("File size missing from GetFile response".into()))?;
+                    usize::from_str_radix(size, 16).map_err(|e| ConnectionError::ProtocolViolation(ErrorDetail::with_source("Could not parse file size", e)))?
+                },
+                _ => return Err(ConnectionError::ProtocolViolation("Got binary response when expecting a text response".into()))
+            };
+
+            log::debug!("usb2snes: Reading file data with size: {:X}", size);
+            let mut resp_data: Vec<u8> = Vec::with_capacity(size);
+            while resp_data.len() < size {
+                let response = ws.next().await.ok_or_else(|| ConnectionError::Disconnected("Error while reading binary response".into()))?;
+                match response {
+                    WsFrame::Binary(mut d) => resp_data.append(&mut d),
+                    _ => return Err(ConnectionError::ProtocolViolation("Got text data when expecting binary data".into()))
+                }
+            }
+            CommandResponse::Data(resp_data)
+        }
+    };
+
+    // Send any binary data that might be included in a command
+    match command {
+        Command::PutAddress(_, d) => {
+            ws.send(WsFrame::Binary(d)).await?;
+            let _ = ws.flush().await?;           
+        },
+        Command::PutFile(_, d) => {
+            for (i, chunk) in d.chunks(PUT_FILE_CHUNK_SIZE).enumerate() {
+                ws.send(WsFrame::Binary(chunk.to_vec())).await?;
+                if let Some(progress) = progress {
+                    if (i + 1) % PROGRESS_INTERVAL == 0 {
+                        progress((i + 1) * PUT_FILE_CHUNK_SIZE, d.len());
+                    }
+                }
+            }
+            ws.flush().await?;
+            if let Some(progress) = progress {
+                progress(d.len(), d.len());
+            }
+        },
+        _ => ()
+    };
+
+    Ok(response)
 }
 
 pub struct Usb2SnesConnection {
     uri: String,
-    socket: Arc<Mutex<Socket>>,
+    // A websocket can only be attached to one device, so there's one per device, each with its own lock so
+    // devices can be talked to in parallel. Commands that aren't for a device use the socket stored under "".
+    sockets: std::sync::Mutex<HashMap<String, Slot>>
 }
 
 impl Usb2SnesConnection {
     pub fn new(uri: &str) -> Self {
         Self {
             uri: uri.to_string(),
-            socket: Arc::new(Mutex::new(Socket { ws: None, wsio: None, state: ConnectionState::Disconnected, device: String::new() })),
+            sockets: std::sync::Mutex::new(HashMap::new())
         }
     }
 
-    // Handle attach as a special case so we don't end up with attach calling itself over and over
-    async fn attach(&self, device: &str) -> Result<bool, ConnectionError> {
-        let sock_l = self.socket.clone();
-        let mut sock = sock_l.lock().await;
-        let wsio = sock.wsio.as_mut().ok_or(ConnectionError("Could not get websocket".into()))?;
-
-        let _ = wsio.send(WsMessage::Text(
-            serde_json::to_string(&SnesRequest { Opcode: "Attach".into(), Space: "SNES".into(), Flags: None, Operands: Some(vec![device.to_string()]) })
-            .map_err(|_| ConnectionError("Could not send attach request".into()))?
-        )).await.map_err(|_| ConnectionError("Could not send attach request".into()))?;
-        
-        sock.state = ConnectionState::Attached;
-        sock.device = device.to_string();
-        log::debug!("usb2snes: Attached to device: {}", &sock.device);
-        Ok(true)
+    fn slot(&self, device: Option<&str>) -> Slot {
+        self.sockets.lock().unwrap().entry(device.unwrap_or_default().to_string()).or_insert_with(|| Arc::new(Mutex::new(Socket::new()))).clone()
     }
-    
-    async fn update_connection_state(&self, device: Option<&str>) -> Result<bool, ConnectionError> {
-        let conn = Box::new(self as &dyn Connection);
-        let sock = self.socket.clone();
-        
-        /* Capture socket state in an inner scope so the lock is not held */
-        let (state, current_device) = {
-            let mut sock = sock.lock().await;
-
-            /* Check if we're still connected */
-            if let Some(ws) = sock.ws.as_ref() {
-                if ws.ready_state() != WsState::Open {
-                    sock.ws = None;
-                    sock.wsio = None;
-                    sock.state = ConnectionState::Disconnected;
-                    sock.device = String::new();
-                    log::debug!("usb2snes: WebSocket disconnected, retrying connection");
-                }
-            }
-            
-            (sock.state.clone(), sock.device.clone())
-        };        
-        
-        /* Handle required connection state updates */
-        match state {
-            ConnectionState::Disconnected => Ok(conn.connect().await?),
-            ConnectionState::Connected => 
-                match device {
-                    Some(d) => Ok(self.attach(d).await?),
-                    _ => Ok(true)
-            },
-            ConnectionState::Attached => {
-                match device {
-                    Some(d) if d != current_device => Ok(self.attach(d).await?),
-                    _ => Ok(true)
-                }
-            }
+
+    // Get a socket ready for commands, connecting, identifying the server and attaching to the device as needed
+    async fn open(&self, sock: &mut Socket, device: Option<&str>) -> Result<(), ConnectionError> {
+        if sock.ws.as_ref().is_some_and(|ws| !ws.is_open()) {
+            sock.reset();
+            log::debug!("usb2snes: WebSocket disconnected, retrying connection");
+        }
+
+        if let ConnectionState::Disconnected = sock.state {
+            sock.ws = Some(WebSocket::connect(&self.uri).await?);
+            sock.state = ConnectionState::Connected;
+            log::debug!("usb2snes: Connected to {}", &self.uri);
+            let server = negotiate(sock).await?;
+            sock.server = Some(server);
+        }
+
+        match device {
+            Some(d) if matches!(sock.state, ConnectionState::Connected) => attach(sock, d).await,
+            _ => Ok(())
         }
     }
 
-    fn get_size(&self, addrs: &[String]) -> Result<usize, ConnectionError>  {
-        addrs
-        .iter()
-        .skip(1)
-        .step_by(2)
-        .fold(Ok(0), |acc, size| {
-            match acc {
-                Ok(acc) => Ok(usize::from_str_radix(size, 16).map_err(|_| ConnectionError("Could not get data size for request".into()))? + acc),
-                Err(e) => Err(e)
-            }
-        })
-    }
-
-    async fn send_command(&self, device: Option<&str>, command: Command) -> Result<CommandResponse, ConnectionError> {        
-        self.update_connection_state(device).await?;        
-        log::debug!("usb2snes: Sending command: {:?}", &command);
-        let sock_l = self.socket.clone();
-        let mut sock = sock_l.lock().await;
-        let wsio = sock.wsio.as_mut().ok_or(ConnectionError("Could not get websocket".into()))?;
-        
-        let (opcode, operands, flags, space, response_type) = match &command {
-            Command::DeviceList =>                  ("DeviceList", None, None, "SNES", CommandResponseType::Text),
-            Command::Info =>                        ("Info", None, None, "SNES", CommandResponseType::Text),
-            Command::AppVersion =>                  ("AppVersion", None, None, "SNES", CommandResponseType::Text),
-            Command::PutAddress(addrs, _) =>        ("PutAddress", Some(addrs), None, "SNES", CommandResponseType::None),
-            Command::GetAddress(addrs) =>           { let size = self.get_size(&addrs)?; ("GetAddress", Some(addrs), None, "SNES", CommandResponseType::Binary(size)) },
-            _ => return Err(ConnectionError(format!("Attempted to use unsupported command: {:?}", &command).into()))
-        };
+    // What the server supports, connecting first if needed
+    pub async fn server_info(&self) -> Result<ServerInfo, ConnectionError> {
+        self.server(None).await
+    }
 
-        let _ = wsio.send(WsMessage::Text(
-            serde_json::to_string(
-                &SnesRequest {
-                    Opcode: opcode.into(),
-                    Space: space.into(),
-                    Flags: flags,
-                    Operands: operands.cloned()
-                }
-            ).map_err(|_| ConnectionError("Could not send device command".into()))?
-        )).await.map_err(|_| ConnectionError("Could not send device command".into()))?;
+    async fn server(&self, device: Option<&str>) -> Result<ServerInfo, ConnectionError> {
+        let slot = self.slot(device);
+        let mut sock = slot.lock().await;
+        if let Err(e) = self.open(&mut sock, device).await {
+            sock.reset();
+            return Err(e);
+        }
+        sock.server.clone().ok_or_else(|| ConnectionError::Disconnected("Connection lost before the server was identified".into()))
+    }
 
-        let _ = wsio.flush().await.map_err(|_| ConnectionError("Could not flush data".into()))?;
+    // The VGET/VPUT limits of the server
+    async fn limits(&self, device: &str) -> Result<Limits, ConnectionError> {
+        Ok(self.server(Some(device)).await?.limits)
+    }
 
-        // Read the response if needed
-        let response = match response_type {
-            CommandResponseType::None => CommandResponse::Empty,
-            CommandResponseType::Text => {
-                let response = wsio.next().await.ok_or(ConnectionError("Could not read response data".into()))?;
-                match response {
-                    WsMessage::Text(t) => CommandResponse::Response(serde_json::from_str(&t).map_err(|_| ConnectionError("Could not read command response".into()))?),
-                    _ => return Err(ConnectionError("Got binary response when expecting a text response".into()))
-                }
-            },
-            CommandResponseType::Binary(size) => {
-                log::debug!("usb2snes: Reading binary data with size: {:X}", size);
-                let mut resp_data: Vec<u8> = Vec::new();
-                while resp_data.len() < size {
-                    let response = wsio.next().await.ok_or(ConnectionError("Error while reading binary response".into()))?;    
-                    match response {
-                        WsMessage::Binary(mut d) => resp_data.append(&mut d),
-                        _ => return Err(ConnectionError("Got text data when expecting binary data".into()))
-                    }
-                }
-                CommandResponse::Data(resp_data)
-            }
-        };
+    // Filesystem commands have no response, so follow them up with an Info request on the same device.
+    // The server handles device commands in order, so once that returns the previous command has completed.
+    async fn sync_device(&self, device: &str) -> Result<(), ConnectionError> {
+        match self.send_command(Some(device), Command::Info).await? {
+            CommandResponse::Response(_) => Ok(()),
+            _ => Err(ConnectionError::ProtocolViolation("Unexpected Info response".into()))
+        }
+    }
 
-        // Send any binary data that might be included in a command
-        match command {
-            Command::PutAddress(_, d) | Command::PutFile(_, d) => {
-                wsio.send(WsMessage::Binary(d)).await.map_err(|_| ConnectionError("Could not send binary data".into()))?;
-                let _ = wsio.flush().await.map_err(|_| ConnectionError("Could not flush data".into()))?;           
-            },
-            _ => ()
-        };
+    // PutAddress has no response, so check that the socket is still open afterwards. If it isn't, the caller
+    // gets to know that the write most likely did not go through.
+    async fn check_open(&self, device: &str) -> Result<(), ConnectionError> {
+        let slot = self.slot(Some(device));
+        let mut sock = slot.lock().await;
+        if sock.ws.as_ref().is_some_and(|ws| ws.is_open()) {
+            return Ok(());
+        }
+        sock.reset();
+        Err(ConnectionError::Disconnected("Connection was lost while writing".into()))
+    }
+
+    async fn device_info(&self, device: &str) -> Result<Device, ConnectionError> {
+        match self.send_command(Some(device), Command::Info).await? {
+            CommandResponse::Response(i) => Ok(parse_device_info(device, &i.Results)),
+            _ => Err(ConnectionError::ProtocolViolation("Unexpected Info response".into()))
+        }
+    }
+
+    async fn send_command(&self, device: Option<&str>, command: Command) -> Result<CommandResponse, ConnectionError> {
+        self.send_command_with_progress(device, command, None).await
+    }
 
-        Ok(response)
+    // Send a command over the socket for the device
+    async fn send_command_with_progress(&self, device: Option<&str>, command: Command, progress: Option<&dyn Fn(usize, usize)>) -> Result<CommandResponse, ConnectionError> {
+        let slot = self.slot(device);
+        let mut sock = slot.lock().await;
+        if let Err(e) = self.open(&mut sock, device).await {
+            sock.reset();
+            return Err(e);
+        }
+
+        let result = exchange(&mut sock, command, progress).await;
+
+        // A command that failed half way leaves the socket out of step, refused commands don't
+        if let Err(ConnectionError::Disconnected(_)) | Err(ConnectionError::ProtocolViolation(_)) = &result {
+            sock.reset();
+        }
+        result
     }
 }
 
@@ -234,128 +444,500 @@ impl Usb2SnesConnection {
 impl Connection for Usb2SnesConnection {
     
     async fn connect(&self) -> Result<bool, ConnectionError> {
-        let (ws, wsio) = WsMeta::connect(&self.uri, None).await.map_err(|_| ConnectionError("Could not connect to websocket".into()))?;
-        {
-            let sock_l = self.socket.clone();
-            let mut sock = sock_l.lock().await;
-            sock.ws = Some(ws);
-            sock.wsio = Some(wsio);
-            sock.state = ConnectionState::Connected;
-            log::debug!("usb2snes: Connected to {}", &self.uri);
-        }
-
+        self.slot(None).lock().await.reset();
+        self.server(None).await?;
         Ok(true)
     }
 
     async fn disconnect(&self) -> Result<bool, ConnectionError> {
-        let sock_l = self.socket.clone();
-        let mut sock = sock_l.lock().await;
-        if let Some(ws) = sock.ws.as_ref() {
-            ws.close().await.map_err(|_| ConnectionError("Could not close websocket".into()))?;
-            sock.ws = None;
-            sock.wsio = None;
-            sock.state = ConnectionState::Disconnected;
-            sock.device = String::new();
+        let slots: Vec<Slot> = self.sockets.lock().unwrap().drain().map(|(_, slot)| slot).collect();
+        for slot in slots {
+            let mut sock = slot.lock().await;
+            // Sockets that already died can't be closed, they're dropped all the same
+            if let Some(ws) = sock.ws.as_mut() {
+                let _ = ws.close().await;
+            }
+            sock.reset();
         }
         Ok(true)
     }
 
-    // Get the device list from the server
-    // For each device also attach and issue an info request
+    // Drop the sockets without closing them, since a stalled server won't answer the close either.
+    // The next command reconnects and attaches again.
+    async fn recover(&self) {
+        self.sockets.lock().unwrap().clear();
+        log::debug!("usb2snes: Dropped connections after an abandoned command");
+    }
+
+    // Get the device list from the server and ask every device for its info over its own socket.
+    // Sockets for devices that are gone are dropped.
     async fn list_devices(&self) -> Result<Vec<Device>, ConnectionError>
     {
-        match self.send_command(None, Command::DeviceList).await? {
-            CommandResponse::Response(r) => {
-                let mut devices = Vec::new();
-                for device in &r.Results {
-                    self.attach(device).await?;
-                    devices.push(match self.send_command(Some(device), Command::Info).await? {
-                        CommandResponse::Response(i) => {
-                            Device {
-                                name: device.to_string(),
-                                uri: device.to_string(),
-                                info: Some(i.Results)
-                            }
-                        },
-                        _ => return Err(ConnectionError("Unexpected Info response".into()))
-                    });                    
-                }
+        let names = match self.send_command(None, Command::DeviceList).await? {
+            CommandResponse::Response(r) => r.Results,
+            _ => return Err(ConnectionError::ProtocolViolation("Unexpected DeviceList response".into()))
+        };
 
-                Ok(devices)
-            },
-            _ => Err(ConnectionError("Unexpected DeviceList response".into()))
-        }
+        self.sockets.lock().unwrap().retain(|device, _| device.is_empty() || names.contains(device));
+        futures::future::join_all(names.iter().map(|d| self.device_info(d))).await.into_iter().collect()
     }
 
     async fn read_single(&self, device: &str, address: u32, size: u32) -> Result<Vec<u8>, ConnectionError> {
         Ok(self.read_multi(device, &[address, size]).await?.remove(0))
     }
     
-    // Issue a vectored read, translated to a VGET if possible
-    // The usb2snes protocol doesn't officially support VGET requests larger than 255 bytes, so if the sum
-    // of bytes requested is larger than 255 bytes, split each VGET request into single GET requests.
+    // Issue a vectored read, planned into as few VGET requests as the server limits allow.
+    // Ranges that don't fit in a VGET are read with a plain GET of their own.
     async fn read_multi(&self, device: &str, address_info: &[u32]) -> Result<Vec<Vec<u8>>, ConnectionError> 
     {
-        match address_info.iter().skip(1).step_by(2).map(|s| *s as usize).sum::<usize>() {
-            req_size if address_info.len() > 2 && address_info.len() <= 16 && req_size < 256 => {
-                match self.send_command(Some(device), Command::GetAddress(address_info.iter().map(|a| format!("{:X}", a)).collect())).await? {
-                    CommandResponse::Data(response) => {
-                        let mut position: usize = 0;
-                        let mut data = Vec::new();
-                        
-                        for size in address_info.iter().skip(1).step_by(2).map(|s| *s as usize) {
-                            data.push(response[position..position+size].to_vec());
-                            position += size;
-                        }
-        
-                        Ok(data)
-                    },
-                    _ => Err(ConnectionError("Unexpected ReadMemory response".into()))
-                }
-            },
-            _ => {
-                let mut data = Vec::new();
-                for addr_chunk in address_info.chunks(2) {
-                    match self.send_command(Some(device), Command::GetAddress(addr_chunk.iter().map(|a| format!("{:X}", a)).collect())).await? {
-                        CommandResponse::Data(response) => data.push(response),
-                        _ => return Err(ConnectionError("Unexpected ReadMemory response".into()))
-                    }
-                };
-                Ok(data)
+        let plan = ReadPlan::new(address_info, &self.limits(device).await?);
+        let mut responses = Vec::new();
+        for batch in plan.batches() {
+            match self.send_command(Some(device), Command::GetAddress(batch.iter().map(|a| format!("{:X}", a)).collect())).await? {
+                CommandResponse::Data(response) => responses.push(response),
+                _ => return Err(ConnectionError::ProtocolViolation("Unexpected ReadMemory response".into()))
             }
         }
+
+        plan.assemble_flat(responses)
     }
    
     async fn write_single(&self, device: &str, address: u32, data: &[u8])-> Result<(), ConnectionError> {
         Ok(self.write_multi(device, &[address], &[data.to_vec()]).await?)
     }
     
-    // Issue a vectored write, translated to a VPUT if possible
-    // The usb2snes protocol doesn't officially support VPUT requests larger than 255 bytes, so if the sum
-    // of bytes to send is larger than 255 bytes, split each VPUT request into single PUT requests.
+    // Issue a vectored write, planned into as few VPUT requests as the server limits allow.
+    // Ranges that don't fit in a VPUT are written with a plain PUT of their own.
     async fn write_multi(&self, device: &str, addresses: &[u32], data: &[Vec<u8>]) -> Result<(), ConnectionError> {
-        match data.iter().map(|d| d.len()).sum::<usize>() {
-            req_size if addresses.len() >= 2 && addresses.len() <= 8 && req_size < 256 => {
-                let address_info = addresses.iter().zip(data.iter().map(|d| d.len() as u32)).flat_map(|(a, s)| vec![format!("{:X}", a), format!("{:X}", s)]).collect();
-                match self.send_command(Some(device), Command::PutAddress(address_info, data.iter().flat_map(|d| d.clone()).collect::<Vec<u8>>())).await? {
-                    CommandResponse::Empty => (),
-                    _ => return Err(ConnectionError("Unexpected PutAddress response".into()))
-                }
+        let plan = WritePlan::new(addresses, data, &self.limits(device).await?);
+        for (addresses, data) in plan.batches() {
+            let address_info = addresses.iter().zip(data.iter().map(|d| d.len() as u32)).flat_map(|(a, s)| vec![format!("{:X}", a), format!("{:X}", s)]).collect();
+            match self.send_command(Some(device), Command::PutAddress(address_info, data.concat())).await? {
+                CommandResponse::Empty => (),
+                _ => return Err(ConnectionError::ProtocolViolation("Unexpected PutAddress response".into()))
+            }
+        }
+
+        self.check_open(device).await
+    }
+
+    fn filesystem(&self) -> Option<&dyn FilesystemConnection> {
+        Some(self)
+    }
+
+    fn control(&self) -> Option<&dyn ControlConnection> {
+        Some(self)
+    }
+}
+
+#[async_trait(?Send)]
+impl FilesystemConnection for Usb2SnesConnection {
+    // List results come back as a flat list of (type, name) pairs
+    async fn list_files(&self, device: &str, path: &str) -> Result<Vec<FileEntry>, ConnectionError> {
+        match self.send_command(Some(device), Command::List(path.to_string())).await? {
+            CommandResponse::Response(r) => {
+                Ok(r.Results.chunks(2).filter_map(|entry| match entry {
+                    [file_type, name] if name != "." && name != ".." => Some(FileEntry {
+                        name: name.to_string(),
+                        file_type: if file_type == "0" { FileType::Directory } else { FileType::File }
+                    }),
+                    _ => None
+                }).collect())
             },
-            _ => {
-                for (address, data) in addresses.iter().zip(data.iter()) {
-                    match self.send_command(Some(device), Command::PutAddress(vec![format!("{:X}", address), format!("{:X}", data.len())], data.to_vec())).await? {
-                        CommandResponse::Empty => (),
-                        _ => return Err(ConnectionError("Unexpected PutAddress response".into()))
-                    }
+            _ => Err(ConnectionError::ProtocolViolation("Unexpected List response".into()))
+        }
+    }
+
+    async fn get_file(&self, device: &str, path: &str) -> Result<Vec<u8>, ConnectionError> {
+        match self.send_command(Some(device), Command::GetFile(path.to_string())).await? {
+            CommandResponse::Data(data) => Ok(data),
+            _ => Err(ConnectionError::ProtocolViolation("Unexpected GetFile response".into()))
+        }
+    }
+
+    async fn put_file(&self, device: &str, path: &str, data: &[u8]) -> Result<(), ConnectionError> {
+        self.send_command(Some(device), Command::PutFile(vec![path.to_string(), format!("{:X}", data.len())], data.to_vec())).await?;
+        self.sync_device(device).await
+    }
+
+    async fn remove_file(&self, device: &str, path: &str) -> Result<(), ConnectionError> {
+        self.send_command(Some(device), Command::Remove(path.to_string())).await?;
+        self.sync_device(device).await
+    }
+
+    async fn rename_file(&self, device: &str, path: &str, new_path: &str) -> Result<(), ConnectionError> {
+        self.send_command(Some(device), Command::Rename(vec![path.to_string(), new_path.to_string()])).await?;
+        self.sync_device(device).await
+    }
+
+    async fn make_directory(&self, device: &str, path: &str) -> Result<(), ConnectionError> {
+        self.send_command(Some(device), Command::MakeDir(path.to_string())).await?;
+        self.sync_device(device).await
+    }
+
+    async fn put_file_with_progress(&self, device: &str, path: &str, data: &[u8], progress: &dyn Fn(usize, usize)) -> Result<(), ConnectionError> {
+        self.send_command_with_progress(Some(device), Command::PutFile(vec![path.to_string(), format!("{:X}", data.len())], data.to_vec()), Some(progress)).await?;
+        self.sync_device(device).await
+    }
+}
+
+// The device is busy resetting after these commands, so unlike the filesystem commands they're not followed up
+// with an Info request. Any connection problem will show up on the next command instead.
+#[async_trait(?Send)]
+impl ControlConnection for Usb2SnesConnection {
+    async fn reset(&self, device: &str) -> Result<(), ConnectionError> {
+        self.send_command(Some(device), Command::Reset).await?;
+        Ok(())
+    }
+
+    async fn reset_to_menu(&self, device: &str) -> Result<(), ConnectionError> {
+        self.send_command(Some(device), Command::Menu).await?;
+        Ok(())
+    }
+
+    async fn boot(&self, device: &str, path: &str) -> Result<(), ConnectionError> {
+        self.send_command(Some(device), Command::Boot(path.to_string())).await?;
+        Ok(())
+    }
+
+    async fn pause_emulation(&self, _device: &str, _paused: bool) -> Result<bool, ConnectionError> {
+        Err(ConnectionError::Unsupported("usb2snes does not support pausing emulation".into()))
+    }
+
+    async fn toggle_pause_emulation(&self, _device: &str) -> Result<(), ConnectionError> {
+        Err(ConnectionError::Unsupported("usb2snes does not support pausing emulation".into()))
+    }
+}
+
+#[cfg(all(test, feature = "native"))]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream as StdTcpStream};
+    use std::sync::Mutex as StdMutex;
+    use tokio_tungstenite::tungstenite::{self, Message};
+
+    // What unwritten memory on the stand-in reads as, different for every byte of a bank
+    fn pattern(address: u32) -> u8 {
+        (address ^ (address >> 8) ^ (address >> 16)) as u8
+    }
+
+    // A command as the stand-in got it, along with the connection it came in on and the device that was attached
+    #[derive(Debug, Clone)]
+    struct Received {
+        connection: usize,
+        device: String,
+        opcode: String,
+        operands: Vec<String>
+    }
+
+    #[derive(Default)]
+    struct State {
+        version: String,
+        devices: Vec<String>,
+        memory: HashMap<(String, u32), u8>,
+        files: HashMap<String, Vec<u8>>,
+        commands: Vec<Received>,
+        // Sizes of the binary messages every PutFile came in as
+        chunks: Vec<usize>,
+        // Every accepted connection, so they can be cut off
+        streams: Vec<StdTcpStream>
+    }
+
+    // Stands in for a usb2snes server with a number of devices attached
+    struct FakeServer {
+        address: String,
+        state: Arc<StdMutex<State>>
+    }
+
+    impl FakeServer {
+        fn start(version: &str, devices: &[&str]) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let address = listener.local_addr().unwrap().to_string();
+            let state = Arc::new(StdMutex::new(State {
+                version: version.to_string(),
+                devices: devices.iter().map(|d| d.to_string()).collect(),
+                ..Default::default()
+            }));
+
+            let server = state.clone();
+            std::thread::spawn(move || {
+                for (connection, stream) in listener.incoming().flatten().enumerate() {
+                    server.lock().unwrap().streams.push(stream.try_clone().unwrap());
+                    let state = server.clone();
+                    std::thread::spawn(move || Self::serve(stream, connection, state));
+                }
+            });
+
+            Self { address, state }
+        }
+
+        // Answer commands until the connection closes, dropping it on commands that aren't known like the real servers do
+        fn serve(stream: StdTcpStream, connection: usize, state: Arc<StdMutex<State>>) {
+            let mut ws = match tungstenite::accept(stream) {
+                Ok(ws) => ws,
+                Err(_) => return
+            };
+
+            let mut device = String::new();
+            while let Ok(Message::Text(text)) = ws.read_message() {
+                let request: serde_json::Value = serde_json::from_str(&text).unwrap();
+                let opcode = request["Opcode"].as_str().unwrap().to_string();
+                let operands: Vec<String> = request["Operands"].as_array()
+                    .map(|o| o.iter().map(|v| v.as_str().unwrap().to_string()).collect())
+                    .unwrap_or_default();
+                state.lock().unwrap().commands.push(Received { connection, device: device.clone(), opcode: opcode.clone(), operands: operands.clone() });
+
+                let hex = |s: &String| u32::from_str_radix(s, 16).unwrap();
+                let ranges: Vec<(u32, u32)> = operands.chunks(2).filter_map(|r| match r {
+                    [address, size] => Some((u32::from_str_radix(address, 16).ok()?, u32::from_str_radix(size, 16).ok()?)),
+                    _ => None
+                }).collect();
+                let result = match opcode.as_str() {
+                    "AppVersion" => reply(&mut ws, vec![state.lock().unwrap().version.clone()]),
+                    "DeviceList" => reply(&mut ws, state.lock().unwrap().devices.clone()),
+                    "Info" => reply(&mut ws, vec!["1.11.0".into(), "FakeSNES".into(), "No Info".into()]),
+                    "Attach" => {
+                        device = operands[0].clone();
+                        Ok(())
+                    },
+                    "GetAddress" => {
+                        let state = state.lock().unwrap();
+                        let data: Vec<u8> = ranges.iter()
+                            .flat_map(|(address, size)| (*address..address + size).map(|a| state.memory.get(&(device.clone(), a)).copied().unwrap_or_else(|| pattern(a))))
+                            .collect();
+                        data.chunks(64).try_for_each(|c| send(&mut ws, Message::Binary(c.to_vec())))
+                    },
+                    "PutAddress" => {
+                        let data = receive(&mut ws, ranges.iter().map(|(_, size)| *size as usize).sum()).concat();
+                        let mut state = state.lock().unwrap();
+                        let mut data = data.into_iter();
+                        for (address, size) in ranges {
+                            for a in address..address + size {
+                                state.memory.insert((device.clone(), a), data.next().unwrap());
+                            }
+                        }
+                        Ok(())
+                    },
+                    "PutFile" => {
+                        let chunks = receive(&mut ws, hex(&operands[1]) as usize);
+                        let mut state = state.lock().unwrap();
+                        state.chunks = chunks.iter().map(|c| c.len()).collect();
+                        state.files.insert(operands[0].clone(), chunks.concat());
+                        Ok(())
+                    },
+                    "GetFile" => {
+                        let data = state.lock().unwrap().files.get(&operands[0]).cloned().unwrap_or_default();
+                        reply(&mut ws, vec![format!("{:X}", data.len())])
+                            .and_then(|_| data.chunks(1024).try_for_each(|c| send(&mut ws, Message::Binary(c.to_vec()))))
+                    },
+                    "List" => {
+                        let mut results = vec!["0".to_string(), ".".to_string()];
+                        let state = state.lock().unwrap();
+                        for name in state.files.keys().filter_map(|f| f.strip_prefix(&format!("{}/", operands[0]))) {
+                            results.extend(vec!["1".to_string(), name.to_string()]);
+                        }
+                        reply(&mut ws, results)
+                    },
+                    "Name" | "MakeDir" | "Remove" | "Rename" | "Boot" | "Menu" | "Reset" => Ok(()),
+                    _ => break
+                };
+
+                if result.is_err() {
+                    break;
                 }
             }
-        };
+        }
 
-        // Silly workaround for the WASM websocket library that can't detect if we're disconnected without reading.
-        // So let's send an AppVersion command to force the issue and detect if we're still connected.
-        // If we're disconnected it'll trigger an error so the caller can know that the transfer most likely did not go through.
-        let _ = self.send_command(None, Command::AppVersion).await?;
-        Ok(())
+        fn connection(&self) -> Usb2SnesConnection {
+            Usb2SnesConnection::new(&format!("ws://{}", self.address))
+        }
+
+        fn commands(&self, opcode: &str) -> Vec<Received> {
+            self.state.lock().unwrap().commands.iter().filter(|c| c.opcode == opcode).cloned().collect()
+        }
+
+        fn connections(&self) -> usize {
+            self.state.lock().unwrap().streams.len()
+        }
+
+        // Cut every connection off from the server side
+        fn hang_up(&self) {
+            for stream in self.state.lock().unwrap().streams.iter() {
+                let _ = stream.shutdown(std::net::Shutdown::Both);
+            }
+        }
+    }
+
+    // Only whether the message went out matters, the connection is dropped if it didn't
+    fn send(ws: &mut tungstenite::WebSocket<StdTcpStream>, message: Message) -> Result<(), ()> {
+        ws.write_message(message).map_err(|_| ())
+    }
+
+    fn reply(ws: &mut tungstenite::WebSocket<StdTcpStream>, results: Vec<String>) -> Result<(), ()> {
+        send(ws, Message::Text(serde_json::json!({ "Results": results }).to_string()))
+    }
+
+    // Read binary messages until the expected number of bytes has come in
+    fn receive(ws: &mut tungstenite::WebSocket<StdTcpStream>, size: usize) -> Vec<Vec<u8>> {
+        let mut chunks: Vec<Vec<u8>> = Vec::new();
+        while chunks.iter().map(|c| c.len()).sum::<usize>() < size {
+            match ws.read_message() {
+                Ok(Message::Binary(d)) => chunks.push(d),
+                _ => break
+            }
+        }
+        chunks
+    }
+
+    #[tokio::test]
+    async fn every_device_gets_its_own_socket() {
+        let server = FakeServer::start("QUsb2Snes-0.7.22", &["SD2SNES COM3", "SD2SNES COM4"]);
+        let connection = server.connection();
+
+        let devices = connection.list_devices().await.unwrap();
+        assert_eq!(devices.iter().map(|d| d.name.as_str()).collect::<Vec<_>>(), vec!["SD2SNES COM3", "SD2SNES COM4"]);
+        assert_eq!(devices[0].kind, "fakesnes");
+        connection.list_devices().await.unwrap();
+
+        // Device lists go over the socket that isn't attached, device info over the socket of that device
+        let lists = server.commands("DeviceList");
+        assert!(lists.iter().all(|c| c.connection == lists[0].connection && c.device.is_empty()));
+        let info = server.commands("Info");
+        assert_eq!(info.len(), 4);
+        assert!(info.iter().all(|c| c.connection != lists[0].connection && !c.device.is_empty()));
+        assert!(info.iter().filter(|c| c.device == "SD2SNES COM3").all(|c| c.connection != info.iter().find(|i| i.device == "SD2SNES COM4").unwrap().connection));
+
+        assert_eq!(server.connections(), 3);
+        assert_eq!(server.commands("Attach").len(), 2);
+        assert_eq!(server.commands("Name").len(), 3);
+        assert!(server.commands("Name").iter().all(|c| c.operands == vec![APP_NAME.to_string()]));
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn the_original_server_is_not_sent_a_name() {
+        let server = FakeServer::start("7", &["SD2SNES COM3"]);
+        let connection = server.connection();
+
+        connection.connect().await.unwrap();
+        assert_eq!(connection.server_info().await.unwrap().kind, ServerKind::Usb2Snes);
+        connection.read_single("SD2SNES COM3", 0xF5_0000, 4).await.unwrap();
+        assert!(server.commands("Name").is_empty());
+        assert_eq!(server.commands("AppVersion").len(), 2);
+    }
+
+    #[tokio::test]
+    async fn devices_are_attached_again_after_recovering() {
+        let server = FakeServer::start("QUsb2Snes-0.7.22", &["SD2SNES COM3"]);
+        let connection = server.connection();
+
+        connection.write_single("SD2SNES COM3", 0xF5_0010, &[1, 2, 3]).await.unwrap();
+        connection.recover().await;
+        assert_eq!(connection.read_single("SD2SNES COM3", 0xF5_0010, 3).await.unwrap(), vec![1, 2, 3]);
+
+        let attaches = server.commands("Attach");
+        assert_eq!(attaches.len(), 2);
+        assert_ne!(attaches[0].connection, attaches[1].connection);
+        assert!(attaches.iter().all(|c| c.operands == vec!["SD2SNES COM3".to_string()]));
+    }
+
+    #[tokio::test]
+    async fn reads_and_writes_stay_within_the_server_limits() {
+        let server = FakeServer::start("7", &["SD2SNES COM3"]);
+        let connection = server.connection();
+
+        let data: Vec<Vec<u8>> = (0..4u8).map(|i| vec![i; 100]).collect();
+        let addresses = [0xF5_0000, 0xF5_1000, 0xF5_2000, 0xF5_3000];
+        connection.write_multi("SD2SNES COM3", &addresses, &data).await.unwrap();
+        let read = connection.read_multi("SD2SNES COM3", &[0xF5_0000, 100, 0xF5_1000, 100, 0xF5_2000, 100, 0xF5_3000, 100]).await.unwrap();
+        assert_eq!(read, data);
+
+        for command in server.commands("GetAddress").iter().chain(server.commands("PutAddress").iter()) {
+            let total: u32 = command.operands.iter().skip(1).step_by(2).map(|s| u32::from_str_radix(s, 16).unwrap()).sum();
+            assert!(total <= Limits::USB2SNES.max_batch_size);
+        }
+        assert!(server.commands("AppVersion").len() == 1);
+    }
+
+    #[tokio::test]
+    async fn files_are_sent_in_chunks_and_synced() {
+        let server = FakeServer::start("QUsb2Snes-0.7.22", &["SD2SNES COM3"]);
+        let connection = server.connection();
+        let data: Vec<u8> = (0..2500u32).map(|i| i as u8).collect();
+
+        let reports = StdMutex::new(Vec::new());
+        connection.put_file_with_progress("SD2SNES COM3", "/roms/seed.sfc", &data, &|sent, total| reports.lock().unwrap().push((sent, total))).await.unwrap();
+        assert_eq!(server.state.lock().unwrap().chunks, vec![1024, 1024, 452]);
+        assert_eq!(reports.lock().unwrap().last(), Some(&(2500, 2500)));
+
+        // The upload is followed by an Info on the same socket, which only answers once the file is written
+        let commands = server.state.lock().unwrap().commands.clone();
+        let put = commands.iter().position(|c| c.opcode == "PutFile").unwrap();
+        assert_eq!(commands[put + 1].opcode, "Info");
+        assert_eq!(commands[put + 1].connection, commands[put].connection);
+
+        assert_eq!(connection.get_file("SD2SNES COM3", "/roms/seed.sfc").await.unwrap(), data);
+        let files = connection.list_files("SD2SNES COM3", "/roms").await.unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].name, "seed.sfc");
+        assert_eq!(files[0].file_type, FileType::File);
+    }
+
+    #[tokio::test]
+    async fn filesystem_commands_wait_for_the_device() {
+        let server = FakeServer::start("QUsb2Snes-0.7.22", &["SD2SNES COM3"]);
+        let connection = server.connection();
+
+        connection.make_directory("SD2SNES COM3", "/roms").await.unwrap();
+        connection.rename_file("SD2SNES COM3", "/a.sfc", "/b.sfc").await.unwrap();
+        connection.remove_file("SD2SNES COM3", "/b.sfc").await.unwrap();
+        let opcodes: Vec<String> = server.state.lock().unwrap().commands.iter().map(|c| c.opcode.clone()).filter(|o| o != "AppVersion" && o != "Name" && o != "Attach").collect();
+        assert_eq!(opcodes, vec!["MakeDir", "Info", "Rename", "Info", "Remove", "Info"]);
+    }
+
+    #[tokio::test]
+    async fn disconnect_drops_sockets_that_are_already_gone() {
+        let server = FakeServer::start("QUsb2Snes-0.7.22", &["SD2SNES COM3", "SD2SNES COM4"]);
+        let connection = server.connection();
+
+        connection.list_devices().await.unwrap();
+        server.hang_up();
+        assert!(connection.disconnect().await.unwrap());
+        assert!(connection.sockets.lock().unwrap().is_empty());
+
+        // The next command connects again
+        assert_eq!(connection.list_devices().await.unwrap().len(), 2);
+        assert_eq!(server.connections(), 6);
+    }
+
+    #[test]
+    fn qusb2snes_is_recognized() {
+        let server = ServerInfo::from_app_version("QUsb2Snes-0.7.22");
+        assert_eq!(server.kind, ServerKind::QUsb2Snes);
+        assert_eq!(server.version, "QUsb2Snes-0.7.22");
+        assert_eq!(server.limits.max_batch_size, QUSB2SNES_LIMITS.max_batch_size);
+        assert!(server.supports_name());
+    }
+
+    #[test]
+    fn sni_is_recognized() {
+        let server = ServerInfo::from_app_version("SNI-0.0.88");
+        assert_eq!(server.kind, ServerKind::Sni);
+        assert_eq!(server.limits.max_batch_size, Limits::USB2SNES.max_batch_size);
+        assert!(server.supports_name());
+    }
+
+    #[test]
+    fn a_bare_version_is_the_original_server() {
+        let server = ServerInfo::from_app_version("7");
+        assert_eq!(server.kind, ServerKind::Usb2Snes);
+        assert_eq!(server.limits.max_batch_size, Limits::USB2SNES.max_batch_size);
+        assert!(!server.supports_name());
+    }
+
+    #[test]
+    fn an_empty_version_is_the_original_server() {
+        let server = ServerInfo::from_app_version("");
+        assert_eq!(server.kind, ServerKind::Usb2Snes);
+        assert!(!server.supports_name());
+    }
+}
@@ -0,0 +1,176 @@
+// Reads that are only trusted once two of them in a row agree, and writes that are only trusted once they read back
+// the same. Games and bridges can hand back torn or corrupted data now and then, so every attempt is retried a
+// limited number of times with a growing pause in between, and gives up with VerificationFailed instead of spinning.
+
+use async_trait::async_trait;
+use std::time::Duration;
+
+use crate::protocols::metrics::Metrics;
+use crate::protocols::protocol::{Connection, ConnectionError};
+use crate::protocols::timeout::sleep;
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    // Number of times to try before giving up
+    pub attempts: u32,
+    // Pause after the first failed attempt, doubled after every one after that
+    pub backoff: Duration,
+    pub max_backoff: Duration
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self { attempts: 5, backoff: Duration::from_millis(10), max_backoff: Duration::from_millis(200) }
+    }
+}
+
+impl RetryPolicy {
+    fn delay(&self, attempt: u32) -> Duration {
+        self.backoff.checked_mul(1 << attempt.min(16)).unwrap_or(self.max_backoff).min(self.max_backoff)
+    }
+
+    // Run the attempt until it comes back with a value or runs out of tries, counting retries and failures in the metrics.
+    // Connection errors are passed on right away, only a None from the attempt is retried.
+    async fn run<T, F, Fut>(&self, what: &str, metrics: Option<&Metrics>, mut attempt: F) -> Result<T, ConnectionError>
+    where
+        F: FnMut() -> Fut,
+        Fut: std::future::Future<Output = Result<Option<T>, ConnectionError>>
+    {
+        for i in 0..self.attempts.max(1) {
+            if i > 0 {
+                log::debug!("verify: {} did not match, trying again ({}/{})", what, i + 1, self.attempts);
+                if let Some(metrics) = metrics {
+                    metrics.record_retry();
+                }
+                sleep(self.delay(i - 1)).await;
+            }
+
+            if let Some(value) = attempt().await? {
+                return Ok(value);
+            }
+        }
+
+        if let Some(metrics) = metrics {
+            metrics.record_verification_failure();
+        }
+        Err(ConnectionError::VerificationFailed(format!("{} did not match after {} attempts", what, self.attempts.max(1)).into()))
+    }
+}
+
+#[async_trait(?Send)]
+pub trait VerifiedConnection {
+    // Read until two reads in a row agree
+    async fn read_stable(&self, device: &str, address: u32, size: u32, policy: &RetryPolicy) -> Result<Vec<u8>, ConnectionError>;
+    async fn read_stable_multi(&self, device: &str, address_info: &[u32], policy: &RetryPolicy) -> Result<Vec<Vec<u8>>, ConnectionError>;
+
+    // Write until the data reads back as written
+    async fn write_verified(&self, device: &str, address: u32, data: &[u8], policy: &RetryPolicy) -> Result<(), ConnectionError>;
+    async fn write_verified_multi(&self, device: &str, addresses: &[u32], data: &[Vec<u8>], policy: &RetryPolicy) -> Result<(), ConnectionError>;
+}
+
+#[async_trait(?Send)]
+impl<C: Connection + ?Sized> VerifiedConnection for C {
+    async fn read_stable(&self, device: &str, address: u32, size: u32, policy: &RetryPolicy) -> Result<Vec<u8>, ConnectionError> {
+        Ok(self.read_stable_multi(device, &[address, size], policy).await?.remove(0))
+    }
+
+    async fn read_stable_multi(&self, device: &str, address_info: &[u32], policy: &RetryPolicy) -> Result<Vec<Vec<u8>>, ConnectionError> {
+        let what = format!("Read of {:06X?}", address_info);
+        policy.run(&what, self.metrics(), || async move {
+            let first = self.read_multi(device, address_info).await?;
+            let second = self.read_multi(device, address_info).await?;
+            Ok(if first == second { Some(second) } else { None })
+        }).await
+    }
+
+    async fn write_verified(&self, device: &str, address: u32, data: &[u8], policy: &RetryPolicy) -> Result<(), ConnectionError> {
+        self.write_verified_multi(device, &[address], &[data.to_vec()], policy).await
+    }
+
+    async fn write_verified_multi(&self, device: &str, addresses: &[u32], data: &[Vec<u8>], policy: &RetryPolicy) -> Result<(), ConnectionError> {
+        let what = format!("Write to {:06X?}", addresses);
+        let address_info: Vec<u32> = addresses.iter().zip(data.iter()).flat_map(|(a, d)| vec![*a, d.len() as u32]).collect();
+        let address_info = &address_info;
+        policy.run(&what, self.metrics(), || async move {
+            self.write_multi(device, addresses, data).await?;
+            let written = self.read_multi(device, address_info).await?;
+            Ok(if written.as_slice() == data { Some(()) } else { None })
+        }).await
+    }
+}
+
+#[cfg(all(test, feature = "native"))]
+mod tests {
+    use super::*;
+    use crate::protocols::metrics::MetricsConnection;
+    use crate::protocols::mock::{Fault, MockConnection};
+
+    const POLICY: RetryPolicy = RetryPolicy { attempts: 3, backoff: Duration::from_millis(1), max_backoff: Duration::from_millis(4) };
+
+    fn connection() -> (MockConnection, MetricsConnection<MockConnection>) {
+        let mock = MockConnection::new();
+        mock.poke("mock", 0xF5_0000, &[1, 2, 3, 4]).unwrap();
+        (mock.clone(), MetricsConnection::new(mock))
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_limit() {
+        assert_eq!(POLICY.delay(0), Duration::from_millis(1));
+        assert_eq!(POLICY.delay(1), Duration::from_millis(2));
+        assert_eq!(POLICY.delay(2), Duration::from_millis(4));
+        assert_eq!(POLICY.delay(3), Duration::from_millis(4));
+        assert_eq!(POLICY.delay(u32::MAX), Duration::from_millis(4));
+    }
+
+    #[tokio::test]
+    async fn reads_settle_after_a_torn_read() {
+        let (mock, connection) = connection();
+        mock.inject_fault(Fault::TornRead);
+
+        assert_eq!(connection.read_stable("mock", 0xF5_0000, 4, &POLICY).await.unwrap(), vec![1, 2, 3, 4]);
+        assert_eq!(mock.operations(), 4);
+        let metrics = connection.metrics().unwrap().snapshot();
+        assert_eq!(metrics.retries, 1);
+        assert_eq!(metrics.verification_failures, 0);
+    }
+
+    #[tokio::test]
+    async fn writes_settle_once_they_read_back() {
+        let (mock, connection) = connection();
+        mock.inject_fault(Fault::DelayedWrite { operations: 5 });
+
+        connection.write_verified_multi("mock", &[0xF5_0000, 0xF5_0010], &[vec![9, 9], vec![8]], &POLICY).await.unwrap();
+        assert_eq!(mock.peek("mock", 0xF5_0000, 2).unwrap(), vec![9, 9]);
+        assert_eq!(mock.peek("mock", 0xF5_0010, 1).unwrap(), vec![8]);
+        assert_eq!(connection.metrics().unwrap().snapshot().retries, 1);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_the_last_attempt() {
+        let (mock, connection) = connection();
+        for _ in 0..POLICY.attempts {
+            mock.inject_fault(Fault::TornRead);
+        }
+
+        let result = connection.write_verified("mock", 0xF5_0000, &[5, 6, 7, 8], &POLICY).await;
+        assert!(matches!(result, Err(ConnectionError::VerificationFailed(_))));
+        // Every attempt is a write and a read back
+        assert_eq!(mock.operations(), 2 * POLICY.attempts as usize);
+        let metrics = connection.metrics().unwrap().snapshot();
+        assert_eq!(metrics.retries, POLICY.attempts as u64 - 1);
+        assert_eq!(metrics.verification_failures, 1);
+    }
+
+    #[tokio::test]
+    async fn connection_errors_are_not_retried() {
+        let (mock, connection) = connection();
+        mock.inject_fault(Fault::Disconnect { after: 0 });
+
+        let result = connection.read_stable_multi("mock", &[0xF5_0000, 4], &POLICY).await;
+        assert!(matches!(result, Err(ConnectionError::Disconnected(_))));
+        assert_eq!(mock.operations(), 1);
+        let metrics = connection.metrics().unwrap().snapshot();
+        assert_eq!(metrics.retries, 0);
+        assert_eq!(metrics.verification_failures, 0);
+    }
+}
@@ -0,0 +1,320 @@
+// Memory watches, for callers that keep polling the same memory to see when it changes.
+//
+// Every watch registers a set of ranges on a device and how often they should be checked. A single polling
+// loop serves all watches: on every tick the ranges of the watches that are due are read from each device
+// with one read_multi, so consumers share the requests to the device, and every watch is only notified when
+// the bytes it covers have changed.
+
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::protocols::protocol::{Connection, ConnectionError};
+use crate::protocols::timeout::{now_ms, sleep};
+
+// Don't let a watch poll the device more often than this
+const MIN_INTERVAL: Duration = Duration::from_millis(16);
+
+// The ids and (address, size) ranges of the watches to read, by device
+type DueWatches = BTreeMap<String, Vec<(u32, Vec<(u32, u32)>)>>;
+
+// A run of bytes that changed in a watched range. The first notification for a watch has all of its
+// ranges as changes, with empty old data.
+#[derive(Debug, Clone, Serialize)]
+pub struct WatchChange {
+    pub address: u32,
+    pub old: Vec<u8>,
+    pub new: Vec<u8>
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub enum WatchEvent {
+    Changed { id: u32, changes: Vec<WatchChange> },
+    // Reading the watched memory failed, the watch keeps polling and reports the next change as usual
+    Failed { id: u32, code: &'static str, message: String }
+}
+
+struct Watch {
+    device: String,
+    ranges: Vec<(u32, u32)>,
+    interval: Duration,
+    // When the watch is read next, in milliseconds as given by now_ms
+    next_ms: f64,
+    data: Option<Vec<Vec<u8>>>,
+    events: UnboundedSender<WatchEvent>
+}
+
+struct WatchState {
+    next_id: u32,
+    watches: BTreeMap<u32, Watch>,
+    running: bool
+}
+
+// Compare the old and new contents of a range starting at address, returning every run of changed bytes
+fn diff(address: u32, old: &[u8], new: &[u8]) -> Vec<WatchChange> {
+    let mut changes: Vec<WatchChange> = Vec::new();
+    let mut previous: Option<usize> = None;
+    for (i, (o, n)) in old.iter().zip(new.iter()).enumerate() {
+        if o == n {
+            continue;
+        }
+
+        match changes.last_mut() {
+            Some(change) if previous == Some(i - 1) => {
+                change.old.push(*o);
+                change.new.push(*n);
+            },
+            _ => changes.push(WatchChange { address: address + i as u32, old: vec![*o], new: vec![*n] })
+        }
+        previous = Some(i);
+    }
+    changes
+}
+
+#[derive(Clone)]
+pub struct Watcher {
+    state: Arc<Mutex<WatchState>>
+}
+
+impl Default for Watcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Watcher {
+    pub fn new() -> Self {
+        Self { state: Arc::new(Mutex::new(WatchState { next_id: 1, watches: BTreeMap::new(), running: false })) }
+    }
+
+    // Watch the ranges, given as a flat list of (address, size) pairs, checking them every interval.
+    // Returns the id of the watch and the stream of events for it, dropping the stream removes the watch.
+    pub fn watch(&self, device: &str, address_info: &[u32], interval: Duration) -> Result<(u32, UnboundedReceiver<WatchEvent>), ConnectionError> {
+        if address_info.is_empty() || address_info.len() % 2 != 0 {
+            return Err(ConnectionError::InvalidRequest("Watches need a list of (address, size) pairs".into()));
+        }
+
+        let interval = interval.max(MIN_INTERVAL);
+        let (events, receiver) = mpsc::unbounded();
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.watches.insert(id, Watch {
+            device: device.to_string(),
+            ranges: address_info.chunks(2).map(|r| (r[0], r[1])).collect(),
+            interval,
+            next_ms: now_ms() + interval.as_secs_f64() * 1000.0,
+            data: None,
+            events
+        });
+        log::debug!("watch: Added watch {} on {} for {} ranges", id, device, address_info.len() / 2);
+        Ok((id, receiver))
+    }
+
+    pub fn unwatch(&self, id: u32) -> bool {
+        self.state.lock().unwrap().watches.remove(&id).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().watches.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Claim the polling loop, returns true if the caller should start running it because nobody else is
+    pub fn start(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.running || state.watches.is_empty() {
+            return false;
+        }
+        state.running = true;
+        true
+    }
+
+    // Take the watches that are due at now, grouped by device
+    fn due(&self, now: f64) -> DueWatches {
+        let mut state = self.state.lock().unwrap();
+        let mut due = DueWatches::new();
+        for (id, watch) in state.watches.iter_mut() {
+            if watch.next_ms <= now {
+                watch.next_ms = now + watch.interval.as_secs_f64() * 1000.0;
+                due.entry(watch.device.to_string()).or_default().push((*id, watch.ranges.clone()));
+            }
+        }
+        due
+    }
+
+    // Time from now until the next watch is due, or None when there are no watches left and the loop should stop
+    fn next_tick(&self, now: f64) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap();
+        state.watches.retain(|_, w| !w.events.is_closed());
+        let next_ms = state.watches.values().map(|w| w.next_ms).fold(None, |next: Option<f64>, ms| Some(next.map_or(ms, |n| n.min(ms))));
+        if next_ms.is_none() {
+            state.running = false;
+        }
+        next_ms.map(|ms| Duration::from_millis((ms - now).max(0.0).ceil() as u64))
+    }
+
+    fn notify(&self, id: u32, data: Vec<Vec<u8>>) {
+        let mut state = self.state.lock().unwrap();
+        let watch = match state.watches.get_mut(&id) {
+            Some(w) => w,
+            None => return
+        };
+
+        let changes: Vec<WatchChange> = match &watch.data {
+            Some(old) => watch.ranges.iter().zip(old.iter().zip(data.iter())).flat_map(|((address, _), (o, n))| diff(*address, o, n)).collect(),
+            None => watch.ranges.iter().zip(data.iter()).map(|((address, _), n)| WatchChange { address: *address, old: Vec::new(), new: n.to_vec() }).collect()
+        };
+
+        watch.data = Some(data);
+        if !changes.is_empty() {
+            let _ = watch.events.unbounded_send(WatchEvent::Changed { id, changes });
+        }
+    }
+
+    fn fail(&self, id: u32, error: &ConnectionError) {
+        if let Some(watch) = self.state.lock().unwrap().watches.get(&id) {
+            let _ = watch.events.unbounded_send(WatchEvent::Failed { id, code: error.code(), message: error.detail().to_string() });
+        }
+    }
+
+    // The polling loop, runs until there are no watches left. Only one loop should run at a time, see start.
+    pub async fn run(&self, conn: &dyn Connection) {
+        log::debug!("watch: Started polling");
+        while let Some(tick) = self.next_tick(now_ms()) {
+            sleep(tick).await;
+            self.poll(conn, now_ms()).await;
+        }
+        log::debug!("watch: Stopped polling, no watches left");
+    }
+
+    // Read the watches that are due at now, with one read_multi per device
+    async fn poll(&self, conn: &dyn Connection, now: f64) {
+        for (device, watches) in self.due(now) {
+            let address_info: Vec<u32> = watches.iter().flat_map(|(_, ranges)| ranges.iter().flat_map(|(a, s)| vec![*a, *s])).collect();
+            match conn.read_multi(&device, &address_info).await {
+                Ok(mut data) => {
+                    for (id, ranges) in watches {
+                        let rest = data.split_off(ranges.len().min(data.len()));
+                        self.notify(id, data);
+                        data = rest;
+                    }
+                },
+                Err(e) => {
+                    log::debug!("watch: Polling {} failed: {}", device, e);
+                    for (id, _) in watches {
+                        self.fail(id, &e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "native"))]
+mod tests {
+    use super::*;
+    use crate::protocols::mock::{Fault, MockConnection};
+    use futures::{FutureExt, StreamExt};
+
+    const SECOND: f64 = 1000.0;
+    const SECOND_INTERVAL: Duration = Duration::from_secs(1);
+
+    // The next event that is already waiting, if any
+    fn next(events: &mut UnboundedReceiver<WatchEvent>) -> Option<WatchEvent> {
+        events.next().now_or_never().flatten()
+    }
+
+    fn changes(event: Option<WatchEvent>) -> Vec<(u32, Vec<u8>, Vec<u8>)> {
+        match event {
+            Some(WatchEvent::Changed { changes, .. }) => changes.into_iter().map(|c| (c.address, c.old, c.new)).collect(),
+            e => panic!("Expected a change, got {:?}", e)
+        }
+    }
+
+    #[test]
+    fn diff_finds_every_run_of_changes() {
+        assert!(diff(0x100, &[1, 2, 3], &[1, 2, 3]).is_empty());
+        let runs: Vec<(u32, Vec<u8>, Vec<u8>)> = diff(0x100, &[1, 2, 3, 4, 5, 6], &[9, 9, 3, 4, 8, 6]).into_iter().map(|c| (c.address, c.old, c.new)).collect();
+        assert_eq!(runs, vec![(0x100, vec![1, 2], vec![9, 9]), (0x104, vec![5], vec![8])]);
+        assert_eq!(diff(0x100, &[1, 2], &[1, 7]).len(), 1);
+    }
+
+    #[test]
+    fn watches_need_address_and_size_pairs() {
+        let watcher = Watcher::new();
+        assert!(matches!(watcher.watch("mock", &[], SECOND_INTERVAL), Err(ConnectionError::InvalidRequest(_))));
+        assert!(matches!(watcher.watch("mock", &[0xF5_0000, 2, 0xF5_0010], SECOND_INTERVAL), Err(ConnectionError::InvalidRequest(_))));
+        assert!(watcher.is_empty());
+    }
+
+    #[test]
+    fn new_watches_wait_a_full_interval() {
+        let watcher = Watcher::new();
+        let (first, _events) = watcher.watch("mock", &[0xF5_0000, 2], SECOND_INTERVAL).unwrap();
+        let now = now_ms();
+        assert!(watcher.due(now).is_empty());
+        assert_eq!(watcher.due(now + SECOND)["mock"][0].0, first);
+
+        // A watch added while the loop waits is only due a full interval after it was added
+        let (second, _events) = watcher.watch("mock", &[0xF5_0010, 2], SECOND_INTERVAL).unwrap();
+        let due = watcher.due(now_ms() + SECOND / 2.0);
+        assert!(due.is_empty());
+        assert!(watcher.next_tick(now_ms()).unwrap() <= SECOND_INTERVAL);
+        assert_eq!(watcher.due(now_ms() + 2.0 * SECOND)["mock"].iter().map(|(id, _)| *id).collect::<Vec<_>>(), vec![first, second]);
+    }
+
+    #[tokio::test]
+    async fn due_watches_share_one_read_per_device() {
+        let mock = MockConnection::with_devices(&["one", "two"]);
+        mock.poke("one", 0xF5_0000, &[1, 2, 3, 4]).unwrap();
+        let watcher = Watcher::new();
+        let (_, mut first) = watcher.watch("one", &[0xF5_0000, 2], SECOND_INTERVAL).unwrap();
+        let (_, mut second) = watcher.watch("one", &[0xF5_0002, 2, 0xF5_0100, 1], SECOND_INTERVAL).unwrap();
+        let (_, mut third) = watcher.watch("two", &[0xF5_0000, 1], SECOND_INTERVAL).unwrap();
+
+        watcher.poll(&mock, now_ms() + SECOND).await;
+        assert_eq!(mock.operations(), 2);
+        assert_eq!(changes(next(&mut first)), vec![(0xF5_0000, vec![], vec![1, 2])]);
+        assert_eq!(changes(next(&mut second)), vec![(0xF5_0002, vec![], vec![3, 4]), (0xF5_0100, vec![], vec![0])]);
+        assert_eq!(changes(next(&mut third)), vec![(0xF5_0000, vec![], vec![0])]);
+
+        // Only watches with changes hear about the next poll
+        mock.poke("one", 0xF5_0003, &[9]).unwrap();
+        watcher.poll(&mock, now_ms() + 2.0 * SECOND).await;
+        assert_eq!(mock.operations(), 4);
+        assert!(next(&mut first).is_none());
+        assert_eq!(changes(next(&mut second)), vec![(0xF5_0003, vec![4], vec![9])]);
+        assert!(next(&mut third).is_none());
+    }
+
+    #[tokio::test]
+    async fn failed_reads_are_reported_to_every_watch_of_the_device() {
+        let mock = MockConnection::new();
+        let watcher = Watcher::new();
+        let (first_id, mut first) = watcher.watch("mock", &[0xF5_0000, 2], SECOND_INTERVAL).unwrap();
+        let (second_id, mut second) = watcher.watch("mock", &[0xF5_0010, 2], SECOND_INTERVAL).unwrap();
+
+        mock.inject_fault(Fault::Disconnect { after: 0 });
+        watcher.poll(&mock, now_ms() + SECOND).await;
+        assert!(matches!(next(&mut first), Some(WatchEvent::Failed { id, code: "DISCONNECTED", .. }) if id == first_id));
+        assert!(matches!(next(&mut second), Some(WatchEvent::Failed { id, .. }) if id == second_id));
+    }
+
+    #[test]
+    fn the_loop_stops_once_every_stream_is_dropped() {
+        let watcher = Watcher::new();
+        let (_, events) = watcher.watch("mock", &[0xF5_0000, 2], SECOND_INTERVAL).unwrap();
+        assert!(watcher.start());
+        assert!(!watcher.start());
+
+        drop(events);
+        assert!(watcher.next_tick(now_ms()).is_none());
+        assert!(watcher.is_empty());
+    }
+}
@@ -0,0 +1,114 @@
+// Thin websocket wrapper so the usb2snes protocol code doesn't need to care about which
+// websocket implementation is in use. The browser build uses ws_stream_wasm and the
+// native build uses tokio-tungstenite, both exposed through the same API.
+
+use futures::{stream::StreamExt, SinkExt};
+use crate::protocols::protocol::{ConnectionError, ErrorDetail};
+
+#[derive(Debug)]
+pub enum WsFrame {
+    Text(String),
+    Binary(Vec<u8>)
+}
+
+#[cfg(feature = "wasm")]
+pub struct WebSocket {
+    ws: ws_stream_wasm::WsMeta,
+    wsio: ws_stream_wasm::WsStream
+}
+
+#[cfg(feature = "wasm")]
+impl WebSocket {
+    pub async fn connect(uri: &str) -> Result<Self, ConnectionError> {
+        let (ws, wsio) = ws_stream_wasm::WsMeta::connect(uri, None).await.map_err(|e| ConnectionError::ConnectFailed(ErrorDetail::with_source("Could not connect to websocket", e)))?;
+        Ok(Self { ws, wsio })
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.ws.ready_state() == ws_stream_wasm::WsState::Open
+    }
+
+    pub async fn send(&mut self, frame: WsFrame) -> Result<(), ConnectionError> {
+        let message = match frame {
+            WsFrame::Text(t) => ws_stream_wasm::WsMessage::Text(t),
+            WsFrame::Binary(d) => ws_stream_wasm::WsMessage::Binary(d)
+        };
+        self.wsio.send(message).await.map_err(|e| ConnectionError::Disconnected(ErrorDetail::with_source("Could not send websocket message", e)))
+    }
+
+    pub async fn flush(&mut self) -> Result<(), ConnectionError> {
+        self.wsio.flush().await.map_err(|e| ConnectionError::Disconnected(ErrorDetail::with_source("Could not flush data", e)))
+    }
+
+    pub async fn next(&mut self) -> Option<WsFrame> {
+        match self.wsio.next().await? {
+            ws_stream_wasm::WsMessage::Text(t) => Some(WsFrame::Text(t)),
+            ws_stream_wasm::WsMessage::Binary(d) => Some(WsFrame::Binary(d))
+        }
+    }
+
+    pub async fn close(&mut self) -> Result<(), ConnectionError> {
+        self.ws.close().await.map_err(|e| ConnectionError::Disconnected(ErrorDetail::with_source("Could not close websocket", e)))?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "native")]
+pub struct WebSocket {
+    wsio: tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>,
+    open: bool
+}
+
+#[cfg(feature = "native")]
+impl WebSocket {
+    pub async fn connect(uri: &str) -> Result<Self, ConnectionError> {
+        let (wsio, _) = tokio_tungstenite::connect_async(uri).await.map_err(|e| ConnectionError::ConnectFailed(ErrorDetail::with_source("Could not connect to websocket", e)))?;
+        Ok(Self { wsio, open: true })
+    }
+
+    // tungstenite has no ready state, so track it from the results of reads and writes instead
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+
+    pub async fn send(&mut self, frame: WsFrame) -> Result<(), ConnectionError> {
+        use tokio_tungstenite::tungstenite::Message;
+        let message = match frame {
+            WsFrame::Text(t) => Message::Text(t),
+            WsFrame::Binary(d) => Message::Binary(d)
+        };
+
+        self.wsio.send(message).await.map_err(|e| {
+            self.open = false;
+            ConnectionError::Disconnected(ErrorDetail::with_source("Could not send websocket message", e))
+        })
+    }
+
+    pub async fn flush(&mut self) -> Result<(), ConnectionError> {
+        self.wsio.flush().await.map_err(|e| {
+            self.open = false;
+            ConnectionError::Disconnected(ErrorDetail::with_source("Could not flush data", e))
+        })
+    }
+
+    pub async fn next(&mut self) -> Option<WsFrame> {
+        use tokio_tungstenite::tungstenite::Message;
+        loop {
+            match self.wsio.next().await {
+                Some(Ok(Message::Text(t))) => return Some(WsFrame::Text(t)),
+                Some(Ok(Message::Binary(d))) => return Some(WsFrame::Binary(d)),
+                // Control frames are handled by tungstenite itself, just skip past them
+                Some(Ok(Message::Ping(_))) | Some(Ok(Message::Pong(_))) => continue,
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => {
+                    self.open = false;
+                    return None;
+                }
+            }
+        }
+    }
+
+    pub async fn close(&mut self) -> Result<(), ConnectionError> {
+        self.open = false;
+        self.wsio.close(None).await.map_err(|e| ConnectionError::Disconnected(ErrorDetail::with_source("Could not close websocket", e)))
+    }
+}
@@ -1,6 +1,6 @@
 fn main() {
     tonic_build::configure()
-        .type_attribute(".", "#[derive(Serialize)]")
+        .type_attribute(".", "#[derive(Serialize, Deserialize)]")
         .build_server(false)
         .compile(
             &["proto/randomizer.proto"],
@@ -2,6 +2,7 @@ use crate::ClientContext;
 use std::convert::TryInto;
 use crate::Message;
 use crate::services::randomizer::{EventType, SessionEvent, ClientState};
+use console_interface::protocols::verify::{RetryPolicy, VerifiedConnection};
 
 /* SMZ3 Game mode updates, this takes the client context so it can talk to both the backend service and some kind of console connector */
 
@@ -19,7 +20,8 @@ pub struct SMZ3Client {
     items_base: u32,
     seed_data: u32,
     verified_events: Vec<i32>,
-    game_state: GameState
+    game_state: GameState,
+    retry_policy: RetryPolicy
 }
 
 impl SMZ3Client {
@@ -43,6 +45,7 @@ impl SMZ3Client {
         let svc = &ctx.randomizer_service;
         let client = &ctx.client.as_ref().ok_or("Client must be initialized and authenticated")?;
         let conn = &ctx.console_connection.as_ref().ok_or("Console connection must be initialized")?;
+        let policy = &self.retry_policy;
 
         match self.game_state {
             GameState::Initialized => {
@@ -76,21 +79,7 @@ impl SMZ3Client {
                 // we'll just fetch blank SRAM and things will be smooth
                 
                 // Read and verify read to make sure the input data is consistent        
-                let in_ptrs = {
-                    let mut verified = false;
-                    let mut verified_in_ptrs = Vec::new();
-                    
-                    while !verified {
-                        let in_ptrs = conn.read_single(&ctx.device, self.items_base + 0x600, 0x10).await?;
-                        verified_in_ptrs = conn.read_single(&ctx.device, self.items_base + 0x600, 0x10).await?;
-                        if in_ptrs == verified_in_ptrs {
-                            verified = true;
-                        } else {
-                            log::debug!("smz3: Verification of read input pointers failed, trying again");
-                        }
-                    }
-                    verified_in_ptrs
-                };
+                let in_ptrs = conn.read_stable(&ctx.device, self.items_base + 0x600, 0x10, policy).await?;
 
                 let (_snes_read_ptr, snes_write_ptr, snes_event_id) = (
                     u16::from_le_bytes(in_ptrs[0..2].try_into()?), 
@@ -123,64 +112,24 @@ impl SMZ3Client {
                                 i32::to_le_bytes(recv_events.events.iter().map(|e| e.id).max().ok_or("Could not get max id of events")?).to_vec()];
 
                     // Write this data to the snes, (and verify that it got written before doing anything further)
-                    // Any connection error will break us out of the loop as it should, but verify/rewrite will help against accidental
+                    // Any connection error will break us out as it should, but verify/rewrite will help against accidental
                     // data corruption for whatever reason
 
                     // Write the actual data first and verify that it's written
-                    {
-                        let mut verified = false;
-                        while !verified {
-                            log::debug!("smz3: Writing item received data to SNES");
-                            let _ = conn.write_single(&ctx.device, addresses[0], &data[0]).await?;
-                            let verify_data = conn.read_single(&ctx.device, addresses[0], data[0].len() as u32).await?;
-                            
-                            if verify_data == data[0] {
-                                verified = true;
-                            } else {
-                                log::debug!("smz3: Verification of written data of received items failed, trying again");
-                            }                    
-                        }
-                    }
+                    log::debug!("smz3: Writing item received data to SNES");
+                    conn.write_verified(&ctx.device, addresses[0], &data[0], policy).await?;
 
                     // The data is ok, write the updated pointers
                     // If this fails, it's fine since worst case we just wrote some data previously that'll get overwritten again            
-                    {
-                        let mut verified = false;
-                        while !verified {
-                            log::debug!("smz3: Writing item received pointers to SNES");
-                            let _ = conn.write_multi(&ctx.device, &addresses[1..], &data[1..]).await?;
-                            let verify_data = conn.read_multi(&ctx.device, 
-                                &[addresses[1], data[1].len() as u32,
-                                            addresses[2], data[2].len() as u32]).await?;
-                            
-                            if verify_data == data[1..] {
-                                verified = true;
-                            } else {
-                                log::debug!("smz3: Verification of written pointers of received items failed, trying again");
-                            }   
-                        }
-                    }
+                    log::debug!("smz3: Writing item received pointers to SNES");
+                    conn.write_verified_multi(&ctx.device, &addresses[1..], &data[1..], policy).await?;
                     
                     // Append the correct written events to the list of events to report back
                     self.verified_events.append(&mut recv_events.events.iter().map(|e| e.id).collect());
                 }
 
                 // Double-read again to really make sure the data makes sense
-                let out_ptrs = {
-                    let mut verified = false;
-                    let mut verified_out_ptrs = Vec::new();
-                    
-                    while !verified {
-                        let out_ptrs = conn.read_single(&ctx.device, self.items_base + 0x680, 0x04).await?;
-                        verified_out_ptrs = conn.read_single(&ctx.device, self.items_base + 0x680, 0x04).await?;
-                        if out_ptrs == verified_out_ptrs {
-                            verified = true;
-                        } else {
-                            log::debug!("smz3: Verification of read output pointers failed, trying again");
-                        }
-                    }
-                    verified_out_ptrs
-                };
+                let out_ptrs = conn.read_stable(&ctx.device, self.items_base + 0x680, 0x04, policy).await?;
 
                 // Ok, verified data, let's extract the write pointers
                 let (sync_read_ptr, snes_write_ptr) = (
@@ -225,19 +174,9 @@ impl SMZ3Client {
                     }
 
                     // If we get here, all the events were correctly sent to the server and we can write the confirmation to the SNES
-                    let mut verified = false;
                     let new_sync_read_ptr = sync_read_ptr + messages;
-                    while !verified {
-                        log::debug!("smz3: Updating outgoing message pointer on the SNES to: {}", new_sync_read_ptr);
-                        let _ = conn.write_single(&ctx.device, self.items_base + 0x680, &u16::to_le_bytes(new_sync_read_ptr)).await?;
-                        let verify_data = conn.read_single(&ctx.device, self.items_base + 0x680, 0x02).await?;
-                        let verify_value = u16::from_le_bytes(verify_data[..2].try_into()?);
-                        if verify_value == new_sync_read_ptr {
-                            verified = true;
-                        } else {
-                            log::debug!("smz3: Verification of written data of sent items failed, trying again");
-                        }
-                    }            
+                    log::debug!("smz3: Updating outgoing message pointer on the SNES to: {}", new_sync_read_ptr);
+                    conn.write_verified(&ctx.device, self.items_base + 0x680, &u16::to_le_bytes(new_sync_read_ptr), policy).await?;
                 }
 
                 // Send item confirmation, at this point it doesn't matter too much if we error out
@@ -255,4 +194,205 @@ impl SMZ3Client {
         // All done
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use console_interface::protocols::mock::MockConnection;
+    use console_interface::protocols::protocol::ConnectionError;
+    use console_interface::protocols::recording::{Call, ReplayConnection, Trace, TraceEntry, TracePlayer, TRACE_VERSION};
+    use futures::executor::block_on;
+    use crate::services::randomizer::*;
+    use crate::tests::*;
+
+    const ITEMS: u32 = 0xE04000;
+    const SEED_DATA: u32 = 0xE046A0;
+
+    fn read(address_info: &[u32], data: &[&[u8]]) -> TraceEntry {
+        entry(Call::ReadMulti { device: DEVICE.into(), address_info: address_info.to_vec() }, data)
+    }
+
+    fn write(addresses: &[u32], data: &[&[u8]]) -> TraceEntry {
+        let data: Vec<Vec<u8>> = data.iter().map(|d| d.to_vec()).collect();
+        entry(Call::WriteMulti { device: DEVICE.into(), addresses: addresses.to_vec(), data }, ())
+    }
+
+    // What a recording of a session that receives one item and sends one looks like
+    fn recorded_session() -> Trace {
+        let in_ptrs: &[u8] = &[0, 0, 2, 0, 0, 0, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0];
+        let received = SessionEvent { id: 7, from_world_id: 2, to_world_id: 1, item_id: 0x30, ..Default::default() };
+        let sent = SessionEvent {
+            id: 0,
+            event_type: EventType::ItemFound as i32,
+            from_world_id: 1,
+            item_id: 0x40,
+            item_location: 0x123,
+            sequence_num: 0,
+            to_world_id: 2,
+            confirmed: false,
+            message: "Sent item 64 at location 291 from world 1 to world 2".into(),
+            time_stamp: "".into()
+        };
+
+        let entries = vec![
+            entry(Call::ReadSingle { device: DEVICE.into(), address: SEED_DATA, size: 0x50 }, seed_data()),
+            service("update_player", UpdatePlayerRequest { client_token: TOKEN.into(), client_state: ClientState::Ready as i32, device_name: Some(DEVICE.into()) }, UpdatePlayerResponse { success: true }),
+            read(&[ITEMS + 0x600, 0x10], &[in_ptrs]),
+            read(&[ITEMS + 0x600, 0x10], &[in_ptrs]),
+            service("get_events", GetEventsRequest { client_token: TOKEN.into(), event_types: vec![EventType::ItemFound as i32], from_event_id: Some(6), to_world_id: Some(1), ..Default::default() }, GetEventsResponse { events: vec![received] }),
+            write(&[ITEMS + 8], &[&[2, 0, 0x30, 0]]),
+            read(&[ITEMS + 8, 4], &[&[2, 0, 0x30, 0]]),
+            write(&[ITEMS + 0x602, ITEMS + 0x608], &[&[3, 0], &[7, 0, 0, 0]]),
+            read(&[ITEMS + 0x602, 2, ITEMS + 0x608, 4], &[&[3, 0], &[7, 0, 0, 0]]),
+            read(&[ITEMS + 0x680, 4], &[&[0, 0, 1, 0]]),
+            read(&[ITEMS + 0x680, 4], &[&[0, 0, 1, 0]]),
+            entry(Call::ReadSingle { device: DEVICE.into(), address: ITEMS + 0x700, size: 8 }, vec![2u8, 0, 0x40, 0, 0x23, 0x01, 0, 0]),
+            service("send_event", SendEventRequest { client_token: TOKEN.into(), event: Some(sent.clone()) }, SendEventResponse { event: Some(SessionEvent { id: 8, ..sent }) }),
+            write(&[ITEMS + 0x680], &[&[1, 0]]),
+            read(&[ITEMS + 0x680, 2], &[&[1, 0]]),
+            service("confirm_events", ConfirmEventsRequest { client_token: TOKEN.into(), event_ids: vec![7] }, ConfirmEventsResponse { event_ids: vec![7] })
+        ];
+
+        Trace { version: TRACE_VERSION, entries }
+    }
+
+    #[test]
+    fn replays_a_recorded_session() {
+        // Through JSON, the same way a trace gets from a player to a developer
+        let trace = Trace::from_json(&recorded_session().to_json().unwrap()).unwrap();
+        let connection = ReplayConnection::new(trace);
+        let player: Arc<TracePlayer> = connection.player();
+        let ctx = context(Box::new(connection), RandomizerService::replay(player.clone()));
+
+        let mut client = SMZ3Client::new();
+        for _ in 0..3 {
+            block_on(client.update(&ctx)).unwrap();
+        }
+
+        assert_eq!(player.remaining(), 0);
+        assert!(matches!(client.game_state, GameState::Running));
+        assert!(client.verified_events.is_empty());
+    }
+
+    #[test]
+    fn replay_fails_when_the_session_goes_differently() {
+        let connection = ReplayConnection::new(recorded_session());
+        let player = connection.player();
+        let ctx = context(Box::new(connection), RandomizerService::replay(player));
+
+        // A client that keeps its item buffers somewhere else than the one that was recorded
+        let mut client = SMZ3Client::new_with_options(ITEMS + 0x1000, SEED_DATA);
+        block_on(client.update(&ctx)).unwrap();
+        block_on(client.update(&ctx)).unwrap();
+        let error = block_on(client.update(&ctx)).unwrap_err();
+        assert!(matches!(error.downcast_ref::<ConnectionError>(), Some(ConnectionError::ProtocolViolation(_))));
+    }
+
+    fn in_ptrs(write_ptr: u16, event_id: i32) -> Vec<u8> {
+        let mut data = vec![0; 0x10];
+        data[2..4].copy_from_slice(&write_ptr.to_le_bytes());
+        data[8..12].copy_from_slice(&event_id.to_le_bytes());
+        data
+    }
+
+    fn get_events(from_event_id: i32, events: Vec<SessionEvent>) -> TraceEntry {
+        service("get_events", GetEventsRequest { client_token: TOKEN.into(), event_types: vec![EventType::ItemFound as i32], from_event_id: Some(from_event_id), to_world_id: Some(1), ..Default::default() }, GetEventsResponse { events })
+    }
+
+    fn ready() -> TraceEntry {
+        service("update_player", UpdatePlayerRequest { client_token: TOKEN.into(), client_state: ClientState::Ready as i32, device_name: Some(DEVICE.into()) }, UpdatePlayerResponse { success: true })
+    }
+
+    // Runs the client up to the running state against a mock device holding this session's seed
+    fn running(mock: &MockConnection, services: Vec<TraceEntry>) -> (SMZ3Client, ClientContext, Arc<TracePlayer>) {
+        mock.poke(DEVICE, SEED_DATA, &seed_data()).unwrap();
+        let player = Arc::new(TracePlayer::new(Trace { version: TRACE_VERSION, entries: services }));
+        let ctx = context(Box::new(mock.clone()), RandomizerService::replay(player.clone()));
+
+        let mut client = SMZ3Client::new();
+        block_on(client.update(&ctx)).unwrap();
+        block_on(client.update(&ctx)).unwrap();
+        assert!(matches!(client.game_state, GameState::Running));
+        (client, ctx, player)
+    }
+
+    #[test]
+    fn waits_for_the_seed_of_the_session() {
+        let mock = MockConnection::new();
+        let mut seed = seed_data();
+        seed[0x10] = b'x';
+        mock.poke(DEVICE, SEED_DATA, &seed).unwrap();
+        let player = Arc::new(TracePlayer::new(Trace { version: TRACE_VERSION, entries: vec![] }));
+        let ctx = context(Box::new(mock), RandomizerService::replay(player));
+
+        let mut client = SMZ3Client::new();
+        for _ in 0..3 {
+            block_on(client.update(&ctx)).unwrap();
+        }
+        assert!(matches!(client.game_state, GameState::Detecting));
+    }
+
+    #[test]
+    fn writes_received_items_to_the_mailbox() {
+        let mock = MockConnection::new();
+        mock.poke(DEVICE, ITEMS + 0x600, &in_ptrs(2, 5)).unwrap();
+        let events = vec![
+            SessionEvent { id: 7, from_world_id: 2, to_world_id: 1, item_id: 0x30, ..Default::default() },
+            SessionEvent { id: 9, from_world_id: 3, to_world_id: 1, item_id: 0x31, ..Default::default() }
+        ];
+        let (mut client, ctx, player) = running(&mock, vec![
+            ready(),
+            get_events(6, events),
+            service("confirm_events", ConfirmEventsRequest { client_token: TOKEN.into(), event_ids: vec![7, 9] }, ConfirmEventsResponse { event_ids: vec![7, 9] })
+        ]);
+
+        block_on(client.update(&ctx)).unwrap();
+
+        assert_eq!(mock.peek(DEVICE, ITEMS + 8, 8).unwrap(), vec![2, 0, 0x30, 0, 3, 0, 0x31, 0]);
+        assert_eq!(mock.peek(DEVICE, ITEMS + 0x600, 0x10).unwrap(), in_ptrs(4, 9));
+        assert!(client.verified_events.is_empty());
+        assert_eq!(player.remaining(), 0);
+    }
+
+    #[test]
+    fn sends_items_from_the_mailbox() {
+        let mock = MockConnection::new();
+        mock.poke(DEVICE, ITEMS + 0x680, &[1, 0, 3, 0]).unwrap();
+        mock.poke(DEVICE, ITEMS + 0x708, &[2, 0, 0x40, 0, 0x23, 0x01, 0, 0, 3, 0, 0x41, 0, 0x24, 0x01, 0, 0]).unwrap();
+        let sent = |sequence_num: i32, to_world_id: i32, item_id: i32, item_location: i32| SessionEvent {
+            event_type: EventType::ItemFound as i32,
+            from_world_id: 1,
+            to_world_id,
+            item_id,
+            item_location,
+            sequence_num,
+            message: format!("Sent item {} at location {} from world 1 to world {}", item_id, item_location, to_world_id),
+            ..Default::default()
+        };
+        let (mut client, ctx, player) = running(&mock, vec![
+            ready(),
+            get_events(1, vec![]),
+            service("send_event", SendEventRequest { client_token: TOKEN.into(), event: Some(sent(1, 2, 0x40, 0x123)) }, SendEventResponse { event: Some(sent(1, 2, 0x40, 0x123)) }),
+            service("send_event", SendEventRequest { client_token: TOKEN.into(), event: Some(sent(2, 3, 0x41, 0x124)) }, SendEventResponse { event: Some(sent(2, 3, 0x41, 0x124)) })
+        ]);
+
+        block_on(client.update(&ctx)).unwrap();
+
+        assert_eq!(mock.peek(DEVICE, ITEMS + 0x680, 4).unwrap(), vec![3, 0, 3, 0]);
+        assert_eq!(player.remaining(), 0);
+    }
+
+    #[test]
+    fn leaves_the_mailbox_alone_when_sending_fails() {
+        let mock = MockConnection::new();
+        mock.poke(DEVICE, ITEMS + 0x680, &[0, 0, 1, 0]).unwrap();
+        mock.poke(DEVICE, ITEMS + 0x700, &[2, 0, 0x40, 0, 0x23, 0x01, 0, 0]).unwrap();
+        // Nothing recorded for send_event, so the service fails it
+        let (mut client, ctx, _) = running(&mock, vec![ready(), get_events(1, vec![])]);
+
+        assert!(block_on(client.update(&ctx)).is_err());
+        assert_eq!(mock.peek(DEVICE, ITEMS + 0x680, 4).unwrap(), vec![0, 0, 1, 0]);
+    }
+}
@@ -1,19 +1,25 @@
 #![allow(clippy::unused_unit)]
 use futures_locks::RwLock;
-use js_sys::{Promise, Function};
+use js_sys::{Promise, Function, Uint8Array};
 use wasm_bindgen::prelude::*;
 use wasm_bindgen_futures::future_to_promise;
 use services::randomizer::{RandomizerService, ClientState};
-use console_interface::protocols::protocol::{self, ConnectionError};
+use console_interface::protocols::discovery;
+use console_interface::protocols::protocol::{self, Capability, ConnectionError, FileType};
+use console_interface::protocols::recording::{Recorder, RecordingConnection, ReplayConnection, Trace};
+use std::sync::Arc;
 pub use console_interface::ConsoleInterface;
 
 mod clients;
+mod rom;
 mod services;
 
 // Use `wee_alloc` as the global allocator.
 #[global_allocator]
 static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
 
+const DEFAULT_INSTALL_FOLDER: &str = "/seeds";
+
 static LOG_LEVEL: log::Level = if cfg!(debug_assertions) { log::Level::Debug } else { log::Level::Info };
 
 #[wasm_bindgen]
@@ -38,27 +44,36 @@ pub enum Message {
 }
 impl Message {
     // Send a message to a JS callback that something has happened
-    pub fn send(&self, callback: &Function, args: Option<&[&str]>) {
-        let args = serde_wasm_bindgen::to_value(&args).unwrap_or_default();
-        let _ = callback.call2(&JsValue::NULL, &JsValue::from(*self as i32), &args);
+    pub fn send(&self, callback: &Callback, args: Option<&[&str]>) {
+        if let Some(callback) = &callback.0 {
+            let args = serde_wasm_bindgen::to_value(&args).unwrap_or_default();
+            let _ = callback.call2(&JsValue::NULL, &JsValue::from(*self as i32), &args);
+        }
     }
 }
 
+// The JS function messages are sent to, None when running without JS like the game client tests do
+pub struct Callback(Option<Function>);
+
 pub struct ClientContext {
-    console_connection: Option<Box<dyn protocol::Connection>>,
+    console_connection: Option<Arc<dyn protocol::Connection>>,
     randomizer_service: RandomizerService,
     session: Option<services::randomizer::GetSessionResponse>,
     client: Option<services::randomizer::RegisterPlayerResponse>,
     device: String,
     connected: bool,
     session_guid: String,
-    callback: Function
+    // SD card folder that seeds are installed to
+    install_folder: String,
+    callback: Callback
 }
 
 #[wasm_bindgen]
 pub struct RandomizerClient {
     context: RwLock<ClientContext>,
-    game_client: RwLock<Option<clients::multiworld::smz3::SMZ3Client>>
+    game_client: RwLock<Option<clients::multiworld::smz3::SMZ3Client>>,
+    // Shared by the randomizer service and the console connection, so a trace has both in order
+    recorder: Arc<Recorder>
 }
 
 #[wasm_bindgen]
@@ -67,48 +82,72 @@ impl RandomizerClient {
         wasm_logger::init(wasm_logger::Config::new(LOG_LEVEL));
     }
 
-    async fn initialize_console_connection() -> Result<Box<dyn protocol::Connection>, Box<dyn std::error::Error>> {
-        /* Can we connect with SNI gRPC? */
-        log::debug!("client: Attempting to connect with SNI");
-        let sni_connection = protocol::create_connection(&protocol::Protocol::Sni);
-        if sni_connection.connect().await.is_ok() {
-            return Ok(sni_connection)
-        }
-
-        /* Can we connect with USB2SNES on the new port? */
-        log::debug!("client: Attempting to connect with USB2SNES");
-        let usb_connection = protocol::create_connection_with_uri(&protocol::Protocol::Usb2Snes, "ws://localhost:23074");
-        if usb_connection.connect().await.is_ok() {
-            return Ok(usb_connection)
-        }
-
-        /* Can we connect with USB2SNES on the old port? */
-        log::debug!("client: Attempting to connect with Legacy USB2SNES");
-        let legacy_connection = protocol::create_connection_with_uri(&protocol::Protocol::Usb2Snes, "ws://localhost:8080");
-        if legacy_connection.connect().await.is_ok() {
-            return Ok(legacy_connection)
+    // Use the first bridge that answers, in the order of the default discovery candidates
+    async fn initialize_console_connection(recorder: Arc<Recorder>) -> Result<Box<dyn protocol::Connection>, Box<dyn std::error::Error>> {
+        log::debug!("client: Looking for console bridges");
+        let bridges = discovery::discover(&discovery::default_candidates(), discovery::DEFAULT_PROBE_TIMEOUT).await;
+        match bridges.into_iter().next() {
+            Some(bridge) => {
+                log::debug!("client: Connected with {:?} at {}", bridge.protocol, bridge.uri);
+                Ok(Box::new(RecordingConnection::with_recorder(bridge.connection, recorder)))
+            },
+            None => Err("Could not connect to any console device".into())
         }
-
-        Err("Could not connect to any console device".into())
     }
 
     #[wasm_bindgen(constructor)]
     pub fn new(session_uri: String, session_guid: String, callback: Function) -> Self {
+        let recorder = Arc::new(Recorder::default());
         Self {
             game_client: RwLock::new(None),
             context: RwLock::new(ClientContext {
                 console_connection: None,
-                randomizer_service: RandomizerService::new(&session_uri),
+                randomizer_service: RandomizerService::new(&session_uri, recorder.clone()),
                 session: None,
                 client: None,
                 device: String::new(),
                 connected: false,
                 session_guid,
-                callback
+                install_folder: DEFAULT_INSTALL_FOLDER.to_string(),
+                callback: Callback(Some(callback))
             }),
+            recorder
          }
     }
 
+    // A client that plays back a trace from stop_recording, both the console traffic and the service responses.
+    // It has to be driven through the same calls as the recorded session, any difference fails the call.
+    pub fn replay(session_guid: String, trace: String, callback: Function) -> Result<RandomizerClient, JsValue> {
+        let connection = ReplayConnection::new(Trace::from_json(&trace)?);
+        let randomizer_service = RandomizerService::replay(connection.player());
+        Ok(Self {
+            game_client: RwLock::new(None),
+            context: RwLock::new(ClientContext {
+                console_connection: Some(Arc::new(connection)),
+                randomizer_service,
+                session: None,
+                client: None,
+                device: String::new(),
+                connected: true,
+                session_guid,
+                install_folder: DEFAULT_INSTALL_FOLDER.to_string(),
+                callback: Callback(Some(callback))
+            }),
+            recorder: Arc::new(Recorder::default())
+        })
+    }
+
+    // Record the service calls and console traffic from now on, replacing any earlier recording.
+    // Start before initialize so the trace has everything a replay needs.
+    pub fn start_recording(&self) {
+        self.recorder.start();
+    }
+
+    // Stop recording and get the trace as JSON, to attach to a bug report or pass to replay
+    pub fn stop_recording(&self) -> Result<String, JsValue> {
+        Ok(self.recorder.stop().to_json()?)
+    }
+
     pub fn initialize(&self) -> Promise {
         let m_ctx = self.context.clone();
         future_to_promise(async move {
@@ -184,27 +223,120 @@ impl RandomizerClient {
         })
     }
 
+    // Download the patch for this player and apply it to the player's ROMs, returning the finished ROM.
+    // The ALttP ROM is only needed for SMZ3 seeds.
+    pub fn build_rom(&self, sm_rom: Uint8Array, z3_rom: Option<Uint8Array>) -> Promise {
+        let m_ctx = self.context.clone();
+        future_to_promise(async move {
+            let ctx = m_ctx.read().await;
+            let rom = Self::assemble_rom(&ctx, &sm_rom.to_vec(), z3_rom.map(|r| r.to_vec()).as_deref()).await?;
+            Ok(JsValue::from(Uint8Array::from(rom.as_slice())))
+        })
+    }
+
+    async fn assemble_rom(ctx: &ClientContext, sm_rom: &[u8], z3_rom: Option<&[u8]>) -> Result<Vec<u8>, JsValue> {
+        let client = ctx.client.as_ref().ok_or_else(|| JsValue::from("Not registered to a session yet."))?;
+        let session = ctx.session.as_ref().ok_or_else(|| JsValue::from("Could not get session data, make sure a session is established before building a ROM"))?;
+        let seed = session.seed.as_ref().ok_or_else(|| JsValue::from("Could not get seed data from session"))?;
+
+        // Check the base ROMs before downloading anything, so a wrong ROM gets a clear error
+        let base = rom::base::BaseRom::for_game(&seed.game_id, sm_rom, z3_rom).map_err(|e| JsValue::from(e.to_string()))?;
+        let data = ctx.randomizer_service.get_patch(&client.client_token).await.map_err(|e| format!("Could not get patch data: {:?}", e.message()))?;
+        rom::build_rom(&base, &data.patch_data).map_err(|e| JsValue::from(e.to_string()))
+    }
+
+    pub fn set_install_folder(&self, folder: String) -> Promise {
+        let m_ctx = self.context.clone();
+        future_to_promise(async move {
+            let mut ctx = m_ctx.write().await;
+            ctx.install_folder = folder.trim_end_matches('/').to_string();
+            Ok(JsValue::TRUE)
+        })
+    }
+
+    // Build the ROM for this player, upload it to the install folder on the selected device and boot it.
+    // The optional progress callback is called with (bytes sent, total bytes) during the upload.
+    pub fn install_seed(&self, sm_rom: Uint8Array, z3_rom: Option<Uint8Array>, progress: Option<Function>) -> Promise {
+        let m_ctx = self.context.clone();
+        future_to_promise(async move {
+            let no_filesystem = || JsValue::from("The console connection does not support uploading files");
+            let no_control = || JsValue::from("The console connection does not support booting files");
+
+            // Only hold on to the context while building the ROM, so updates aren't held up by the upload
+            let (conn, device, install_folder, path, rom) = {
+                let ctx = m_ctx.read().await;
+                let conn = ctx.console_connection.clone().ok_or_else(|| JsValue::from("No console connection, list devices and select one first"))?;
+                if ctx.device.is_empty() {
+                    return Err(JsValue::from("No device selected"));
+                }
+
+                conn.filesystem().ok_or_else(no_filesystem)?;
+                conn.control().ok_or_else(no_control)?;
+
+                let rom = Self::assemble_rom(&ctx, &sm_rom.to_vec(), z3_rom.map(|r| r.to_vec()).as_deref()).await?;
+                let seed = ctx.session.as_ref().and_then(|s| s.seed.as_ref()).ok_or_else(|| JsValue::from("Could not get seed data from session"))?;
+                let world_id = ctx.client.as_ref().map(|c| c.world_id).unwrap_or_default();
+                let path = format!("{}/{}-{}-{}.sfc", ctx.install_folder, seed.game_id.to_lowercase(), seed.number, world_id);
+                (conn, ctx.device.to_string(), ctx.install_folder.to_string(), path, rom)
+            };
+            let fs = conn.filesystem().ok_or_else(no_filesystem)?;
+            let control = conn.control().ok_or_else(no_control)?;
+
+            // usb2snes drops the connection on errors, so only create the folder if it isn't there already
+            let (parent, name) = install_folder.rsplit_once('/').unwrap_or(("", &install_folder));
+            let parent = if parent.is_empty() { "/" } else { parent };
+            if !name.is_empty() && !fs.list_files(&device, parent).await?.iter().any(|f| f.name == name && f.file_type == FileType::Directory) {
+                fs.make_directory(&device, &install_folder).await?;
+            }
+
+            log::info!("client: Installing seed to {}", path);
+            match progress {
+                Some(callback) => {
+                    let report = |sent: usize, total: usize| { let _ = callback.call2(&JsValue::NULL, &JsValue::from(sent as u32), &JsValue::from(total as u32)); };
+                    fs.put_file_with_progress(&device, &path, &rom, &report).await?
+                },
+                None => fs.put_file(&device, &path, &rom).await?
+            }
+
+            control.boot(&device, &path).await?;
+            Ok(JsValue::from(path))
+        })
+    }
+
     pub fn list_devices(&self) -> Promise {
         let m_ctx = self.context.clone();
+        let recorder = self.recorder.clone();
         future_to_promise(async move {
             let mut ctx = m_ctx.write().await;
 
             let connection = match &ctx.console_connection {
                 Some(conn) => conn,
                 None => {
-                    let conn = Self::initialize_console_connection().await.map_err(|e| JsValue::from(format!("Could not initialize a console connection: {:?}", e)))?;
-                    ctx.console_connection = Some(conn);
+                    let conn = Self::initialize_console_connection(recorder).await.map_err(|e| JsValue::from(format!("Could not initialize a console connection: {:?}", e)))?;
+                    ctx.console_connection = Some(conn.into());
                     ctx.connected = true;
                     Message::ConsoleConnected.send(&ctx.callback,Some(&[&ctx.device]));
                     ctx.console_connection.as_ref().unwrap()
                 }
             };
 
-            let devices = connection.list_devices().await.map_err(|_| JsValue::from("Could not get device list"))?;
+            let devices = connection.list_devices().await?;
             serde_wasm_bindgen::to_value(&devices).map_err(|_| JsValue::from("Could not parse device list data"))
         })
     }
 
+    // Metrics of the console connection for showing the connection quality, null if there is no connection yet
+    pub fn connection_metrics(&self) -> Promise {
+        let m_ctx = self.context.clone();
+        future_to_promise(async move {
+            let ctx = m_ctx.read().await;
+            match ctx.console_connection.as_ref().and_then(|c| c.metrics()) {
+                Some(metrics) => serde_wasm_bindgen::to_value(&metrics.snapshot()).map_err(|_| JsValue::from("Could not parse connection metrics")),
+                None => Ok(JsValue::NULL)
+            }
+        })
+    }
+
     pub fn get_events(&self, event_types: Vec<i32>, from_event_id: Option<i32>, to_event_id: Option<i32>, from_world_id: Option<i32>, to_world_id: Option<i32>) -> Promise {
         let m_ctx = self.context.clone();
         future_to_promise(async move {
@@ -290,6 +422,16 @@ impl RandomizerClient {
             }
             
             let ctx = m_ctx.read().await;
+
+            // Refuse devices that can't do what the game clients need before doing anything else
+            if let Some(conn) = ctx.console_connection.as_ref() {
+                let devices = conn.list_devices().await?;
+                let selected = devices.iter().find(|d| d.uri == ctx.device).ok_or_else(|| JsValue::from(format!("Device {} is not available", ctx.device)))?;
+                if !selected.supports(Capability::ReadMemory) || !selected.supports(Capability::WriteMemory) {
+                    return Err(JsValue::from(format!("Device {} does not support reading and writing memory", selected.name)));
+                }
+            }
+
             let mut cli = m_cli.write().await;
             let session = ctx.session.as_ref().ok_or_else(|| JsValue::from("Could not get session data, make sure a session is established before running start"))?;
             let seed = session.seed.as_ref().ok_or_else(|| JsValue::from("Could not get seed data from session"))?;
@@ -312,59 +454,10 @@ impl RandomizerClient {
         future_to_promise(async move {
             let mut ctx = mut_ctx.write().await;            
             if let Some(cli) = client_ctx.write().await.as_mut() {
-
-                // If we're not connected, that means we got disconnected from the console during an update
-                // and we'll have to attempt to reconnect here
-
-                if !ctx.connected {
-                    Message::ConsoleReconnecting.send(&ctx.callback, None);
-                    let conn = ctx.console_connection.as_ref().ok_or_else(|| JsValue::from("Tried to reconnect, but no client available?"))?;
-                    let _ = conn.connect().await.map_err(|_| JsValue::from("Could not connect to device"))?;
-                    let devices = conn.list_devices().await.map_err(|_| JsValue::from("Could not list devices"))?;
-                    
-                    if devices.is_empty() {
-                        return Err(JsValue::from("Could get device list, but it's empty, trying again later"));
-                    } else {
-                        // Ok, there's a few devices, if the previous one we're connected to is there do nothing
-                        if !devices.iter().any(|d| d.uri == ctx.device) {
-                            // Otherwise we need to check if there's more than one, in that case we don't know what to do here
-                            if devices.len() > 1 {
-                                Message::ConsoleError.send(&ctx.callback, Some(&["Could get device list, but there's more than one device, please reconnect manually"]));
-                                return Err(JsValue::from("Could get device list, but there's more than one device"));
-                            } else {
-                                // Only one to pick from, so let's take that one
-                                ctx.device = devices[0].uri.to_string();
-                            }
-                        }
-                    }
-
-                    // ok, we're back and we got a device setup, continue as normal
-                    ctx.connected = true;
-                    Message::ConsoleConnected.send(&ctx.callback,Some(&[&ctx.device]));
-                }
-
-                match cli.update(&ctx).await {
-                    Err(e) => {
-                        if e.downcast_ref::<ConnectionError>().is_some() {                            
-                            // If we get a connection error, something bad happened to the device, we'll have to back off completely
-                            // and try to reconnect to the first available device, if there are more than one device when we try to
-                            // auto-reconnect we'll just completely bail out.
-                            {
-                                // Try to update state back to server, but don't fail out if we can't
-                                let client = ctx.client.as_ref().unwrap();
-                                let _ = &ctx.randomizer_service.update_player(&client.client_token, ClientState::Registered as i32, None).await;
-                            }
-                            Message::ConsoleDisconnected.send(&ctx.callback, None);
-                            let conn = ctx.console_connection.as_ref().ok_or_else(|| JsValue::from("Tried to reconnect, but no client available?"))?;
-                            if let Err(e) = conn.disconnect().await {
-                                log::debug!("Got error when attempting to close the connection: {:?}", e);
-                            }
-                            
-                            ctx.connected = false;
-                            Err(JsValue::from("The console connection has disconnected, attempting to reconnect"))
-                        } else {
-                            Err(JsValue::from(format!("Update error: {:?}", e)))
-                        }
+                match update_game(&mut ctx, cli).await {
+                    Err(e) => match e.downcast::<ConnectionError>() {
+                        Ok(e) => Err(JsValue::from(*e)),
+                        Err(e) => Err(JsValue::from(e.to_string()))
                     },
                     _ => Ok(JsValue::TRUE)
                 }
@@ -374,3 +467,181 @@ impl RandomizerClient {
         })
     }
 }
+
+// One update of the game client, reconnecting first if the last update lost the console connection
+async fn update_game(ctx: &mut ClientContext, cli: &mut clients::multiworld::smz3::SMZ3Client) -> Result<(), Box<dyn std::error::Error>> {
+    // If we're not connected, that means we got disconnected from the console during an update
+    // and we'll have to attempt to reconnect here
+
+    if !ctx.connected {
+        Message::ConsoleReconnecting.send(&ctx.callback, None);
+        let conn = ctx.console_connection.as_ref().ok_or("Tried to reconnect, but no client available?")?;
+        let _ = conn.connect().await?;
+        let devices = conn.list_devices().await?;
+        
+        if devices.is_empty() {
+            return Err("Could get device list, but it's empty, trying again later".into());
+        } else {
+            // Ok, there's a few devices, if the previous one we're connected to is there do nothing
+            if !devices.iter().any(|d| d.uri == ctx.device) {
+                // Otherwise we need to check if there's more than one, in that case we don't know what to do here
+                if devices.len() > 1 {
+                    Message::ConsoleError.send(&ctx.callback, Some(&["Could get device list, but there's more than one device, please reconnect manually"]));
+                    return Err("Could get device list, but there's more than one device".into());
+                } else {
+                    // Only one to pick from, so let's take that one
+                    ctx.device = devices[0].uri.to_string();
+                }
+            }
+        }
+
+        // ok, we're back and we got a device setup, continue as normal
+        ctx.connected = true;
+        Message::ConsoleConnected.send(&ctx.callback,Some(&[&ctx.device]));
+    }
+
+    match cli.update(ctx).await {
+        Err(e) => match e.downcast::<ConnectionError>() {
+            Ok(e) if e.requires_reconnect() => {
+                // If we get a connection error, something bad happened to the device, we'll have to back off completely
+                // and try to reconnect to the first available device, if there are more than one device when we try to
+                // auto-reconnect we'll just completely bail out.
+                log::debug!("client: Connection error during update: {}", e);
+                {
+                    // Try to update state back to server, but don't fail out if we can't
+                    let client = ctx.client.as_ref().unwrap();
+                    let _ = &ctx.randomizer_service.update_player(&client.client_token, ClientState::Registered as i32, None).await;
+                }
+                Message::ConsoleDisconnected.send(&ctx.callback, None);
+                let conn = ctx.console_connection.as_ref().ok_or("Tried to reconnect, but no client available?")?;
+                if let Err(e) = conn.disconnect().await {
+                    log::debug!("Got error when attempting to close the connection: {:?}", e);
+                }
+                
+                ctx.connected = false;
+                Err(e)
+            },
+            // Errors that don't affect the connection itself are passed on as is
+            Ok(e) => Err(e),
+            Err(e) => Err(format!("Update error: {:?}", e).into())
+        },
+        _ => Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use console_interface::protocols::mock::{Fault, MockConnection};
+    use console_interface::protocols::recording::{Call, Outcome, TraceEntry, TracePlayer, TRACE_VERSION};
+    use futures::executor::block_on;
+    use serde::Serialize;
+    use services::randomizer::*;
+
+    pub(crate) const DEVICE: &str = "mock";
+    pub(crate) const SESSION_GUID: &str = "0123456789abcdef0123456789abcdef";
+    pub(crate) const WORLD_GUID: &str = "fedcba9876543210fedcba9876543210";
+    pub(crate) const TOKEN: &str = "token";
+    const SEED_DATA: u32 = 0xE046A0;
+
+    pub(crate) fn entry(call: Call, value: impl Serialize) -> TraceEntry {
+        TraceEntry { at_ms: 0.0, duration_ms: 0.0, call, outcome: Outcome::Ok { value: serde_json::to_value(value).unwrap() } }
+    }
+
+    pub(crate) fn service(method: &str, request: impl Serialize, response: impl Serialize) -> TraceEntry {
+        entry(Call::Service { method: method.into(), request: serde_json::to_value(request).unwrap() }, response)
+    }
+
+    // SRAM identifiers of the seed and world the test session is playing
+    pub(crate) fn seed_data() -> Vec<u8> {
+        let mut data = vec![0; 0x50];
+        data[0x10..0x30].copy_from_slice(SESSION_GUID.as_bytes());
+        data[0x30..0x50].copy_from_slice(WORLD_GUID.as_bytes());
+        data
+    }
+
+    // A registered player in world 1 of an SMZ3 multiworld session, with messages going nowhere
+    pub(crate) fn context(connection: Box<dyn protocol::Connection>, randomizer_service: RandomizerService) -> ClientContext {
+        ClientContext {
+            console_connection: Some(connection.into()),
+            randomizer_service,
+            session: Some(GetSessionResponse {
+                guid: SESSION_GUID.into(),
+                seed: Some(Seed {
+                    game_id: "smz3".into(),
+                    game_mode: "multiworld".into(),
+                    worlds: vec![World { guid: WORLD_GUID.into(), world_id: 1, ..Default::default() }],
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            client: Some(RegisterPlayerResponse { client_token: TOKEN.into(), world_id: 1, ..Default::default() }),
+            device: DEVICE.into(),
+            connected: true,
+            session_guid: SESSION_GUID.into(),
+            install_folder: String::new(),
+            callback: Callback(None)
+        }
+    }
+
+    fn update_player(client_state: ClientState, device_name: Option<&str>) -> TraceEntry {
+        let request = UpdatePlayerRequest { client_token: TOKEN.into(), client_state: client_state as i32, device_name: device_name.map(String::from) };
+        service("update_player", request, UpdatePlayerResponse { success: true })
+    }
+
+    fn replay(entries: Vec<TraceEntry>) -> (RandomizerService, Arc<TracePlayer>) {
+        let player = Arc::new(TracePlayer::new(Trace { version: TRACE_VERSION, entries }));
+        (RandomizerService::replay(player.clone()), player)
+    }
+
+    #[test]
+    fn reconnects_after_losing_the_console() {
+        let mock = MockConnection::new();
+        mock.poke(DEVICE, SEED_DATA, &seed_data()).unwrap();
+        let (service, player) = replay(vec![update_player(ClientState::Registered, None), update_player(ClientState::Ready, Some(DEVICE))]);
+        let mut ctx = context(Box::new(mock.clone()), service);
+        let mut cli = clients::multiworld::smz3::SMZ3Client::new();
+
+        block_on(update_game(&mut ctx, &mut cli)).unwrap();
+
+        // The seed check is the first thing that touches the console
+        mock.inject_fault(Fault::Disconnect { after: 0 });
+        let error = block_on(update_game(&mut ctx, &mut cli)).unwrap_err();
+        assert!(matches!(error.downcast_ref::<ConnectionError>(), Some(ConnectionError::Disconnected(_))));
+        assert!(!ctx.connected);
+        assert!(!mock.is_connected());
+        assert_eq!(player.remaining(), 1);
+
+        block_on(update_game(&mut ctx, &mut cli)).unwrap();
+        assert!(ctx.connected);
+        assert!(mock.is_connected());
+        assert_eq!(ctx.device, DEVICE);
+        assert_eq!(player.remaining(), 0);
+    }
+
+    #[test]
+    fn reconnect_picks_the_only_device_left() {
+        let mock = MockConnection::with_devices(&["other"]);
+        let (service, _) = replay(vec![]);
+        let mut ctx = context(Box::new(mock.clone()), service);
+        ctx.connected = false;
+        let mut cli = clients::multiworld::smz3::SMZ3Client::new();
+
+        block_on(update_game(&mut ctx, &mut cli)).unwrap();
+        assert!(ctx.connected);
+        assert_eq!(ctx.device, "other");
+    }
+
+    #[test]
+    fn reconnect_gives_up_with_several_devices() {
+        let mock = MockConnection::with_devices(&["first", "second"]);
+        let (service, _) = replay(vec![]);
+        let mut ctx = context(Box::new(mock.clone()), service);
+        ctx.connected = false;
+        let mut cli = clients::multiworld::smz3::SMZ3Client::new();
+
+        assert!(block_on(update_game(&mut ctx, &mut cli)).is_err());
+        assert!(!ctx.connected);
+        assert_eq!(ctx.device, DEVICE);
+    }
+}
@@ -0,0 +1,159 @@
+use sha1::{Digest, Sha1};
+use crate::rom::RomError;
+
+// Copiers put a 512 byte header in front of the ROM, which shows up as an odd size
+const COPIER_HEADER_SIZE: usize = 0x200;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Game {
+    SuperMetroid,
+    LinkToThePast,
+    SMZ3
+}
+
+struct KnownRom {
+    game: Game,
+    name: &'static str,
+    size: usize,
+    crc32: u32,
+    sha1: &'static str
+}
+
+// The randomizer patches are made against these exact versions
+const KNOWN_ROMS: &[KnownRom] = &[
+    KnownRom { game: Game::SuperMetroid, name: "Super Metroid (Japan, USA)", size: 0x30_0000, crc32: 0xD63ED5F8, sha1: "da957f0d63d14cb441d215462904c4fa8519c613" },
+    KnownRom { game: Game::LinkToThePast, name: "Zelda no Densetsu: Kamigami no Triforce (Japan) v1.0", size: 0x10_0000, crc32: 0x3322EFFC, sha1: "6d4f10a8b10e10dbe624cb23cf03b88bb8252973" },
+];
+
+// A base ROM that has been checked against the known good dumps, and is ready to be patched
+#[derive(Debug, Clone)]
+pub struct BaseRom {
+    game: Game,
+    data: Vec<u8>
+}
+
+impl BaseRom {
+    pub fn super_metroid(data: &[u8]) -> Result<Self, RomError> {
+        Self::verify(Game::SuperMetroid, data)
+    }
+
+    pub fn link_to_the_past(data: &[u8]) -> Result<Self, RomError> {
+        Self::verify(Game::LinkToThePast, data)
+    }
+
+    // Combine both games into the ExHiROM layout the SMZ3 patches expect. Super Metroid goes in the upper half
+    // of the first 64 banks and then the lower half of the first 32, ALttP in the upper half of the banks at 4MB.
+    pub fn smz3(sm: &BaseRom, z3: &BaseRom) -> Result<Self, RomError> {
+        if sm.game != Game::SuperMetroid || z3.game != Game::LinkToThePast {
+            return Err(RomError::InvalidBaseRom("SMZ3 needs a Super Metroid and an ALttP ROM".into()));
+        }
+
+        let mut data = vec![0u8; 0x60_0000];
+        let (sm_hi, sm_lo) = sm.data.split_at(0x40 * 0x8000);
+        for (i, chunk) in sm_hi.chunks(0x8000).enumerate() {
+            data[i * 0x1_0000 + 0x8000..(i + 1) * 0x1_0000].copy_from_slice(chunk);
+        }
+        for (i, chunk) in sm_lo.chunks(0x8000).enumerate() {
+            data[i * 0x1_0000..i * 0x1_0000 + 0x8000].copy_from_slice(chunk);
+        }
+        for (i, chunk) in z3.data.chunks(0x8000).enumerate() {
+            data[0x40_0000 + i * 0x1_0000 + 0x8000..0x40_0000 + (i + 1) * 0x1_0000].copy_from_slice(chunk);
+        }
+
+        Ok(Self { game: Game::SMZ3, data })
+    }
+
+    // Build the base ROM needed for a seed from the game id in the session
+    pub fn for_game(game_id: &str, sm: &[u8], z3: Option<&[u8]>) -> Result<Self, RomError> {
+        match game_id.to_lowercase().as_str() {
+            "smz3" => {
+                let z3 = z3.ok_or_else(|| RomError::InvalidBaseRom("SMZ3 needs an ALttP ROM as well".into()))?;
+                Self::smz3(&Self::super_metroid(sm)?, &Self::link_to_the_past(z3)?)
+            },
+            "sm" => Self::super_metroid(sm),
+            game => Err(RomError::InvalidBaseRom(format!("Unknown game {}", game)))
+        }
+    }
+
+    fn verify(game: Game, data: &[u8]) -> Result<Self, RomError> {
+        let known = KNOWN_ROMS.iter().find(|k| k.game == game).expect("Every game has a known ROM");
+        let data = if data.len() % 0x400 == COPIER_HEADER_SIZE { &data[COPIER_HEADER_SIZE..] } else { data };
+
+        if data.len() != known.size {
+            return Err(RomError::InvalidBaseRom(format!("{} should be {} bytes, got {}", known.name, known.size, data.len())));
+        }
+
+        let crc32 = crc32fast::hash(data);
+        if crc32 != known.crc32 {
+            return Err(RomError::InvalidBaseRom(format!("{} should have CRC32 {:08X}, got {:08X}", known.name, known.crc32, crc32)));
+        }
+
+        let sha1: String = Sha1::digest(data).iter().map(|b| format!("{:02x}", b)).collect();
+        if sha1 != known.sha1 {
+            return Err(RomError::InvalidBaseRom(format!("{} should have SHA-1 {}, got {}", known.name, known.sha1, sha1)));
+        }
+
+        Ok(Self { game, data: data.to_vec() })
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every bank of the fixture starts with the game and the bank number, so it can be found again in the layout
+    fn fixture(game: Game, size: usize) -> BaseRom {
+        let mut data = vec![0; size];
+        for (i, bank) in data.chunks_mut(0x8000).enumerate() {
+            bank[0] = game as u8;
+            bank[1] = i as u8;
+        }
+        BaseRom { game, data }
+    }
+
+    fn bank_at(rom: &BaseRom, offset: usize) -> (u8, u8) {
+        (rom.data[offset], rom.data[offset + 1])
+    }
+
+    #[test]
+    fn smz3_exhirom_layout() {
+        let sm = fixture(Game::SuperMetroid, 0x30_0000);
+        let z3 = fixture(Game::LinkToThePast, 0x10_0000);
+        let smz3 = BaseRom::smz3(&sm, &z3).unwrap();
+        let (sm_id, z3_id) = (Game::SuperMetroid as u8, Game::LinkToThePast as u8);
+
+        assert_eq!(smz3.data().len(), 0x60_0000);
+        assert_eq!(bank_at(&smz3, 0x00_8000), (sm_id, 0));
+        assert_eq!(bank_at(&smz3, 0x3F_8000), (sm_id, 0x3F));
+        assert_eq!(bank_at(&smz3, 0x00_0000), (sm_id, 0x40));
+        assert_eq!(bank_at(&smz3, 0x1F_0000), (sm_id, 0x5F));
+        assert_eq!(bank_at(&smz3, 0x40_8000), (z3_id, 0));
+        assert_eq!(bank_at(&smz3, 0x5F_8000), (z3_id, 0x1F));
+        // The lower halves of the ALttP banks and of the second half of the Super Metroid banks are left empty
+        assert_eq!(bank_at(&smz3, 0x20_0000), (0, 0));
+        assert_eq!(bank_at(&smz3, 0x40_0000), (0, 0));
+    }
+
+    #[test]
+    fn smz3_needs_both_games() {
+        let sm = fixture(Game::SuperMetroid, 0x30_0000);
+        let z3 = fixture(Game::LinkToThePast, 0x10_0000);
+        assert!(matches!(BaseRom::smz3(&z3, &sm), Err(RomError::InvalidBaseRom(_))));
+        assert!(matches!(BaseRom::for_game("smz3", sm.data(), None), Err(RomError::InvalidBaseRom(_))));
+        assert!(matches!(BaseRom::for_game("metroid prime", sm.data(), None), Err(RomError::InvalidBaseRom(_))));
+    }
+
+    #[test]
+    fn only_the_known_dumps_are_accepted() {
+        let error = BaseRom::super_metroid(&[0; 0x20_0000]).unwrap_err().to_string();
+        assert!(error.contains("bytes"), "{}", error);
+
+        // A copier header is skipped, so it gets as far as the checksum
+        let error = BaseRom::super_metroid(&vec![0; 0x30_0000 + COPIER_HEADER_SIZE]).unwrap_err().to_string();
+        assert!(error.contains("CRC32"), "{}", error);
+    }
+}
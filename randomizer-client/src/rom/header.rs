@@ -0,0 +1,158 @@
+use crate::rom::RomError;
+
+// Internal SNES header, found at the end of the first bank the CPU sees at $00:FFC0
+const HEADER_SIZE: usize = 0x40;
+const MAP_MODE: usize = 0x15;
+const COMPLEMENT: usize = 0x1C;
+const CHECKSUM: usize = 0x1E;
+const RESET_VECTOR: usize = 0x3C;
+
+// Largest ROM the FxPakPro can map
+pub const MAX_ROM_SIZE: usize = 0x80_0000;
+
+// Header locations in file order of preference, with the map mode expected there
+const HEADER_LOCATIONS: &[(usize, u8)] = &[
+    (0x40_FFC0, 0x05), // ExHiROM
+    (0x00_FFC0, 0x01), // HiROM
+    (0x00_7FC0, 0x00), // LoROM
+];
+
+// Find the internal header by looking for a plausible map mode and reset vector, the checksum can't be used
+// for this since it's usually wrong right after patching
+pub fn find_header(rom: &[u8]) -> Option<usize> {
+    HEADER_LOCATIONS.iter()
+        .filter(|(offset, _)| rom.len() >= offset + HEADER_SIZE)
+        .find(|(offset, mode)| {
+            let header = &rom[*offset..offset + HEADER_SIZE];
+            let map_mode = header[MAP_MODE];
+            let reset = u16::from_le_bytes([header[RESET_VECTOR], header[RESET_VECTOR + 1]]);
+            map_mode & 0xE0 == 0x20 && map_mode & 0x0F == *mode && reset >= 0x8000
+        })
+        .map(|(offset, _)| *offset)
+}
+
+fn sum(data: &[u8]) -> u32 {
+    data.iter().fold(0u32, |acc, b| acc.wrapping_add(*b as u32))
+}
+
+// Sum of the data mirrored to fill size bytes, which is how carts with sizes that aren't a power of two
+// show up to the console, e.g. a 3MB ROM is the first 2MB followed by the last 1MB twice
+fn mirrored_sum(data: &[u8], size: usize) -> u32 {
+    let part = 1 << (usize::BITS - 1 - data.len().leading_zeros());
+    let (block, block_size) = if part == data.len() {
+        (sum(data), part)
+    } else {
+        (sum(&data[..part]).wrapping_add(mirrored_sum(&data[part..], part)), part * 2)
+    };
+    block.wrapping_mul((size / block_size) as u32)
+}
+
+pub fn checksum(rom: &[u8]) -> u16 {
+    if rom.is_empty() {
+        return 0;
+    }
+    mirrored_sum(rom, rom.len().next_power_of_two()) as u16
+}
+
+// Recalculate the header checksum and its complement after the ROM contents changed
+pub fn fix_checksum(rom: &mut [u8]) -> Result<(), RomError> {
+    let header = find_header(rom).ok_or_else(|| RomError::InvalidRom("Could not find the ROM header".into()))?;
+
+    // The checksum is calculated with the checksum fields holding a matching pair, which always adds up the same
+    rom[header + COMPLEMENT..header + COMPLEMENT + 4].copy_from_slice(&[0xFF, 0xFF, 0x00, 0x00]);
+    let checksum = checksum(rom);
+    rom[header + COMPLEMENT..header + COMPLEMENT + 2].copy_from_slice(&(!checksum).to_le_bytes());
+    rom[header + CHECKSUM..header + CHECKSUM + 2].copy_from_slice(&checksum.to_le_bytes());
+    Ok(())
+}
+
+pub fn validate(rom: &[u8]) -> Result<(), RomError> {
+    if rom.is_empty() || rom.len() & 0x7FFF != 0 {
+        return Err(RomError::InvalidRom(format!("Size {:X} is not a whole number of banks", rom.len())));
+    }
+
+    if rom.len() > MAX_ROM_SIZE {
+        return Err(RomError::InvalidRom(format!("Size {:X} is larger than the maximum of {:X}", rom.len(), MAX_ROM_SIZE)));
+    }
+
+    let header = find_header(rom).ok_or_else(|| RomError::InvalidRom("Could not find the ROM header".into()))?;
+    let stored = u16::from_le_bytes([rom[header + CHECKSUM], rom[header + CHECKSUM + 1]]);
+    let complement = u16::from_le_bytes([rom[header + COMPLEMENT], rom[header + COMPLEMENT + 1]]);
+    if stored ^ complement != 0xFFFF || stored != checksum(rom) {
+        return Err(RomError::InvalidRom(format!("Header checksum {:04X} does not match the contents", stored)));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A ROM of the given size with a header at offset, filled with something that doesn't add up to zero
+    fn rom(size: usize, header: usize, mode: u8) -> Vec<u8> {
+        let mut rom: Vec<u8> = (0..size).map(|i| (i ^ (i >> 9) ^ (i >> 17)) as u8).collect();
+        rom[header + MAP_MODE] = 0x30 | mode;
+        rom[header + RESET_VECTOR..header + RESET_VECTOR + 2].copy_from_slice(&0x8000u16.to_le_bytes());
+        rom
+    }
+
+    #[test]
+    fn finds_the_header_for_every_mapping() {
+        assert_eq!(find_header(&rom(0x60_0000, 0x40_FFC0, 0x05)), Some(0x40_FFC0));
+        assert_eq!(find_header(&rom(0x40_0000, 0x00_FFC0, 0x01)), Some(0x00_FFC0));
+        assert_eq!(find_header(&rom(0x10_0000, 0x00_7FC0, 0x00)), Some(0x00_7FC0));
+
+        // A LoROM map mode where a HiROM header should be doesn't count
+        assert_eq!(find_header(&rom(0x40_0000, 0x00_FFC0, 0x00)).filter(|h| *h == 0x00_FFC0), None);
+    }
+
+    #[test]
+    fn checksum_of_a_power_of_two_is_the_plain_sum() {
+        let rom = rom(0x10_0000, 0x00_7FC0, 0x00);
+        assert_eq!(checksum(&rom), sum(&rom) as u16);
+    }
+
+    #[test]
+    fn checksum_mirrors_the_last_part_of_a_3mb_rom() {
+        let rom = rom(0x30_0000, 0x00_7FC0, 0x00);
+        let expected = sum(&rom[..0x20_0000]).wrapping_add(sum(&rom[0x20_0000..]).wrapping_mul(2));
+        assert_eq!(checksum(&rom), expected as u16);
+    }
+
+    #[test]
+    fn fixes_the_checksum_of_the_6mb_exhirom_layout() {
+        let mut rom = rom(0x60_0000, 0x40_FFC0, 0x05);
+        fix_checksum(&mut rom).unwrap();
+
+        // The last 2MB show up twice to fill the 8MB the console sees
+        let header = 0x40_FFC0;
+        let stored = u16::from_le_bytes([rom[header + CHECKSUM], rom[header + CHECKSUM + 1]]);
+        let complement = u16::from_le_bytes([rom[header + COMPLEMENT], rom[header + COMPLEMENT + 1]]);
+        let expected = sum(&rom[..0x40_0000]).wrapping_add(sum(&rom[0x40_0000..]).wrapping_mul(2));
+        assert_eq!(stored, expected as u16);
+        assert_eq!(stored ^ complement, 0xFFFF);
+        assert!(validate(&rom).is_ok());
+
+        // Fixing it again doesn't change anything
+        let fixed = rom.clone();
+        fix_checksum(&mut rom).unwrap();
+        assert_eq!(rom, fixed);
+    }
+
+    #[test]
+    fn validate_refuses_unusable_roms() {
+        let mut rom = rom(0x10_0000, 0x00_7FC0, 0x00);
+        fix_checksum(&mut rom).unwrap();
+        assert!(validate(&rom).is_ok());
+
+        assert!(validate(&rom[..0x10_0000 - 0x200]).is_err());
+        rom[0] = rom[0].wrapping_add(1);
+        assert!(validate(&rom).is_err());
+
+        let mut large = vec![0; MAX_ROM_SIZE + 0x8000];
+        large[..0x10_0000].copy_from_slice(&rom);
+        assert!(validate(&large).is_err());
+        assert!(fix_checksum(&mut [0; 0x8000]).is_err());
+    }
+}
@@ -0,0 +1,40 @@
+// Everything needed to turn a downloaded seed into a playable ROM
+pub mod base;
+pub mod header;
+pub mod patch;
+
+use core::fmt;
+use base::BaseRom;
+
+#[derive(Debug)]
+pub enum RomError {
+    // The patch data is malformed or in a format we don't know about
+    InvalidPatch(String),
+    // The player supplied ROM is not the expected version of the game
+    InvalidBaseRom(String),
+    // The patch was made for a different base ROM
+    BaseMismatch(String),
+    // The patched ROM doesn't look like something a SNES can run
+    InvalidRom(String)
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RomError::InvalidPatch(m) => write!(f, "Invalid patch: {}", m),
+            RomError::InvalidBaseRom(m) => write!(f, "Invalid base ROM: {}", m),
+            RomError::BaseMismatch(m) => write!(f, "Base ROM does not match the patch: {}", m),
+            RomError::InvalidRom(m) => write!(f, "Invalid ROM: {}", m)
+        }
+    }
+}
+
+impl std::error::Error for RomError {}
+
+// Apply a seed patch to the base ROM, fix up the header checksum and make sure the result is usable
+pub fn build_rom(base: &BaseRom, patch_data: &[u8]) -> Result<Vec<u8>, RomError> {
+    let mut rom = patch::apply(base.data(), patch_data)?;
+    header::fix_checksum(&mut rom)?;
+    header::validate(&rom)?;
+    Ok(rom)
+}
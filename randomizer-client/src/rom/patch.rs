@@ -0,0 +1,373 @@
+use std::convert::TryFrom;
+use crate::rom::RomError;
+use crate::rom::header::MAX_ROM_SIZE;
+
+// Patch formats served by the randomizer, detected from the magic at the start of the patch
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+const BPS_MAGIC: &[u8] = b"BPS1";
+
+pub fn apply(base: &[u8], patch: &[u8]) -> Result<Vec<u8>, RomError> {
+    if patch.starts_with(IPS_MAGIC) {
+        apply_ips(base, patch)
+    } else if patch.starts_with(BPS_MAGIC) {
+        apply_bps(base, patch)
+    } else {
+        Err(RomError::InvalidPatch("Unknown patch format".into()))
+    }
+}
+
+fn truncated() -> RomError {
+    RomError::InvalidPatch("Patch data ends unexpectedly".into())
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], RomError> {
+        let end = self.pos.checked_add(len).ok_or_else(truncated)?;
+        let bytes = self.data.get(self.pos..end).ok_or_else(truncated)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, RomError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    // Big endian integer, as used by IPS
+    fn be(&mut self, len: usize) -> Result<usize, RomError> {
+        Ok(self.bytes(len)?.iter().fold(0, |acc, b| (acc << 8) | *b as usize))
+    }
+
+    // BPS variable length integer, where every continuation also adds one to remove redundant encodings
+    fn varint(&mut self) -> Result<usize, RomError> {
+        let mut value: usize = 0;
+        let mut shift: usize = 1;
+        loop {
+            let x = self.byte()?;
+            value = ((x & 0x7F) as usize).checked_mul(shift).and_then(|v| v.checked_add(value)).ok_or_else(|| RomError::InvalidPatch("Number out of range".into()))?;
+            if x & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift.checked_shl(7).filter(|s| *s != 0).ok_or_else(|| RomError::InvalidPatch("Number out of range".into()))?;
+            value += shift;
+        }
+    }
+}
+
+fn apply_ips(base: &[u8], patch: &[u8]) -> Result<Vec<u8>, RomError> {
+    let mut rom = base.to_vec();
+    let mut reader = Reader { data: patch, pos: IPS_MAGIC.len() };
+
+    loop {
+        if reader.data[reader.pos..].starts_with(IPS_EOF) {
+            reader.pos += IPS_EOF.len();
+            break;
+        }
+
+        let offset = reader.be(3)?;
+        let size = reader.be(2)?;
+
+        // A zero size record is run-length encoded
+        let (size, data) = match size {
+            0 => {
+                let size = reader.be(2)?;
+                (size, None)
+            },
+            size => (size, Some(reader.bytes(size)?))
+        };
+
+        // The offset and size come from the patch, so don't grow the ROM past what a ROM can be
+        if offset + size > MAX_ROM_SIZE {
+            return Err(RomError::InvalidPatch(format!("Patched ROM would be {} bytes, more than the maximum of {}", offset + size, MAX_ROM_SIZE)));
+        }
+
+        if rom.len() < offset + size {
+            rom.resize(offset + size, 0);
+        }
+
+        match data {
+            Some(data) => rom[offset..offset + size].copy_from_slice(data),
+            None => {
+                let value = reader.byte()?;
+                rom[offset..offset + size].iter_mut().for_each(|b| *b = value);
+            }
+        }
+    }
+
+    // Some patches add the final size of the file after the EOF marker
+    if patch.len() - reader.pos == 3 {
+        let size = reader.be(3)?;
+        rom.truncate(size);
+    }
+
+    Ok(rom)
+}
+
+fn apply_bps(base: &[u8], patch: &[u8]) -> Result<Vec<u8>, RomError> {
+    if patch.len() < BPS_MAGIC.len() + 12 {
+        return Err(truncated());
+    }
+
+    let footer = patch.len() - 12;
+    let crc = |offset: usize| u32::from_le_bytes([patch[offset], patch[offset + 1], patch[offset + 2], patch[offset + 3]]);
+    let (source_crc, target_crc, patch_crc) = (crc(footer), crc(footer + 4), crc(footer + 8));
+
+    if crc32fast::hash(&patch[..footer + 8]) != patch_crc {
+        return Err(RomError::InvalidPatch("Patch checksum does not match, the patch is corrupt".into()));
+    }
+
+    if crc32fast::hash(base) != source_crc {
+        return Err(RomError::BaseMismatch(format!("Expected CRC32 {:08X}, got {:08X}", source_crc, crc32fast::hash(base))));
+    }
+
+    let mut reader = Reader { data: &patch[..footer], pos: BPS_MAGIC.len() };
+    let source_size = reader.varint()?;
+    let target_size = reader.varint()?;
+    let metadata_size = reader.varint()?;
+    reader.bytes(metadata_size)?;
+
+    if source_size != base.len() {
+        return Err(RomError::BaseMismatch(format!("Expected {} bytes, got {}", source_size, base.len())));
+    }
+
+    // The size comes from the patch, so don't trust it with an allocation before checking it
+    if target_size > MAX_ROM_SIZE {
+        return Err(RomError::InvalidPatch(format!("Patched ROM would be {} bytes, more than the maximum of {}", target_size, MAX_ROM_SIZE)));
+    }
+
+    let invalid = || RomError::InvalidPatch("Patch refers to data outside of the ROM".into());
+    let source = |start: usize, length: usize| start.checked_add(length).and_then(|end| base.get(start..end)).ok_or_else(invalid);
+    let mut target = Vec::with_capacity(target_size);
+    let mut source_offset: isize = 0;
+    let mut target_offset: isize = 0;
+
+    while reader.pos < footer {
+        let data = reader.varint()?;
+        let length = (data >> 2) + 1;
+        if target.len() + length > target_size {
+            return Err(invalid());
+        }
+
+        match data & 3 {
+            // SourceRead
+            0 => {
+                target.extend_from_slice(source(target.len(), length)?);
+            },
+            // TargetRead
+            1 => target.extend_from_slice(reader.bytes(length)?),
+            // SourceCopy and TargetCopy, relative to the last position used by the same command
+            command => {
+                let offset = reader.varint()?;
+                let delta = if offset & 1 != 0 { -((offset >> 1) as isize) } else { (offset >> 1) as isize };
+                if command == 2 {
+                    source_offset += delta;
+                    let start = usize::try_from(source_offset).map_err(|_| invalid())?;
+                    target.extend_from_slice(source(start, length)?);
+                    source_offset += length as isize;
+                } else {
+                    target_offset += delta;
+                    // The copy can overlap the bytes it is writing, so it has to go one byte at a time
+                    for _ in 0..length {
+                        let byte = *usize::try_from(target_offset).ok().and_then(|o| target.get(o)).ok_or_else(invalid)?;
+                        target.push(byte);
+                        target_offset += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    if target.len() != target_size {
+        return Err(RomError::InvalidPatch(format!("Patch produced {} bytes, expected {}", target.len(), target_size)));
+    }
+
+    if crc32fast::hash(&target) != target_crc {
+        return Err(RomError::InvalidPatch("Patched ROM checksum does not match".into()));
+    }
+
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ips(records: &[(usize, &[u8])], rle: &[(usize, u16, u8)], truncate: Option<usize>) -> Vec<u8> {
+        let mut patch = IPS_MAGIC.to_vec();
+        for (offset, data) in records {
+            patch.extend_from_slice(&offset.to_be_bytes()[5..]);
+            patch.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            patch.extend_from_slice(data);
+        }
+        for (offset, size, value) in rle {
+            patch.extend_from_slice(&offset.to_be_bytes()[5..]);
+            patch.extend_from_slice(&[0, 0]);
+            patch.extend_from_slice(&size.to_be_bytes());
+            patch.push(*value);
+        }
+        patch.extend_from_slice(IPS_EOF);
+        if let Some(size) = truncate {
+            patch.extend_from_slice(&size.to_be_bytes()[5..]);
+        }
+        patch
+    }
+
+    fn varint(mut value: usize, out: &mut Vec<u8>) {
+        loop {
+            let x = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                out.push(0x80 | x);
+                return;
+            }
+            out.push(x);
+            value -= 1;
+        }
+    }
+
+    enum Command<'a> {
+        SourceRead(usize),
+        TargetRead(&'a [u8]),
+        SourceCopy(usize, isize),
+        TargetCopy(usize, isize)
+    }
+
+    fn bps(base: &[u8], target: &[u8], target_size: usize, commands: &[Command]) -> Vec<u8> {
+        let mut patch = BPS_MAGIC.to_vec();
+        varint(base.len(), &mut patch);
+        varint(target_size, &mut patch);
+        varint(4, &mut patch);
+        patch.extend_from_slice(b"meta");
+
+        let offset = |delta: isize| ((delta.unsigned_abs()) << 1) | (delta < 0) as usize;
+        for command in commands {
+            match command {
+                Command::SourceRead(length) => varint((length - 1) << 2, &mut patch),
+                Command::TargetRead(data) => {
+                    varint(((data.len() - 1) << 2) | 1, &mut patch);
+                    patch.extend_from_slice(data);
+                },
+                Command::SourceCopy(length, delta) => {
+                    varint(((length - 1) << 2) | 2, &mut patch);
+                    varint(offset(*delta), &mut patch);
+                },
+                Command::TargetCopy(length, delta) => {
+                    varint(((length - 1) << 2) | 3, &mut patch);
+                    varint(offset(*delta), &mut patch);
+                }
+            }
+        }
+
+        patch.extend_from_slice(&crc32fast::hash(base).to_le_bytes());
+        patch.extend_from_slice(&crc32fast::hash(target).to_le_bytes());
+        let crc = crc32fast::hash(&patch);
+        patch.extend_from_slice(&crc.to_le_bytes());
+        patch
+    }
+
+    fn invalid(result: Result<Vec<u8>, RomError>) -> bool {
+        matches!(result, Err(RomError::InvalidPatch(_)))
+    }
+
+    #[test]
+    fn unknown_formats_are_refused() {
+        assert!(invalid(apply(&[0; 4], b"UPS1")));
+    }
+
+    #[test]
+    fn ips_records_and_runs() {
+        let base: Vec<u8> = (0..16).collect();
+        let patch = ips(&[(2, &[0xAA, 0xBB])], &[(8, 4, 0xCC), (18, 2, 0xDD)], None);
+        let rom = apply(&base, &patch).unwrap();
+        assert_eq!(rom, vec![0, 1, 0xAA, 0xBB, 4, 5, 6, 7, 0xCC, 0xCC, 0xCC, 0xCC, 12, 13, 14, 15, 0, 0, 0xDD, 0xDD]);
+    }
+
+    #[test]
+    fn ips_size_after_eof_truncates() {
+        let base: Vec<u8> = (0..16).collect();
+        let rom = apply(&base, &ips(&[(0, &[9])], &[], Some(8))).unwrap();
+        assert_eq!(rom, vec![9, 1, 2, 3, 4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn truncated_ips_patches_are_refused() {
+        let base = vec![0; 16];
+        let patch = ips(&[(2, &[0xAA, 0xBB, 0xCC])], &[(8, 4, 0xCC)], None);
+        // Cut off in the record data, in the run value and right before the EOF marker
+        for cut in [12, patch.len() - 4, patch.len() - 3] {
+            assert!(invalid(apply(&base, &patch[..cut])), "cut at {}", cut);
+        }
+    }
+
+    #[test]
+    fn ips_records_past_the_maximum_size_are_refused() {
+        let base = vec![0; 16];
+        assert!(invalid(apply(&base, &ips(&[(MAX_ROM_SIZE - 1, &[1, 2])], &[], None))));
+        assert!(invalid(apply(&base, &ips(&[], &[(0xFF_FFF0, 0xFFFF, 0xCC)], None))));
+        assert_eq!(apply(&base, &ips(&[(MAX_ROM_SIZE - 1, &[1])], &[], None)).unwrap().len(), MAX_ROM_SIZE);
+    }
+
+    #[test]
+    fn bps_commands() {
+        let base: Vec<u8> = (0..32).collect();
+        let mut target = base[..8].to_vec();
+        target.extend_from_slice(&base[20..24]);
+        target.extend_from_slice(&base[4..6]);
+        target.extend_from_slice(b"new");
+        // Copying from just behind the end of the target repeats what is there
+        target.extend_from_slice(b"newnewn");
+        let patch = bps(&base, &target, target.len(), &[
+            Command::SourceRead(8),
+            Command::SourceCopy(4, 20),
+            Command::SourceCopy(2, -20),
+            Command::TargetRead(b"new"),
+            Command::TargetCopy(7, 14)
+        ]);
+
+        assert_eq!(apply(&base, &patch).unwrap(), target);
+    }
+
+    #[test]
+    fn bps_checks_the_base_and_the_patch() {
+        let base: Vec<u8> = (0..32).collect();
+        let patch = bps(&base, &base, base.len(), &[Command::SourceRead(32)]);
+
+        assert!(matches!(apply(&base[..31], &patch), Err(RomError::BaseMismatch(_))));
+
+        let mut corrupt = patch.clone();
+        corrupt[8] ^= 1;
+        assert!(invalid(apply(&base, &corrupt)));
+    }
+
+    #[test]
+    fn bps_refuses_reads_outside_the_rom() {
+        let base: Vec<u8> = (0..32).collect();
+        let target = vec![0; 8];
+        assert!(invalid(apply(&base, &bps(&base, &target, 8, &[Command::SourceCopy(8, 30)]))));
+        assert!(invalid(apply(&base, &bps(&base, &target, 8, &[Command::SourceCopy(8, -1)]))));
+        assert!(invalid(apply(&base, &bps(&base, &target, 8, &[Command::TargetRead(&[0]), Command::TargetCopy(7, 1)]))));
+        assert!(invalid(apply(&base, &bps(&base, &target, 4, &[Command::TargetRead(&target)]))));
+    }
+
+    #[test]
+    fn bps_sizes_are_checked_before_they_are_used() {
+        let base: Vec<u8> = (0..32).collect();
+        assert!(invalid(apply(&base, &bps(&base, &base, MAX_ROM_SIZE + 1, &[Command::SourceRead(32)]))));
+
+        // Metadata that claims to be larger than anything that fits in memory
+        let mut patch = BPS_MAGIC.to_vec();
+        varint(base.len(), &mut patch);
+        varint(base.len(), &mut patch);
+        varint(usize::MAX - 1, &mut patch);
+        patch.extend_from_slice(&crc32fast::hash(&base).to_le_bytes());
+        patch.extend_from_slice(&crc32fast::hash(&base).to_le_bytes());
+        let crc = crc32fast::hash(&patch);
+        patch.extend_from_slice(&crc.to_le_bytes());
+        assert!(invalid(apply(&base, &patch)));
+    }
+}
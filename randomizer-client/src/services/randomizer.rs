@@ -1,154 +1,178 @@
 tonic::include_proto!("randomizer");
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use std::future::Future;
+use std::sync::Arc;
+use console_interface::protocols::recording::{Call, Outcome, Recorder, TracePlayer};
+use console_interface::protocols::timeout::now_ms;
+
+enum Backend {
+    Live(grpc_web_client::Client),
+    Replay(Arc<TracePlayer>)
+}
 
+// Every call goes through the session recorder while it's recording, so a trace has the service responses
+// next to the console traffic and a session can be replayed without the service.
 pub struct RandomizerService {
-    client: grpc_web_client::Client
+    backend: Backend,
+    recorder: Arc<Recorder>
 }
 
 impl RandomizerService {
-    pub fn new(uri: &str) -> Self {
+    pub fn new(uri: &str, recorder: Arc<Recorder>) -> Self {
+        Self {
+            backend: Backend::Live(grpc_web_client::Client::new(uri.to_string())),
+            recorder
+        }
+    }
+
+    // Answer every call with the next recorded response instead of asking the service
+    pub fn replay(player: Arc<TracePlayer>) -> Self {
         Self {
-            client: grpc_web_client::Client::new(uri.to_string())
+            backend: Backend::Replay(player),
+            recorder: Arc::new(Recorder::default())
         }
     }
 
+    async fn call<Req, Res, F, Fut>(&self, method: &str, request: Req, send: F) -> Result<Res, tonic::Status>
+    where
+        Req: Serialize,
+        Res: Serialize + DeserializeOwned,
+        F: FnOnce(grpc_web_client::Client, Req) -> Fut,
+        Fut: Future<Output = Result<tonic::Response<Res>, tonic::Status>>
+    {
+        let describe = |request: &Req| Call::Service { method: method.to_string(), request: serde_json::to_value(request).unwrap_or_default() };
+
+        let client = match &self.backend {
+            Backend::Live(client) => client.clone(),
+            Backend::Replay(player) => return match player.next(&describe(&request)) {
+                Ok(Outcome::Ok { value }) => serde_json::from_value(value).map_err(|e| tonic::Status::internal(format!("Could not read recorded response: {}", e))),
+                Ok(Outcome::Err { code, message }) => Err(tonic::Status::new(tonic::Code::from_i32(code.parse().unwrap_or(tonic::Code::Unknown as i32)), message)),
+                Err(e) => Err(tonic::Status::failed_precondition(e.to_string()))
+            }
+        };
+
+        if !self.recorder.is_recording() {
+            return Ok(send(client, request).await?.into_inner());
+        }
+
+        let call = describe(&request);
+        let start = now_ms();
+        let result = send(client, request).await.map(|r| r.into_inner());
+        let outcome = match &result {
+            Ok(response) => Outcome::Ok { value: serde_json::to_value(response).unwrap_or_default() },
+            Err(status) => Outcome::Err { code: (status.code() as i32).to_string(), message: status.message().to_string() }
+        };
+        self.recorder.add(start, call, outcome);
+        result
+    }
+
     pub async fn get_session(&self, session_guid: &str) -> Result<GetSessionResponse, tonic::Status> {
-        let mut client = session_client::SessionClient::new(self.client.clone());
-    
-        let request = tonic::Request::new(GetSessionRequest {
+        let request = GetSessionRequest {
             session_guid: session_guid.to_string()
-        });
-    
-        let response = client.get_session(request).await?.into_inner();
-        Ok(response)
+        };
+
+        self.call("get_session", request, |client, request| async move { session_client::SessionClient::new(client).get_session(request).await }).await
     }
 
     pub async fn register_player(&self, session_guid: &str, world_id: i32) -> Result<RegisterPlayerResponse, tonic::Status> {
-        let mut client = session_client::SessionClient::new(self.client.clone());
-
-        let request = tonic::Request::new(RegisterPlayerRequest {
+        let request = RegisterPlayerRequest {
             session_guid: session_guid.to_string(),
             world_id
-        });
+        };
 
-        let response = client.register_player(request).await?.into_inner();
-        Ok(response)
+        self.call("register_player", request, |client, request| async move { session_client::SessionClient::new(client).register_player(request).await }).await
     }
     
     pub async fn login_player(&self, session_guid: &str, client_guid: &str) -> Result<RegisterPlayerResponse, tonic::Status> {
-        let mut client = session_client::SessionClient::new(self.client.clone());
-
-        let request = tonic::Request::new(LoginPlayerRequest {
+        let request = LoginPlayerRequest {
             session_guid: session_guid.to_string(),
             client_guid: client_guid.to_string()
-        });
+        };
 
-        let response = client.login_player(request).await?.into_inner();
-        Ok(response)
+        self.call("login_player", request, |client, request| async move { session_client::SessionClient::new(client).login_player(request).await }).await
     }
 
     pub async fn unregister_player(&self, client_token: &str) -> Result<UnregisterPlayerResponse, tonic::Status> {
-        let mut client = session_client::SessionClient::new(self.client.clone());
-
-        let request = tonic::Request::new(UnregisterPlayerRequest {
+        let request = UnregisterPlayerRequest {
             client_token: client_token.to_string(),
             sram_backup: None
-        });
+        };
 
-        let response = client.unregister_player(request).await?.into_inner();
-        Ok(response)
+        self.call("unregister_player", request, |client, request| async move { session_client::SessionClient::new(client).unregister_player(request).await }).await
     }
 
     pub async fn update_player(&self, client_token: &str, client_state: i32, device_name: Option<String>) -> Result<UpdatePlayerResponse, tonic::Status> {
-        let mut client = session_client::SessionClient::new(self.client.clone());
-
-        let request = tonic::Request::new(UpdatePlayerRequest {
+        let request = UpdatePlayerRequest {
             client_token: client_token.to_string(),
             client_state,
             device_name
-        });
+        };
 
-        let response = client.update_player(request).await?.into_inner();
-        Ok(response)
+        self.call("update_player", request, |client, request| async move { session_client::SessionClient::new(client).update_player(request).await }).await
     }
 
     pub async fn get_patch(&self, client_token: &str) -> Result<GetPatchResponse, tonic::Status> {
-        let mut client = metadata_client::MetadataClient::new(self.client.clone());
-        
-        let request = tonic::Request::new(GetPatchRequest {
+        let request = GetPatchRequest {
             client_token: client_token.to_string()
-        });
+        };
 
-        let response = client.get_patch(request).await?.into_inner();
-        Ok(response)
+        self.call("get_patch", request, |client, request| async move { metadata_client::MetadataClient::new(client).get_patch(request).await }).await
     }
 
     pub async fn _get_spoiler(&self, client_token: &str) -> Result<GetSpoilerResponse, tonic::Status> {
-        let mut client = metadata_client::MetadataClient::new(self.client.clone());
-
-        let request = tonic::Request::new(GetSpoilerRequest {
+        let request = GetSpoilerRequest {
             client_token: client_token.to_string()
-        });
+        };
 
-        let response = client.get_spoiler(request).await?.into_inner();
-        Ok(response)
+        self.call("get_spoiler", request, |client, request| async move { metadata_client::MetadataClient::new(client).get_spoiler(request).await }).await
     }
 
     pub async fn get_events(&self, client_token: &str, event_types: &[i32], 
                                    from_event_id: Option<i32>, to_event_id: Option<i32>,
                                    from_world_id: Option<i32>, to_world_id: Option<i32>) -> Result<GetEventsResponse, tonic::Status> 
     {        
-        let mut client = event_client::EventClient::new(self.client.clone());
-        let request = tonic::Request::new(GetEventsRequest {
+        let request = GetEventsRequest {
             client_token: client_token.to_string(),
             from_event_id,
             to_event_id,
             from_world_id,
             to_world_id,
             event_types: event_types.to_vec()
-        });
+        };
 
-        let response = client.get_events(request).await?.into_inner();
-        Ok(response)
+        self.call("get_events", request, |client, request| async move { event_client::EventClient::new(client).get_events(request).await }).await
     }
 
     pub async fn get_report(&self, client_token: &str, seed_id: i32, from_event_id: i32, world_id: i32, event_types: &[i32]) -> Result<GetReportResponse, tonic::Status>
     {
-        let mut client = event_client::EventClient::new(self.client.clone());
-        let request = tonic::Request::new(GetReportRequest {
+        let request = GetReportRequest {
             client_token: client_token.to_string(),
             seed_id,
             from_event_id,
             world_id,
             event_types: event_types.to_vec()
-        });
+        };
 
-        let response = client.get_report(request).await?.into_inner();
-        Ok(response)        
+        self.call("get_report", request, |client, request| async move { event_client::EventClient::new(client).get_report(request).await }).await
     }
 
     pub async fn send_event(&self, client_token: &str, session_event: SessionEvent) -> Result<SendEventResponse, tonic::Status> {
-        let mut client = event_client::EventClient::new(self.client.clone());
-        
-        let request = tonic::Request::new(SendEventRequest {
+        let request = SendEventRequest {
             client_token: client_token.to_string(),
             event: Some(session_event)
-        });
+        };
 
-        let response = client.send_event(request).await?.into_inner();
-        Ok(response)
+        self.call("send_event", request, |client, request| async move { event_client::EventClient::new(client).send_event(request).await }).await
     }
 
     pub async fn confirm_events(&self, client_token: &str, events_ids: &[i32])-> Result<ConfirmEventsResponse, tonic::Status> {
-        let mut client = event_client::EventClient::new(self.client.clone());
-
-        let request = tonic::Request::new(ConfirmEventsRequest {
+        let request = ConfirmEventsRequest {
             client_token: client_token.to_string(),
             event_ids: events_ids.to_vec()
-        });
+        };
 
-        let response = client.confirm_events(request).await?.into_inner();
-        Ok(response)
+        self.call("confirm_events", request, |client, request| async move { event_client::EventClient::new(client).confirm_events(request).await }).await
     }
 }
 